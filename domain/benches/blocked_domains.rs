@@ -0,0 +1,121 @@
+//! Compares the in-memory blocked domain index with the SQL lookup it replaced.
+//! `DOMAIN_COUNT` controls how many synthetic domains are loaded, 1M by default.
+
+use std::collections::BTreeSet;
+use std::env;
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use sqlx::{Connection, SqliteConnection};
+use tokio::runtime::Runtime;
+
+use domain::block_index::BlockIndex;
+use domain::db::block_list::find_blocked_domain;
+use domain::db::init_db;
+
+const TLDS: &[&str] = &["com", "net", "org", "io", "co.uk", "info"];
+const WORDS: &[&str] = &[
+    "ads",
+    "track",
+    "metrics",
+    "pixel",
+    "cdn",
+    "stats",
+    "beacon",
+    "click",
+    "promo",
+    "telemetry",
+];
+
+/// Deterministic names like `pixel-48211.stats7.co.uk`
+fn domains(count: usize) -> Vec<(String, String)> {
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as usize
+    };
+    (0..count)
+        .map(|i| {
+            let domain = format!(
+                "{}-{}.{}{}.{}",
+                WORDS[next() % WORDS.len()],
+                i,
+                WORDS[next() % WORDS.len()],
+                next() % 100,
+                TLDS[next() % TLDS.len()]
+            );
+            (domain, format!("https://lists.example/{}.txt", i % 20))
+        })
+        .collect()
+}
+
+async fn load_db(domains: &[(String, String)]) -> anyhow::Result<()> {
+    let dir = env::temp_dir().join("domain_bench");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    env::set_current_dir(&dir)?;
+    init_db().await?;
+
+    let mut connection = SqliteConnection::connect("sqlite://domain.db?mode=rw").await?;
+    let mut trans = connection.begin().await?;
+    for chunk in domains.chunks(400) {
+        let sql = format!(
+            "insert into blocked_domains(domain_name, source) values {}",
+            vec!["(?, ?)"; chunk.len()].join(", ")
+        );
+        let mut query = sqlx::query(&sql);
+        for (domain, source) in chunk {
+            query = query.bind(domain).bind(source);
+        }
+        query.execute(&mut trans).await?;
+    }
+    // Only the domains of the enabled sources are looked up
+    let sources = domains.iter().map(|(_, s)| s).collect::<BTreeSet<_>>();
+    for source in sources {
+        sqlx::query("insert into block_list(src) values(?)")
+            .bind(source)
+            .execute(&mut trans)
+            .await?;
+    }
+    trans.commit().await?;
+    Ok(())
+}
+
+fn bench_lookup(c: &mut Criterion) {
+    let count = env::var("DOMAIN_COUNT")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(1_000_000);
+    let domains = domains(count);
+    let hit = format!("www.{}.", domains[count / 2].0);
+    let miss = "www.not-blocked.example.com.".to_owned();
+
+    let rt = Runtime::new().unwrap();
+    rt.block_on(load_db(&domains)).unwrap();
+
+    let start = Instant::now();
+    let index = BlockIndex::build(domains).unwrap();
+    println!(
+        "Index of {} domains takes {} KB, built in {:?}",
+        index.len(),
+        index.size_bytes() / 1024,
+        start.elapsed()
+    );
+    assert!(index.find(&hit).is_some() && index.find(&miss).is_none());
+
+    let mut group = c.benchmark_group("find_blocked_domain");
+    group.bench_function("index hit", |b| b.iter(|| index.find(&hit)));
+    group.bench_function("index miss", |b| b.iter(|| index.find(&miss)));
+    group.bench_function("sql hit", |b| {
+        b.to_async(&rt).iter(|| find_blocked_domain(&hit))
+    });
+    group.bench_function("sql miss", |b| {
+        b.to_async(&rt).iter(|| find_blocked_domain(&miss))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_lookup);
+criterion_main!(benches);
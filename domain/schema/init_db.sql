@@ -6,9 +6,14 @@ create table filters (
     expr TEXT NOT NULL,
     is_regex BOOLEAN NOT NULL,
     enabled BOOLEAN NOT NULL,
-    is_allow BOOLEAN NOT NULL
+    is_allow BOOLEAN NOT NULL,
+    modifiers TEXT DEFAULT '' NOT NULL,
+    version INTEGER DEFAULT 1 NOT NULL,
+    hit_count INTEGER DEFAULT 0 NOT NULL,
+    last_hit DATETIME,
+    expires_at DATETIME
 );
-create unique index unique_filter_expr on filters(expr, is_regex);
+create unique index unique_filter_expr on filters(expr, is_regex, modifiers);
 insert into filters(expr, is_regex, enabled, is_allow) values('hn.algolia.com', false, true, true);
 
 create table block_list (
@@ -16,8 +21,28 @@ create table block_list (
     src TEXT NOT NULL,
     retry_count INTEGER DEFAULT 0 NOT NULL,
     domain_count INTEGER DEFAULT -1 NOT NULL,
-    last_updated DATETIME DEFAULT (datetime('now','localtime')) NOT NULL
+    last_updated DATETIME DEFAULT (datetime('now','localtime')) NOT NULL,
+    version INTEGER DEFAULT 1 NOT NULL,
+    hit_count INTEGER DEFAULT 0 NOT NULL,
+    last_hit DATETIME,
+    etag TEXT,
+    last_modified TEXT,
+    format TEXT,
+    invalid_count INTEGER DEFAULT 0 NOT NULL,
+    kind TEXT DEFAULT 'block' NOT NULL,
+    min_domains INTEGER,
+    max_shrink INTEGER,
+    warning TEXT,
+    next_retry DATETIME,
+    http_status INTEGER,
+    fetch_millis INTEGER,
+    fetch_bytes INTEGER,
+    name TEXT,
+    category TEXT,
+    enabled BOOLEAN DEFAULT true NOT NULL,
+    refresh_hours INTEGER
 );
+create unique index unique_block_list_src on block_list(src);
 insert into block_list(src) values('https://v.firebog.net/hosts/Prigent-Malware.txt');
 
 create table blocked_domains(
@@ -26,4 +51,24 @@ create table blocked_domains(
     source TEXT,
     updated DATETIME DEFAULT (datetime('now', 'localtime')) NOT NULL
 );
-create unique index unique_domain_name on blocked_domains(domain_name);
\ No newline at end of file
+create unique index unique_domain_source on blocked_domains(domain_name, source);
+create index blocked_domains_source on blocked_domains(source);
+
+create table allowed_domains(
+    ad_id INTEGER PRIMARY KEY NOT NULL,
+    domain_name TEXT NOT NULL,
+    source TEXT,
+    updated DATETIME DEFAULT (datetime('now', 'localtime')) NOT NULL
+);
+create unique index unique_allowed_domain_source on allowed_domains(domain_name, source);
+create index allowed_domains_source on allowed_domains(source);
+
+create table unblock_requests (
+    ur_id INTEGER PRIMARY KEY NOT NULL,
+    create_time DATETIME DEFAULT (datetime('now','localtime')) NOT NULL,
+    domain_name TEXT NOT NULL,
+    requester TEXT NOT NULL,
+    reason TEXT,
+    status TEXT DEFAULT 'pending' NOT NULL
+);
+create unique index unique_pending_unblock on unblock_requests(domain_name) where status = 'pending';
@@ -0,0 +1,9 @@
+create table unblock_requests (
+    ur_id INTEGER PRIMARY KEY NOT NULL,
+    create_time DATETIME DEFAULT (datetime('now','localtime')) NOT NULL,
+    domain_name TEXT NOT NULL,
+    requester TEXT NOT NULL,
+    reason TEXT,
+    status TEXT DEFAULT 'pending' NOT NULL
+);
+create unique index unique_pending_unblock on unblock_requests(domain_name) where status = 'pending';
@@ -0,0 +1,3 @@
+alter table filters add column modifiers TEXT DEFAULT '' NOT NULL;
+drop index unique_filter_expr;
+create unique index unique_filter_expr on filters(expr, is_regex, modifiers);
@@ -0,0 +1,5 @@
+alter table filters add column version INTEGER DEFAULT 1 NOT NULL;
+alter table block_list add column version INTEGER DEFAULT 1 NOT NULL;
+-- Rows are edited by their source, so only the first entry of a source is kept
+delete from block_list where bl_id not in (select min(bl_id) from block_list group by src);
+create unique index unique_block_list_src on block_list(src);
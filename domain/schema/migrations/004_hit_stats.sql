@@ -0,0 +1,4 @@
+alter table filters add column hit_count INTEGER DEFAULT 0 NOT NULL;
+alter table filters add column last_hit DATETIME;
+alter table block_list add column hit_count INTEGER DEFAULT 0 NOT NULL;
+alter table block_list add column last_hit DATETIME;
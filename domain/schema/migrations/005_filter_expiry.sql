@@ -0,0 +1 @@
+alter table filters add column expires_at DATETIME;
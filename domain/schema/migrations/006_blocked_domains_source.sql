@@ -0,0 +1 @@
+create index blocked_domains_source on blocked_domains(source);
@@ -0,0 +1,2 @@
+alter table block_list add column etag TEXT;
+alter table block_list add column last_modified TEXT;
@@ -0,0 +1,2 @@
+alter table block_list add column format TEXT;
+alter table block_list add column invalid_count INTEGER DEFAULT 0 NOT NULL;
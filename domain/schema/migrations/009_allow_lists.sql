@@ -0,0 +1,10 @@
+alter table block_list add column kind TEXT DEFAULT 'block' NOT NULL;
+
+create table allowed_domains(
+    ad_id INTEGER PRIMARY KEY NOT NULL,
+    domain_name TEXT NOT NULL,
+    source TEXT,
+    updated DATETIME DEFAULT (datetime('now', 'localtime')) NOT NULL
+);
+create unique index unique_allowed_domain_name on allowed_domains(domain_name);
+create index allowed_domains_source on allowed_domains(source);
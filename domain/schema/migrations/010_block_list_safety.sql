@@ -0,0 +1,3 @@
+alter table block_list add column min_domains INTEGER;
+alter table block_list add column max_shrink INTEGER;
+alter table block_list add column warning TEXT;
@@ -0,0 +1,4 @@
+alter table block_list add column next_retry DATETIME;
+alter table block_list add column http_status INTEGER;
+alter table block_list add column fetch_millis INTEGER;
+alter table block_list add column fetch_bytes INTEGER;
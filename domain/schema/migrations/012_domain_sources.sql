@@ -0,0 +1,6 @@
+drop index unique_domain_name;
+create unique index unique_domain_source on blocked_domains(domain_name, source);
+drop index unique_allowed_domain_name;
+create unique index unique_allowed_domain_source on allowed_domains(domain_name, source);
+-- Only the first source of a domain was kept, so every source is fetched in full again
+update block_list set domain_count=-1;
@@ -0,0 +1,4 @@
+alter table block_list add column name TEXT;
+alter table block_list add column category TEXT;
+alter table block_list add column enabled BOOLEAN DEFAULT true NOT NULL;
+alter table block_list add column refresh_hours INTEGER;
@@ -0,0 +1,650 @@
+//! Parser for the AdGuard/uBlock style DNS filtering rules, e.g. `||example.com^$important`.
+//!
+//! A rule without modifiers turns into the same [`DbFilter`] the plain syntax creates, so it's
+//! served by the name trie or the regex sets. Rules with modifiers keep their canonical modifier
+//! text in [`DbFilter::modifiers`] and are evaluated by a [`RuleSet`].
+
+use std::fmt;
+use std::net::IpAddr;
+
+use anyhow::Context;
+use chrono::Local;
+use itertools::Itertools;
+use log::warn;
+use regex::Regex;
+
+use crate::db::filters::DbFilter;
+use crate::domain_name::{is_domain_char, is_subdomain, normalize};
+
+/// Parses a single rule, `Ok(None)` is returned for blank lines and `!` comments.
+/// `is_allow` is used unless the rule is an `@@` exception, while the legacy `#` prefix
+/// disables the rule and `*` marks a raw regex.
+pub fn parse_rule(rule: &str, is_allow: bool) -> anyhow::Result<Option<DbFilter>> {
+    let mut rule = rule.trim();
+    if rule.is_empty() || rule.starts_with('!') {
+        return Ok(None);
+    }
+    let enabled = match rule.strip_prefix('#') {
+        Some(rest) => {
+            rule = rest.trim();
+            false
+        }
+        None => true,
+    };
+    // `*$denyallow=...` is the adblock way of matching everything, not a regex
+    if let Some(regex) = rule.strip_prefix('*').filter(|_| !rule.starts_with("*$")) {
+        let regex = regex.trim().to_lowercase();
+        Regex::new(&regex).with_context(|| format!("Can't parse {regex} as regex"))?;
+        return Ok(Some(new_filter(regex, true, is_allow, enabled, "")));
+    }
+
+    let (is_allow, rule) = match rule.strip_prefix("@@") {
+        Some(rest) => (true, rest),
+        None => (is_allow, rule),
+    };
+    let (pattern, modifiers) = split_modifiers(rule);
+    let modifiers = match modifiers {
+        Some(modifiers) => Modifiers::parse(modifiers)?.to_string(),
+        None => String::new(),
+    };
+    let (expr, is_regex) = parse_pattern(pattern)?;
+    Ok(Some(new_filter(
+        expr, is_regex, is_allow, enabled, &modifiers,
+    )))
+}
+
+/// Formats the filter back in the adblock syntax
+pub fn rule_text(filter: &DbFilter) -> String {
+    let mut text = String::new();
+    if filter.is_allow {
+        text.push_str("@@");
+    }
+    if filter.is_regex {
+        text.push_str(&format!("/{}/", filter.expr));
+    } else {
+        text.push_str(&format!("||{}^", filter.expr));
+    }
+    if !filter.modifiers.is_empty() {
+        text.push_str(&format!("${}", filter.modifiers));
+    }
+    text
+}
+
+fn new_filter(
+    expr: String,
+    is_regex: bool,
+    is_allow: bool,
+    enabled: bool,
+    modifiers: &str,
+) -> DbFilter {
+    DbFilter {
+        f_id: -1,
+        create_time: Local::now().naive_local(),
+        expr,
+        is_regex,
+        enabled,
+        is_allow,
+        modifiers: modifiers.into(),
+        version: 1,
+        hit_count: 0,
+        last_hit: None,
+        expires_at: None,
+    }
+}
+
+fn split_modifiers(rule: &str) -> (&str, Option<&str>) {
+    if rule.starts_with('/') {
+        // The regex may have `$` of its own, modifiers can only follow the closing slash
+        return match rule.rfind("/$") {
+            Some(idx) if idx > 0 => (&rule[..=idx], Some(&rule[idx + 2..])),
+            _ => (rule, None),
+        };
+    }
+    match rule.split_once('$') {
+        Some((pattern, modifiers)) => (pattern, Some(modifiers)),
+        None => (rule, None),
+    }
+}
+
+/// Returns the expression and whether it's a regex
+fn parse_pattern(pattern: &str) -> anyhow::Result<(String, bool)> {
+    if pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+        let regex = &pattern[1..pattern.len() - 1];
+        Regex::new(regex).with_context(|| format!("Can't parse {regex} as regex"))?;
+        return Ok((regex.into(), true));
+    }
+
+    let plain = pattern
+        .strip_prefix("||")
+        .and_then(|p| p.strip_suffix("^|").or_else(|| p.strip_suffix('^')))
+        .or_else(|| (!pattern.contains(['|', '^', '*'])).then_some(pattern));
+    if let Some(domain) = plain.and_then(normalize) {
+        return Ok((domain, false));
+    }
+
+    if pattern.is_empty() || (pattern.contains("||") && !pattern.starts_with("||")) {
+        return Err(anyhow::anyhow!("Invalid rule pattern '{pattern}'"));
+    }
+    let mut regex = String::new();
+    let mut rest = pattern;
+    if let Some(r) = rest.strip_prefix("||") {
+        regex.push_str(r"(^|\.)");
+        rest = r;
+    } else if let Some(r) = rest.strip_prefix('|') {
+        regex.push('^');
+        rest = r;
+    }
+    let (rest, anchored) = match rest
+        .strip_suffix("^|")
+        .or_else(|| rest.strip_suffix(['^', '|']))
+    {
+        Some(rest) => (rest, true),
+        None => (rest, false),
+    };
+    for part in rest.split('*') {
+        if !part.chars().all(is_domain_char) {
+            return Err(anyhow::anyhow!("Invalid rule pattern '{pattern}'"));
+        }
+    }
+    regex.push_str(&rest.split('*').map(regex::escape).join(".*"));
+    if anchored {
+        // Query names come with the trailing dot
+        regex.push_str(r"\.?$");
+    }
+    Ok((regex.to_lowercase(), true))
+}
+
+/// Modifiers after the `$` of a rule, the rule only applies to the queries they match
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    /// Beats the allow rules, unless they are important as well
+    pub important: bool,
+    /// Ranks the rule among the others of the same importance, higher first, `0` by default
+    pub priority: i64,
+    /// Subnets with whether they are excluded with `~`
+    clients: Vec<(Subnet, bool)>,
+    dns_types: Vec<(String, bool)>,
+    /// Domains (and their sub-domains) the rule doesn't apply to
+    deny_allow: Vec<String>,
+}
+
+impl Modifiers {
+    pub fn parse(text: &str) -> anyhow::Result<Modifiers> {
+        let mut modifiers = Modifiers::default();
+        for modifier in text.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+            let (name, value) = match modifier.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (modifier, None),
+            };
+            let values = || {
+                value
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.split('|').map(str::trim).map(negation))
+                    .ok_or_else(|| anyhow::anyhow!("${name} needs a value"))
+            };
+            match name {
+                "important" if value.is_none() => modifiers.important = true,
+                "priority" => {
+                    let value = value
+                        .filter(|v| !v.is_empty())
+                        .ok_or_else(|| anyhow::anyhow!("$priority needs a value"))?;
+                    modifiers.priority = value.parse().map_err(|_| {
+                        anyhow::anyhow!("Priority '{value}' must be a whole number")
+                    })?;
+                }
+                "client" => {
+                    for (client, negated) in values()? {
+                        let client = client.trim_matches(|c| c == '\'' || c == '"');
+                        let subnet = Subnet::parse(client).ok_or_else(|| {
+                            anyhow::anyhow!("Client '{client}' must be an IP address or subnet")
+                        })?;
+                        modifiers.clients.push((subnet, negated));
+                    }
+                }
+                "dnstype" => {
+                    for (dns_type, negated) in values()? {
+                        if dns_type.is_empty()
+                            || !dns_type.chars().all(|c| c.is_ascii_alphanumeric())
+                        {
+                            return Err(anyhow::anyhow!("Invalid dns type '{dns_type}'"));
+                        }
+                        modifiers.dns_types.push((dns_type.to_uppercase(), negated));
+                    }
+                }
+                "denyallow" => {
+                    for (domain, negated) in values()? {
+                        let normalized =
+                            normalize(domain).filter(|_| !negated).ok_or_else(|| {
+                                anyhow::anyhow!("Invalid denyallow domain '{domain}'")
+                            })?;
+                        modifiers.deny_allow.push(normalized);
+                    }
+                }
+                _ => return Err(anyhow::anyhow!("Unsupported modifier '{modifier}'")),
+            }
+        }
+        Ok(modifiers)
+    }
+
+    /// Whether the rule applies to the query, a client or dns type which isn't known
+    /// only satisfies the modifiers made of exclusions
+    pub fn applies(&self, domain: &str, client: Option<IpAddr>, dns_type: Option<&str>) -> bool {
+        include_exclude(&self.clients, |subnet| {
+            client.map(|client| subnet.contains(client))
+        }) && include_exclude(&self.dns_types, |rtype| {
+            dns_type.map(|dns_type| rtype.eq_ignore_ascii_case(dns_type))
+        }) && !self.deny_allow.iter().any(|d| is_subdomain(domain, d))
+    }
+}
+
+/// `matches` returns `None` if the value to match is unknown
+fn include_exclude<T>(list: &[(T, bool)], matches: impl Fn(&T) -> Option<bool>) -> bool {
+    let mut includes = list.iter().filter(|(_, negated)| !negated).peekable();
+    let included = includes.peek().is_none() || includes.any(|(v, _)| matches(v) == Some(true));
+    let excluded = list
+        .iter()
+        .any(|(v, negated)| *negated && matches(v) == Some(true));
+    included && !excluded
+}
+
+fn negation(value: &str) -> (&str, bool) {
+    match value.strip_prefix('~') {
+        Some(value) => (value.trim(), true),
+        None => (value, false),
+    }
+}
+
+impl fmt::Display for Modifiers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn list<T: fmt::Display>(values: &[(T, bool)]) -> String {
+            values
+                .iter()
+                .map(|(v, negated)| format!("{}{v}", if *negated { "~" } else { "" }))
+                .join("|")
+        }
+
+        let mut parts = Vec::new();
+        if self.important {
+            parts.push("important".to_owned());
+        }
+        if self.priority != 0 {
+            parts.push(format!("priority={}", self.priority));
+        }
+        if !self.clients.is_empty() {
+            parts.push(format!("client={}", list(&self.clients)));
+        }
+        if !self.dns_types.is_empty() {
+            parts.push(format!("dnstype={}", list(&self.dns_types)));
+        }
+        if !self.deny_allow.is_empty() {
+            parts.push(format!("denyallow={}", self.deny_allow.join("|")));
+        }
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Subnet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Subnet {
+    fn parse(text: &str) -> Option<Subnet> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (text.parse::<IpAddr>().ok()?, None),
+        };
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        (prefix <= max_prefix).then_some(Subnet { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        let (addr, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                (u32::from(addr) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => (u128::from(addr), u128::from(ip), 128),
+            _ => return false,
+        };
+        self.prefix == 0 || (addr ^ ip) >> (bits - self.prefix as u32) == 0
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let max_prefix = if self.addr.is_ipv4() { 32 } else { 128 };
+        if self.prefix == max_prefix {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Domain(String),
+    Regex(Regex),
+}
+
+/// A filter with modifiers
+#[derive(Debug)]
+pub struct Rule {
+    pub id: i64,
+    matcher: Matcher,
+    modifiers: Modifiers,
+    pub is_allow: bool,
+    pub text: String,
+}
+
+impl Rule {
+    pub fn from_filter(filter: &DbFilter) -> anyhow::Result<Rule> {
+        let matcher = if filter.is_regex {
+            Matcher::Regex(Regex::new(&filter.expr)?)
+        } else {
+            Matcher::Domain(filter.expr.clone())
+        };
+        Ok(Rule {
+            id: filter.f_id,
+            matcher,
+            modifiers: Modifiers::parse(&filter.modifiers)?,
+            is_allow: filter.is_allow,
+            text: rule_text(filter),
+        })
+    }
+
+    pub fn important(&self) -> bool {
+        self.modifiers.important
+    }
+
+    pub fn priority(&self) -> i64 {
+        self.modifiers.priority
+    }
+
+    pub fn matches(&self, domain: &str, client: Option<IpAddr>, dns_type: Option<&str>) -> bool {
+        self.matches_pattern(domain) && self.modifiers.applies(domain, client, dns_type)
+    }
+
+    /// Whether the domain matches, regardless of the modifiers
+    pub fn matches_pattern(&self, domain: &str) -> bool {
+        match &self.matcher {
+            Matcher::Domain(name) => is_subdomain(domain, name),
+            Matcher::Regex(regex) => regex.is_match(domain),
+        }
+    }
+
+    pub fn applies(&self, domain: &str, client: Option<IpAddr>, dns_type: Option<&str>) -> bool {
+        self.modifiers.applies(domain, client, dns_type)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    pub fn create<'a>(filters: impl IntoIterator<Item = &'a DbFilter>) -> Self {
+        let rules = filters
+            .into_iter()
+            .filter_map(|filter| match Rule::from_filter(filter) {
+                Ok(rule) => Some(rule),
+                Err(e) => {
+                    warn!("Ignoring filter {}: {e}", rule_text(filter));
+                    None
+                }
+            })
+            .collect();
+        RuleSet { rules }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn matching(
+        &self,
+        domain: &str,
+        client: Option<IpAddr>,
+        dns_type: Option<&str>,
+    ) -> Vec<&Rule> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(domain, client, dns_type))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use super::{parse_rule, rule_text, Rule, Subnet};
+
+    /// Expression, is regex, is allow and modifiers
+    type Parsed = Option<(&'static str, bool, bool, &'static str)>;
+
+    /// Taken from AdGuard DNS filter, uBlock lists and our own notes
+    const CORPUS: &[(&str, Parsed)] = &[
+        (
+            "||doubleclick.net^",
+            Some(("doubleclick.net", false, false, "")),
+        ),
+        (
+            "||pagead2.googlesyndication.com^",
+            Some(("pagead2.googlesyndication.com", false, false, "")),
+        ),
+        (
+            "@@||s.youtube.com^",
+            Some(("s.youtube.com", false, true, "")),
+        ),
+        (
+            "@@||cdn.optimizely.com^|",
+            Some(("cdn.optimizely.com", false, true, "")),
+        ),
+        (
+            "||Ads.Example.COM^",
+            Some(("ads.example.com", false, false, "")),
+        ),
+        ("example.org", Some(("example.org", false, false, ""))),
+        ("! Title: AdGuard DNS filter", None),
+        ("", None),
+        (
+            "||adservice.google.*^",
+            Some((r"(^|\.)adservice\.google\..*\.?$", true, false, "")),
+        ),
+        (
+            "||ad*.example.net^",
+            Some((r"(^|\.)ad.*\.example\.net\.?$", true, false, "")),
+        ),
+        ("|ads.", Some((r"^ads\.", true, false, ""))),
+        ("||metrics", Some((r"(^|\.)metrics", true, false, ""))),
+        (
+            r"/^ad[sv]?[0-9]*\./",
+            Some((r"^ad[sv]?[0-9]*\.", true, false, "")),
+        ),
+        (
+            r"/tracking$/$dnstype=A",
+            Some(("tracking$", true, false, "dnstype=A")),
+        ),
+        (
+            "||graph.facebook.com^$important",
+            Some(("graph.facebook.com", false, false, "important")),
+        ),
+        (
+            "@@||login.microsoftonline.com^$important",
+            Some(("login.microsoftonline.com", false, true, "important")),
+        ),
+        (
+            "||youtube.com^$client=192.168.1.30|10.0.0.0/8",
+            Some((
+                "youtube.com",
+                false,
+                false,
+                "client=192.168.1.30|10.0.0.0/8",
+            )),
+        ),
+        (
+            "||example.com^$client=~'192.168.1.5'",
+            Some(("example.com", false, false, "client=~192.168.1.5")),
+        ),
+        (
+            "||example.com^$dnstype=aaaa|~A",
+            Some(("example.com", false, false, "dnstype=AAAA|~A")),
+        ),
+        (
+            "*$denyallow=com|net",
+            Some((".*", true, false, "denyallow=com|net")),
+        ),
+        (
+            "||cdn.example.com^$important,dnstype=A,client=fd00::/8",
+            Some((
+                "cdn.example.com",
+                false,
+                false,
+                "important,client=fd00::/8,dnstype=A",
+            )),
+        ),
+        ("* ^ads?\\.", Some(("^ads?\\.", true, false, ""))),
+        (
+            "@@||example.com^$priority=10,important",
+            Some(("example.com", false, true, "important,priority=10")),
+        ),
+        (
+            "||example.com^$priority=-2",
+            Some(("example.com", false, false, "priority=-2")),
+        ),
+        (
+            "||example.com^$priority=0",
+            Some(("example.com", false, false, "")),
+        ),
+        (
+            "||Bücher.Example^",
+            Some(("xn--bcher-kva.example", false, false, "")),
+        ),
+        (
+            "пример.рф",
+            Some(("xn--e1afmkfd.xn--p1ai", false, false, "")),
+        ),
+        (
+            "@@||XN--BCHER-KVA.example^$denyallow=Shop.Bücher.example",
+            Some((
+                "xn--bcher-kva.example",
+                false,
+                true,
+                "denyallow=shop.xn--bcher-kva.example",
+            )),
+        ),
+    ];
+
+    #[test]
+    fn test_corpus() {
+        for (rule, expected) in CORPUS {
+            let filter = parse_rule(rule, false).unwrap();
+            let actual = filter.as_ref().map(|f| {
+                (
+                    f.expr.as_str(),
+                    f.is_regex,
+                    f.is_allow,
+                    f.modifiers.as_str(),
+                )
+            });
+            assert_eq!(actual, *expected, "{rule}");
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        for rule in [
+            "||example.com^$badfilter",
+            "||example.com^$client=laptop",
+            "||example.com^$client=10.0.0.0/33",
+            "||example.com^$dnstype=",
+            "||example.com^$important=1",
+            "||exa mple.com^",
+            "/ads(/",
+            "||ex^ample.com",
+            "||example.com^$priority",
+            "||example.com^$priority=high",
+            "||example.com^$priority=1.5",
+            "||bü*cher.example^",
+            "||example.com^$denyallow=bü cher.example",
+        ] {
+            assert!(parse_rule(rule, false).is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn test_legacy() {
+        let filter = parse_rule("# *facebook.com", true).unwrap().unwrap();
+        assert!(filter.is_regex && filter.is_allow && !filter.enabled);
+        assert_eq!(filter.expr, "facebook.com");
+
+        let filter = parse_rule("#||ads.com^", false).unwrap().unwrap();
+        assert!(!filter.is_regex && !filter.is_allow && !filter.enabled);
+        assert_eq!(rule_text(&filter), "||ads.com^");
+    }
+
+    fn rule(text: &str) -> Rule {
+        Rule::from_filter(&parse_rule(text, false).unwrap().unwrap()).unwrap()
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_matches() {
+        let r = rule("||example.com^");
+        assert!(r.matches("example.com.", None, None));
+        assert!(r.matches("www.example.com.", None, None));
+        assert!(!r.matches("badexample.com.", None, None));
+
+        let r = rule("||ad*.example.net^");
+        assert!(r.matches("ads.example.net.", None, None));
+        assert!(r.matches("x.adserver.example.net", None, None));
+        assert!(!r.matches("ads.example.net.evil.com.", None, None));
+
+        let r = rule("||youtube.com^$client=192.168.1.30|10.0.0.0/8");
+        assert!(r.matches("youtube.com.", ip("10.1.2.3"), None));
+        assert!(r.matches("youtube.com.", ip("::ffff:192.168.1.30"), None));
+        assert!(!r.matches("youtube.com.", ip("192.168.1.31"), None));
+        assert!(!r.matches("youtube.com.", None, None));
+
+        let r = rule("||example.com^$client=~192.168.1.0/24");
+        assert!(r.matches("example.com.", ip("192.168.2.1"), None));
+        assert!(!r.matches("example.com.", ip("192.168.1.9"), None));
+        assert!(r.matches("example.com.", None, None));
+
+        let r = rule("||example.com^$dnstype=~A|~CNAME");
+        assert!(r.matches("example.com.", None, Some("AAAA")));
+        assert!(!r.matches("example.com.", None, Some("a")));
+
+        let r = rule("*$denyallow=com|net");
+        assert!(r.matches("example.org.", None, None));
+        assert!(!r.matches("example.com.", None, None));
+        assert!(!r.matches("net.", None, None));
+    }
+
+    #[test]
+    fn test_subnet() {
+        let subnet = Subnet::parse("fd00::/8").unwrap();
+        assert!(subnet.contains("fd12::1".parse().unwrap()));
+        assert!(!subnet.contains("fe80::1".parse().unwrap()));
+        assert!(!subnet.contains("10.0.0.1".parse().unwrap()));
+        assert!(Subnet::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert_eq!(Subnet::parse("10.0.0.1").unwrap().to_string(), "10.0.0.1");
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::iter;
+use std::mem;
+use std::time::Instant;
+
+use fst::{Map, MapBuilder};
+use log::*;
+use once_cell::sync::Lazy;
+use tokio::sync::RwLock;
+
+use crate::db::block_list::{for_each_listed_domain, load_block_list, ListCategory, ListKind};
+use crate::domain_name::canonical;
+
+static BLOCK_INDEX: Lazy<RwLock<BlockIndex>> = Lazy::new(|| RwLock::new(BlockIndex::default()));
+static ALLOW_INDEX: Lazy<RwLock<BlockIndex>> = Lazy::new(|| RwLock::new(BlockIndex::default()));
+/// The display name and the category of every source, kept apart from the indexes so a change
+/// to them doesn't rebuild one
+static SOURCE_LABELS: Lazy<RwLock<HashMap<String, SourceLabel>>> = Lazy::new(Default::default);
+
+type SourceLabel = (String, Option<ListCategory>);
+
+/// Immutable index of the blocked or allowed domains, an FST keyed by the domain.
+/// A domain maps to the set of the sources listing it, there are far fewer distinct sets than
+/// domains, so every set is kept once along with how many domains it has.
+#[derive(Debug, Default)]
+pub struct BlockIndex {
+    domains: Map<Vec<u8>>,
+    sources: Vec<String>,
+    source_sets: Vec<(Vec<usize>, u64)>,
+}
+
+/// Builds a [`BlockIndex`] out of the domains as they come, which has to be in byte order
+pub struct IndexBuilder {
+    domains: MapBuilder<Vec<u8>>,
+    sources: Vec<String>,
+    source_sets: Vec<(Vec<usize>, u64)>,
+    set_ids: HashMap<Vec<usize>, usize>,
+    /// The last domain and its sources so far, the next domain adds them to the map
+    domain: String,
+    set: Vec<usize>,
+}
+
+impl IndexBuilder {
+    /// The sources are referred to by their position in `sources`
+    pub fn new(sources: Vec<String>) -> Self {
+        IndexBuilder {
+            domains: MapBuilder::memory(),
+            sources,
+            source_sets: Vec::new(),
+            set_ids: HashMap::new(),
+            domain: String::new(),
+            set: Vec::new(),
+        }
+    }
+
+    /// Adds one of the sources of the domain, the sources of a domain come one after the other
+    pub fn insert(&mut self, domain: &str, source: usize) -> anyhow::Result<()> {
+        if domain != self.domain {
+            self.add_domain()?;
+            self.domain.clear();
+            self.domain.push_str(domain);
+        }
+        if !self.set.contains(&source) {
+            self.set.push(source);
+        }
+        Ok(())
+    }
+
+    fn add_domain(&mut self) -> anyhow::Result<()> {
+        if self.set.is_empty() {
+            return Ok(());
+        }
+        self.set.sort_unstable();
+        let set_id = match self.set_ids.get(&self.set) {
+            Some(set_id) => *set_id,
+            None => {
+                self.source_sets.push((self.set.clone(), 0));
+                self.set_ids
+                    .insert(self.set.clone(), self.source_sets.len() - 1);
+                self.source_sets.len() - 1
+            }
+        };
+        self.source_sets[set_id].1 += 1;
+        self.domains.insert(&self.domain, set_id as u64)?;
+        self.set.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<BlockIndex> {
+        self.add_domain()?;
+        Ok(BlockIndex {
+            domains: self.domains.into_map(),
+            sources: self.sources,
+            source_sets: self.source_sets,
+        })
+    }
+}
+
+impl BlockIndex {
+    /// Builds the index out of the domains in any order with their sources
+    pub fn build(domains: impl IntoIterator<Item = (String, String)>) -> anyhow::Result<Self> {
+        let mut sources = Vec::new();
+        let mut source_ids = HashMap::new();
+        let mut keys = domains
+            .into_iter()
+            .map(|(domain, source)| {
+                let id = *source_ids.entry(source).or_insert_with_key(|source| {
+                    sources.push(source.clone());
+                    sources.len() - 1
+                });
+                let domain = domain.strip_suffix('.').unwrap_or(&domain).to_owned();
+                (domain, id)
+            })
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+
+        let mut builder = IndexBuilder::new(sources);
+        for (domain, id) in keys {
+            builder.insert(&domain, id)?;
+        }
+        builder.finish()
+    }
+
+    /// Returns the listed domain matching the name or one of its parents, with its sources
+    pub fn find(&self, name: &str) -> Option<(String, Vec<&str>)> {
+        let name = canonical(name);
+        // The topmost parent listed wins, so the shortest is looked up first
+        let starts = name.match_indices('.').map(|(idx, _)| idx + 1).rev();
+        for start in starts.chain(iter::once(0)) {
+            let domain = &name[start..];
+            if let Some(set_id) = self.domains.get(domain) {
+                let sources = self.source_sets[set_id as usize]
+                    .0
+                    .iter()
+                    .map(|id| self.sources[*id].as_str())
+                    .collect();
+                return Some((domain.to_owned(), sources));
+            }
+        }
+        None
+    }
+
+    /// How many domains every pair of sources have in common, the pairs without any are left out
+    pub fn overlaps(&self) -> HashMap<(&str, &str), u64> {
+        let mut overlaps = HashMap::new();
+        for (set, count) in &self.source_sets {
+            for (i, a) in set.iter().enumerate() {
+                for b in &set[i + 1..] {
+                    *overlaps
+                        .entry((self.sources[*a].as_str(), self.sources[*b].as_str()))
+                        .or_default() += count;
+                }
+            }
+        }
+        overlaps
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    /// Memory taken by the index
+    pub fn size_bytes(&self) -> usize {
+        self.domains.as_fst().as_bytes().len()
+            + self.sources.iter().map(String::len).sum::<usize>()
+            + self
+                .source_sets
+                .iter()
+                .map(|(set, _)| set.len() * mem::size_of::<usize>())
+                .sum::<usize>()
+    }
+}
+
+fn index(kind: ListKind) -> &'static RwLock<BlockIndex> {
+    match kind {
+        ListKind::Block => &BLOCK_INDEX,
+        ListKind::Allow => &ALLOW_INDEX,
+    }
+}
+
+/// Rebuilds the indexes of the blocked and the allowed domains
+pub async fn reload_block_index() -> anyhow::Result<()> {
+    reload_source_labels().await?;
+    for kind in ListKind::ALL {
+        reload_index(kind).await?;
+    }
+    Ok(())
+}
+
+pub async fn reload_source_labels() -> anyhow::Result<()> {
+    let labels = load_block_list()
+        .await?
+        .into_iter()
+        .map(|bl| {
+            let label = (bl.display_name().to_owned(), bl.category());
+            (bl.src, label)
+        })
+        .collect();
+    *SOURCE_LABELS.write().await = labels;
+    Ok(())
+}
+
+/// The names the sources are shown with, and the most severe of their categories
+pub async fn source_labels(sources: &[String]) -> (Vec<String>, Option<ListCategory>) {
+    let labels = SOURCE_LABELS.read().await;
+    let mut names = Vec::with_capacity(sources.len());
+    let mut category = None;
+    for src in sources {
+        match labels.get(src) {
+            Some((name, c)) => {
+                names.push(name.clone());
+                category = category.max(*c);
+            }
+            None => names.push(src.clone()),
+        }
+    }
+    (names, category)
+}
+
+/// Rebuilds the index from the domains of the kind and swaps it in once it's ready
+pub(crate) async fn reload_index(kind: ListKind) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let mut sources = Vec::new();
+    let mut source_ids = HashMap::new();
+    let mut bl_sources = HashMap::new();
+    for bl in load_block_list().await? {
+        let id = *source_ids.entry(bl.src).or_insert_with_key(|src| {
+            sources.push(src.clone());
+            sources.len() - 1
+        });
+        bl_sources.insert(bl.bl_id, id);
+    }
+    let mut builder = IndexBuilder::new(sources);
+    for_each_listed_domain(kind, |domain, bl_id| match bl_sources.get(&bl_id) {
+        Some(id) => builder.insert(domain, *id),
+        // Added after the sources were loaded, it's picked up by the next reload
+        None => Ok(()),
+    })
+    .await?;
+    let index = builder.finish()?;
+    info!(
+        "Built {kind} domain index of {} domains, {} KB in {:?}",
+        index.len(),
+        index.size_bytes() / 1024,
+        start.elapsed()
+    );
+    *self::index(kind).write().await = index;
+    Ok(())
+}
+
+async fn find(kind: ListKind, name: &str) -> Option<(String, Vec<String>)> {
+    index(kind)
+        .read()
+        .await
+        .find(name)
+        .map(|(domain, sources)| {
+            let sources = sources.into_iter().map(str::to_owned).collect();
+            (domain, sources)
+        })
+}
+
+pub async fn find_blocked_domain(name: impl AsRef<str>) -> Option<(String, Vec<String>)> {
+    find(ListKind::Block, name.as_ref()).await
+}
+
+/// The domain of an allowlist matching the name or one of its parents, with its sources
+pub async fn find_allowed_domain(name: impl AsRef<str>) -> Option<(String, Vec<String>)> {
+    find(ListKind::Allow, name.as_ref()).await
+}
+
+/// How many domains every pair of sources of the same kind have in common, both ways
+pub async fn source_overlaps() -> HashMap<String, HashMap<String, u64>> {
+    let mut overlaps = HashMap::<_, HashMap<_, _>>::new();
+    for kind in ListKind::ALL {
+        for ((a, b), count) in index(kind).read().await.overlaps() {
+            overlaps
+                .entry(a.to_owned())
+                .or_default()
+                .insert(b.to_owned(), count);
+            overlaps
+                .entry(b.to_owned())
+                .or_default()
+                .insert(a.to_owned(), count);
+        }
+    }
+    overlaps
+}
+
+#[cfg(test)]
+mod test {
+    use super::BlockIndex;
+
+    #[test]
+    fn test_find() {
+        let index = BlockIndex::build(
+            [
+                ("ads.example.com", "list1"),
+                ("tracker.net", "list2"),
+                ("a.b.tracker.net", "list1"),
+                ("tracker.net", "list1"),
+            ]
+            .map(|(d, s)| (d.to_owned(), s.to_owned())),
+        )
+        .unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(
+            index.find("ads.example.com."),
+            Some(("ads.example.com".into(), vec!["list1"]))
+        );
+        assert_eq!(
+            index.find("x.ads.example.com"),
+            Some(("ads.example.com".into(), vec!["list1"]))
+        );
+        assert_eq!(
+            index.find("a.b.tracker.net."),
+            Some(("tracker.net".into(), vec!["list1", "list2"]))
+        );
+        assert_eq!(index.find("example.com."), None);
+        assert_eq!(index.find("myads.example.com."), None);
+        assert_eq!(index.find("net."), None);
+        assert_eq!(
+            index.find("X.Ads.Example.COM."),
+            Some(("ads.example.com".into(), vec!["list1"]))
+        );
+        assert_eq!(BlockIndex::default().find("example.com"), None);
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let index = BlockIndex::build(
+            [
+                ("a.com", "list1"),
+                ("a.com", "list2"),
+                ("a.com", "list3"),
+                ("b.com", "list1"),
+                ("b.com", "list2"),
+                ("c.com", "list3"),
+                ("c.com", "list3"),
+            ]
+            .map(|(d, s)| (d.to_owned(), s.to_owned())),
+        )
+        .unwrap();
+        assert_eq!(index.len(), 3);
+        let overlaps = index.overlaps();
+        assert_eq!(overlaps.len(), 3);
+        assert_eq!(overlaps[&("list1", "list2")], 2);
+        assert_eq!(overlaps[&("list1", "list3")], 1);
+        assert_eq!(overlaps[&("list2", "list3")], 1);
+    }
+}
@@ -1,100 +1,462 @@
-use chrono::Local;
+use chrono::{Local, NaiveDateTime};
+use std::collections::HashSet;
+use std::fmt;
 use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use futures_util::{Stream, StreamExt};
+use futures_util::{Stream, TryStreamExt};
 use log::*;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::time;
 
+use crate::block_index::reload_index;
 use crate::db::block_list::{
-    blocked_domain_last_updated, clear_blocked_domain, insert_blocked_domain, load_block_list,
-    update_block_list, DbBlockList,
+    delete_listed_domain, delete_orphan_domains, insert_listed_domain, load_block_list,
+    load_source_domains, update_block_list, DbBlockList, ListKind,
 };
-use crate::db::{db, vacuum};
+use crate::db::db;
+use crate::list_format::{ListFormat, ListParser, ParsedList, DETECT_LINES};
 
+/// `ETag` and `Last-Modified` of the content a source was fetched from the last time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// The result of fetching a source with the validators of its last content. The source is made
+/// of documents, e.g. the files of a directory, each one is parsed in its own format.
+/// The HTTP status of the response is kept for the source, there's none for a local one.
+/// The fetcher counts the bytes of the content as they're read, before they're decompressed.
+pub enum Fetched<S> {
+    Modified(Vec<S>, Validators, Option<u16>, ByteCount),
+    NotModified(Option<u16>),
+}
+
+pub type ByteCount = Arc<AtomicUsize>;
+
+/// The error of a fetch which got a response with a failure status, so the status is kept
+#[derive(Debug)]
+pub struct HttpStatusError(pub u16);
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP status {}", self.0)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// The delay before the first retry of a failed source, doubled on every failure after it
+const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Refreshes the blocked domains of every source from the lines `fun` fetches once it's
+/// [`is_due`], only the domains which were added to or removed from a source are written.
+/// The sources coming through `receiver` have changed and are refreshed right away.
+/// A source which fails to download, or whose content doesn't pass [`check_domain_count`],
+/// keeps its previous domains and is retried on its own after [`retry_delay`].
 pub async fn update_blocked_domains<F, S>(
-    mut receiver: UnboundedReceiver<()>,
-    fun: impl Fn(String) -> F,
+    mut receiver: UnboundedReceiver<Vec<String>>,
+    fun: impl Fn(String, Validators) -> F,
 ) -> anyhow::Result<()>
 where
-    F: Future<Output = anyhow::Result<S>>,
-    S: Stream<Item = String> + Unpin,
+    F: Future<Output = anyhow::Result<Fetched<S>>>,
+    S: Stream<Item = anyhow::Result<String>> + Unpin,
 {
     loop {
-        let should_refresh = match time::timeout(Duration::from_secs(30), receiver.recv()).await {
-            Ok(Some(_)) => true,
-            _ => should_update().await?,
-        };
-        if !should_refresh {
-            continue;
+        let mut signaled = HashSet::new();
+        if let Ok(Some(srcs)) = time::timeout(Duration::from_secs(30), receiver.recv()).await {
+            signaled.extend(srcs);
+            while let Ok(srcs) = receiver.try_recv() {
+                signaled.extend(srcs);
+            }
         }
-        info!("Time to update the blocked domains list.");
-
         let updated = Local::now().naive_local();
-        let mut trans = db().begin().await?;
-
-        let drop_count = clear_blocked_domain(&mut trans).await?;
-        info!("Dropped {drop_count} blocked domains");
-
-        let (mut start, mut insert_count, mut total_count) = (Instant::now(), 0, 0);
-        for mut bl in load_block_list().await? {
-            let DbBlockList {
-                src, retry_count, ..
-            } = &bl;
-            if *retry_count > 3 {
-                warn!("{src} has been retried for {retry_count}, skipping");
-                continue;
-            }
+        let due = load_block_list()
+            .await?
+            .into_iter()
+            .filter(|bl| is_due(bl, &signaled, updated))
+            .collect::<Vec<_>>();
+        // A signal for a removed source has nothing to fetch, only its domains to drop
+        if due.is_empty() && signaled.is_empty() {
+            continue;
+        }
+        info!("Time to update {} of the block list sources.", due.len());
 
-            debug!("Loading blocked domains from {src}");
-            let (retry_count, domain_count) = match fun(src.clone()).await {
-                Ok(mut domain_stream) => {
-                    let mut domain_count = 0;
-                    while let Some(domain) = domain_stream.next().await {
-                        if insert_blocked_domain(&mut trans, &domain, src, updated).await {
-                            insert_count += 1;
-                            domain_count += 1;
-                        }
-                        total_count += 1;
-
-                        if start.elapsed().as_secs() >= 10 {
-                            debug!("Inserted {insert_count}/{total_count} domains so far");
-                            start = Instant::now();
-                        }
-                    }
-                    (0, domain_count)
+        // The kinds whose domains have changed
+        let mut changed = HashSet::new();
+        for mut bl in due {
+            let src = &bl.src;
+            let kind = bl.kind();
+            debug!("Loading {kind}ed domains from {src}");
+            // Without any domains of the source, its content has to be fetched in full
+            let validators = if bl.domain_count >= 0 {
+                Validators {
+                    etag: bl.etag.clone(),
+                    last_modified: bl.last_modified.clone(),
+                }
+            } else {
+                Validators::default()
+            };
+            let start = Instant::now();
+            let fetched = match fun(src.clone(), validators).await {
+                Ok(Fetched::Modified(documents, validators, status, bytes)) => {
+                    bl.http_status = status.map(i64::from);
+                    parse_documents(&bl, documents)
+                        .await
+                        .map(|parsed| Some((parsed, bytes.load(Ordering::Relaxed), validators)))
+                }
+                Ok(Fetched::NotModified(status)) => {
+                    bl.http_status = status.map(i64::from);
+                    Ok(None)
                 }
                 Err(e) => {
-                    warn!("Failed to get the domains from {src}: {e} ");
-                    (retry_count + 1, -1)
+                    bl.http_status = e.downcast_ref().map(|HttpStatusError(s)| i64::from(*s));
+                    Err(e)
                 }
             };
-            bl.retry_count = retry_count;
-            bl.domain_count = domain_count;
+            bl.fetch_millis = Some(start.elapsed().as_millis() as i64);
+            let checked = fetched.and_then(|fetched| match fetched {
+                Some((parsed, bytes, validators)) => {
+                    check_domain_count(&bl, parsed.domains.len())?;
+                    Ok(Some((parsed, bytes, validators)))
+                }
+                None => Ok(None),
+            });
+            match checked {
+                Ok(Some((parsed, bytes, validators))) => {
+                    if parsed.invalid > 0 {
+                        warn!(
+                            "{src}: {} lines couldn't be parsed, e.g. {:?}",
+                            parsed.invalid, parsed.invalid_samples
+                        );
+                    }
+                    if apply_diff(kind, src, &parsed.domains, updated).await? {
+                        changed.insert(kind);
+                    }
+                    bl.retry_count = 0;
+                    bl.next_retry = None;
+                    bl.domain_count = parsed.domains.len() as i64;
+                    bl.invalid_count = parsed.invalid as i64;
+                    bl.etag = validators.etag;
+                    bl.last_modified = validators.last_modified;
+                    bl.warning = None;
+                    bl.fetch_bytes = Some(bytes as i64);
+                }
+                Ok(None) => {
+                    info!("{src} hasn't been modified since it was fetched");
+                    bl.retry_count = 0;
+                    bl.next_retry = None;
+                    bl.warning = None;
+                }
+                Err(e) => {
+                    bl.retry_count += 1;
+                    let delay = retry_delay(bl.retry_count);
+                    warn!(
+                        "Failed to get the domains from {src}, keeping the old ones and retrying \
+                        in {delay:?}: {e:#}"
+                    );
+                    bl.next_retry = chrono::Duration::from_std(delay).ok().map(|d| updated + d);
+                    bl.warning = Some(format!("{e:#}"));
+                }
+            }
             bl.last_updated = updated;
+            let mut trans = db().begin().await?;
             update_block_list(&mut trans, bl).await?;
+            trans.commit().await?;
+        }
+
+        for kind in ListKind::ALL {
+            let orphans = delete_orphan_domains(kind).await?;
+            if orphans > 0 {
+                info!("Dropped {orphans} {kind}ed domains of the removed sources");
+                changed.insert(kind);
+            }
+            if changed.contains(&kind) {
+                reload_index(kind).await?;
+            }
         }
-        trans.commit().await?;
-        vacuum().await?;
-        info!("Inserted {insert_count} of {total_count} blocked domains");
     }
 }
 
-async fn should_update() -> anyhow::Result<bool> {
-    use chrono::Duration;
-
-    let block_list = load_block_list().await?;
-    let unprocessed = block_list.iter().filter(|bl| bl.domain_count == -1).count();
-
-    if block_list.len() == unprocessed {
-        debug!("All block list are unprocessed, refreshing the blocked domains");
-        Ok(true)
-    } else if let Some(last_updated) = blocked_domain_last_updated().await? {
-        debug!("Blocked domains were last updated at: {last_updated}");
-        Ok(Local::now().naive_local() - last_updated > Duration::days(7))
-    } else {
-        debug!("No entry found in blocked domain, forcing refresh");
-        Ok(true)
+/// How long to wait after the `retry_count`th failure in a row
+fn retry_delay(retry_count: i64) -> Duration {
+    let doublings = retry_count.clamp(1, 32) as u32 - 1;
+    RETRY_DELAY
+        .saturating_mul(1 << doublings)
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Parses every document of the source, a failure while reading any of them fails it all
+async fn parse_documents<S>(bl: &DbBlockList, documents: Vec<S>) -> anyhow::Result<ParsedList>
+where
+    S: Stream<Item = anyhow::Result<String>> + Unpin,
+{
+    let format = source_format(bl);
+    let mut parsed = ParsedList::default();
+    for mut lines in documents {
+        // Only the leading lines are held to detect the format, the rest are parsed as they come.
+        // A list cut short by a failure would drop the domains it's missing, so it's all dropped.
+        let mut head = Vec::new();
+        let mut non_blank = 0;
+        while non_blank < DETECT_LINES {
+            match lines.try_next().await? {
+                Some(line) => {
+                    if !line.trim().is_empty() {
+                        non_blank += 1;
+                    }
+                    head.push(line);
+                }
+                None => break,
+            }
+        }
+        let format = format.unwrap_or_else(|| ListFormat::detect(&head));
+        let mut parser = ListParser::new(format);
+        let mut count = head.len();
+        for line in head {
+            parser.push(&line);
+        }
+        while let Some(line) = lines.try_next().await? {
+            count += 1;
+            parser.push(&line);
+        }
+        info!("{}: parsed {count} lines as {format}", bl.src);
+        parsed.merge(parser.finish());
+    }
+    Ok(parsed)
+}
+
+/// Rejects a refreshed list which is too small, or has shrunk too much since the last refresh,
+/// as it's more likely an empty file or an error page than the list the source has published
+fn check_domain_count(bl: &DbBlockList, count: usize) -> anyhow::Result<()> {
+    let count = count as i64;
+    let min_domains = bl.min_domains();
+    if count < min_domains {
+        anyhow::bail!("Got {count} domains, fewer than the minimum of {min_domains}");
+    }
+    let previous = bl.domain_count;
+    let max_shrink = bl.max_shrink();
+    if previous > 0 && (previous - count) * 100 > previous * max_shrink {
+        anyhow::bail!("Got {count} domains, shrinking by more than {max_shrink}% from {previous}");
+    }
+    Ok(())
+}
+
+/// The format set for the source, `None` to detect it
+fn source_format(bl: &DbBlockList) -> Option<ListFormat> {
+    let format = bl.format.as_deref()?;
+    format
+        .parse()
+        .map_err(|e| warn!("{}: {e}, detecting the format instead", bl.src))
+        .ok()
+}
+
+/// Writes the changes in the domains of a source, returns whether anything has changed.
+/// A domain is listed once for every source which has it.
+async fn apply_diff(
+    kind: ListKind,
+    src: &str,
+    domains: &HashSet<String>,
+    updated: NaiveDateTime,
+) -> anyhow::Result<bool> {
+    let existing = load_source_domains(kind, src).await?;
+    let diff = Diff::new(&existing, domains);
+    if diff.is_empty() {
+        info!("{src} is unchanged with {} domains", existing.len());
+        return Ok(false);
+    }
+
+    let start = Instant::now();
+    let mut trans = db().begin().await?;
+    for domain in &diff.removed {
+        delete_listed_domain(&mut trans, kind, domain, src).await?;
+    }
+    for domain in &diff.added {
+        insert_listed_domain(&mut trans, kind, domain, src, updated).await?;
+    }
+    trans.commit().await?;
+    info!(
+        "{src}: added {}, removed {} domains in {:?}",
+        diff.added.len(),
+        diff.removed.len(),
+        start.elapsed()
+    );
+    Ok(true)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Diff<'a> {
+    added: Vec<&'a str>,
+    removed: Vec<&'a str>,
+}
+
+impl<'a> Diff<'a> {
+    fn new(existing: &'a HashSet<String>, fetched: &'a HashSet<String>) -> Self {
+        Diff {
+            added: fetched.difference(existing).map(String::as_str).collect(),
+            removed: existing.difference(fetched).map(String::as_str).collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Whether the source should be fetched now, the `signaled` sources are fetched unless they're
+/// disabled or have failed, those wait for their retry
+fn is_due(bl: &DbBlockList, signaled: &HashSet<String>, now: NaiveDateTime) -> bool {
+    if !bl.enabled {
+        return false;
+    }
+    match bl.next_retry {
+        Some(next_retry) => next_retry <= now,
+        None => {
+            let interval = chrono::Duration::hours(bl.refresh_hours());
+            signaled.contains(&bl.src) || bl.domain_count == -1 || now - bl.last_updated >= interval
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use chrono::{Duration, Local};
+    use futures_util::{stream, StreamExt};
+
+    use super::{
+        check_domain_count, is_due, parse_documents, retry_delay, Diff, MAX_RETRY_DELAY,
+        RETRY_DELAY,
+    };
+    use crate::db::block_list::DbBlockList;
+    use crate::list_format::DETECT_LINES;
+
+    fn set(domains: &[&str]) -> HashSet<String> {
+        domains.iter().map(|d| d.to_string()).collect()
+    }
+
+    fn block_list() -> DbBlockList {
+        DbBlockList {
+            bl_id: 1,
+            src: "https://example.com/hosts".into(),
+            retry_count: 0,
+            domain_count: -1,
+            last_updated: Local::now().naive_local(),
+            version: 1,
+            hit_count: 0,
+            last_hit: None,
+            etag: None,
+            last_modified: None,
+            format: None,
+            invalid_count: 0,
+            kind: "block".into(),
+            min_domains: None,
+            max_shrink: None,
+            warning: None,
+            next_retry: None,
+            http_status: None,
+            fetch_millis: None,
+            fetch_bytes: None,
+            name: None,
+            category: None,
+            enabled: true,
+            refresh_hours: None,
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let existing = set(&["a.com", "b.com", "c.com"]);
+        let fetched = set(&["b.com", "c.com", "d.com"]);
+        let diff = Diff::new(&existing, &fetched);
+        assert_eq!(diff.added, vec!["d.com"]);
+        assert_eq!(diff.removed, vec!["a.com"]);
+        assert!(!diff.is_empty());
+        assert!(Diff::new(&existing, &existing.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_check_domain_count() {
+        let mut bl = block_list();
+        assert!(check_domain_count(&bl, 0).is_err());
+        // Nothing to shrink from before the first refresh
+        assert!(check_domain_count(&bl, 1).is_ok());
+
+        bl.domain_count = 1000;
+        assert!(check_domain_count(&bl, 500).is_ok());
+        assert!(check_domain_count(&bl, 499).is_err());
+        assert!(check_domain_count(&bl, 5000).is_ok());
+
+        bl.max_shrink = Some(100);
+        bl.min_domains = Some(10);
+        assert!(check_domain_count(&bl, 10).is_ok());
+        assert!(check_domain_count(&bl, 9).is_err());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), RETRY_DELAY);
+        assert_eq!(retry_delay(2), RETRY_DELAY * 2);
+        assert_eq!(retry_delay(4), RETRY_DELAY * 8);
+        assert_eq!(retry_delay(10), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(1000), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_is_due() {
+        let mut bl = block_list();
+        let now = bl.last_updated;
+        let none = HashSet::new();
+        let signaled = HashSet::from([bl.src.clone()]);
+        let others = HashSet::from(["https://example.com/other.txt".to_owned()]);
+        // Never fetched
+        assert!(is_due(&bl, &none, now));
+
+        bl.domain_count = 10;
+        assert!(!is_due(&bl, &none, now));
+        assert!(is_due(&bl, &signaled, now));
+        assert!(!is_due(&bl, &others, now));
+        assert!(is_due(&bl, &none, now + Duration::days(7)));
+        bl.refresh_hours = Some(6);
+        assert!(!is_due(&bl, &none, now + Duration::hours(5)));
+        assert!(is_due(&bl, &none, now + Duration::hours(6)));
+
+        bl.next_retry = Some(now + Duration::minutes(5));
+        assert!(!is_due(&bl, &signaled, now));
+        assert!(is_due(&bl, &none, now + Duration::minutes(5)));
+
+        bl.enabled = false;
+        assert!(!is_due(&bl, &signaled, now + Duration::days(30)));
+    }
+
+    #[tokio::test]
+    async fn test_parse_documents() {
+        let lines = |lines: Vec<String>| stream::iter(lines.into_iter().map(Ok));
+        // The format is detected from the leading lines, the later ones are parsed in it
+        let mut adguard = vec![String::new(); 10];
+        adguard.extend((0..DETECT_LINES).map(|i| format!("||ads{i}.example.com^")));
+        adguard.push("example.net".into());
+        adguard.push("0.0.0.0 tracker.example.org".into());
+        let hosts = vec!["0.0.0.0 tracker.net".to_string(), "a b c".to_string()];
+        let bl = block_list();
+        let parsed = parse_documents(&bl, vec![lines(adguard.clone()), lines(hosts)])
+            .await
+            .unwrap();
+        assert_eq!(parsed.domains.len(), DETECT_LINES + 3);
+        assert!(parsed.domains.contains("ads999.example.com"));
+        assert!(parsed.domains.contains("example.net"));
+        assert!(parsed.domains.contains("tracker.example.org"));
+        assert!(parsed.domains.contains("tracker.net"));
+        assert_eq!(parsed.invalid, 1);
+
+        // Whatever was parsed before the failure is dropped
+        let failing = stream::iter(adguard.into_iter().map(Ok))
+            .chain(stream::iter([Err(anyhow::anyhow!("Connection reset"))]));
+        let result = parse_documents(&bl, vec![Box::pin(failing)]).await;
+        assert!(result.is_err());
     }
 }
@@ -1,5 +1,11 @@
-use crate::db::db;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::db::{db, error_message};
+use crate::domain_name::canonical;
 use chrono::NaiveDateTime;
+use futures_util::TryStreamExt;
 
 use itertools::Itertools;
 
@@ -12,6 +18,179 @@ pub struct DbBlockList {
     pub retry_count: i64,
     pub domain_count: i64,
     pub last_updated: NaiveDateTime,
+    /// Bumped on every change by the user, for optimistic concurrency
+    pub version: i64,
+    /// How many queries were blocked by a domain from this source, see [`crate::hits`]
+    pub hit_count: i64,
+    pub last_hit: Option<NaiveDateTime>,
+    /// `ETag` and `Last-Modified` of the content the domains came from, for conditional fetches
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// One of [`crate::list_format::ListFormat`], it's detected from the content if not set
+    pub format: Option<String>,
+    /// How many lines of the content couldn't be parsed
+    pub invalid_count: i64,
+    /// One of [`ListKind`]
+    pub kind: String,
+    /// A refreshed list with fewer domains is rejected, [`DEFAULT_MIN_DOMAINS`] if not set
+    pub min_domains: Option<i64>,
+    /// A refreshed list shrinking by more percent of its domains is rejected,
+    /// [`DEFAULT_MAX_SHRINK`] if not set
+    pub max_shrink: Option<i64>,
+    /// Why the last refresh failed or was rejected, cleared once one succeeds
+    pub warning: Option<String>,
+    /// When a failed source is fetched again, it's skipped until then
+    pub next_retry: Option<NaiveDateTime>,
+    /// The status of the last response, `None` for local sources
+    pub http_status: Option<i64>,
+    /// How long the last fetch took, including the parsing of its content
+    pub fetch_millis: Option<i64>,
+    /// The size of the content the domains came from, after decompression
+    pub fetch_bytes: Option<i64>,
+    /// Shown instead of `src` if set
+    pub name: Option<String>,
+    /// One of [`ListCategory`]
+    pub category: Option<String>,
+    /// The domains of a disabled source are kept, but neither used nor refreshed
+    pub enabled: bool,
+    /// How often the source is refreshed, [`DEFAULT_REFRESH_HOURS`] if not set
+    pub refresh_hours: Option<i64>,
+}
+
+pub const DEFAULT_MIN_DOMAINS: i64 = 1;
+pub const DEFAULT_MAX_SHRINK: i64 = 50;
+pub const DEFAULT_REFRESH_HOURS: i64 = 7 * 24;
+
+impl DbBlockList {
+    pub fn kind(&self) -> ListKind {
+        self.kind.parse().unwrap_or(ListKind::Block)
+    }
+
+    pub fn min_domains(&self) -> i64 {
+        self.min_domains.unwrap_or(DEFAULT_MIN_DOMAINS)
+    }
+
+    pub fn max_shrink(&self) -> i64 {
+        self.max_shrink.unwrap_or(DEFAULT_MAX_SHRINK)
+    }
+
+    pub fn refresh_hours(&self) -> i64 {
+        self.refresh_hours.unwrap_or(DEFAULT_REFRESH_HOURS)
+    }
+
+    pub fn category(&self) -> Option<ListCategory> {
+        self.category.as_deref()?.parse().ok()
+    }
+
+    /// How the source is shown
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.src)
+    }
+}
+
+/// Whether the domains of a source are blocked or allowed, the allowed ones win
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ListKind {
+    Block,
+    Allow,
+}
+
+impl ListKind {
+    pub const ALL: [ListKind; 2] = [ListKind::Block, ListKind::Allow];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListKind::Block => "block",
+            ListKind::Allow => "allow",
+        }
+    }
+
+    /// Where the domains of the sources of this kind are kept
+    fn table(&self) -> &'static str {
+        match self {
+            ListKind::Block => "blocked_domains",
+            ListKind::Allow => "allowed_domains",
+        }
+    }
+}
+
+impl fmt::Display for ListKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ListKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ListKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| format!("Unknown kind: {s}, expected block or allow"))
+    }
+}
+
+/// What a source lists, the blocks of its domains can be grouped by it.
+/// Ordered by severity, a domain in several lists counts as the most severe one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ListCategory {
+    Ads,
+    Tracking,
+    Adult,
+    Malware,
+}
+
+impl ListCategory {
+    pub const ALL: [ListCategory; 4] = [
+        ListCategory::Ads,
+        ListCategory::Tracking,
+        ListCategory::Adult,
+        ListCategory::Malware,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListCategory::Ads => "ads",
+            ListCategory::Tracking => "tracking",
+            ListCategory::Adult => "adult",
+            ListCategory::Malware => "malware",
+        }
+    }
+}
+
+impl fmt::Display for ListCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ListCategory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ListCategory::ALL
+            .into_iter()
+            .find(|category| category.as_str().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| {
+                format!("Unknown category: {s}, expected ads, tracking, adult or malware")
+            })
+    }
+}
+
+/// What the user sets for a source
+#[derive(Debug, Clone)]
+pub struct BlockListSettings {
+    pub src: String,
+    /// Detected from the content if not set
+    pub format: Option<String>,
+    pub kind: ListKind,
+    pub min_domains: Option<i64>,
+    pub max_shrink: Option<i64>,
+    pub name: Option<String>,
+    pub category: Option<ListCategory>,
+    pub enabled: bool,
+    pub refresh_hours: Option<i64>,
 }
 
 pub async fn load_block_list() -> anyhow::Result<Vec<DbBlockList>> {
@@ -23,19 +202,172 @@ pub async fn load_block_list() -> anyhow::Result<Vec<DbBlockList>> {
     .await?)
 }
 
-pub async fn save_block_list(list: impl IntoIterator<Item = DbBlockList>) -> anyhow::Result<()> {
+pub async fn find_block_list(bl_id: i64) -> anyhow::Result<Option<DbBlockList>> {
+    Ok(
+        sqlx::query_as!(DbBlockList, "select * from block_list where bl_id=?", bl_id)
+            .fetch_optional(db())
+            .await?,
+    )
+}
+
+/// Inserts all of the sources or none of them, like [`crate::db::filters::create_filters`]
+pub async fn create_block_lists(
+    srcs: &[BlockListSettings],
+) -> anyhow::Result<Vec<Result<i64, String>>> {
     let mut trans = db().begin().await?;
-    sqlx::query!("delete from block_list")
+    let mut results = Vec::with_capacity(srcs.len());
+    for settings in srcs {
+        let kind = settings.kind.as_str();
+        let category = settings.category.map(|c| c.as_str());
+        let result = sqlx::query!(
+            r"
+            insert into block_list(
+                src, format, kind, min_domains, max_shrink, name, category, enabled, refresh_hours
+            )
+            values(?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ",
+            settings.src,
+            settings.format,
+            kind,
+            settings.min_domains,
+            settings.max_shrink,
+            settings.name,
+            category,
+            settings.enabled,
+            settings.refresh_hours,
+        )
+        .execute(&mut trans)
+        .await;
+        results.push(result.map(|r| r.last_insert_rowid()).map_err(error_message));
+    }
+    if results.iter().all(Result::is_ok) {
+        trans.commit().await?;
+    } else {
+        trans.rollback().await?;
+    }
+    Ok(results)
+}
+
+/// Replaces the settings of the entry if its version is still `version`, returns false otherwise.
+/// A change of the source, its format or its kind resets the fetch status, so the next update
+/// downloads it all over again.
+pub async fn update_block_list_src(
+    bl_id: i64,
+    settings: &BlockListSettings,
+    version: i64,
+) -> anyhow::Result<bool> {
+    let kind = settings.kind.as_str();
+    let category = settings.category.map(|c| c.as_str());
+    let mut trans = db().begin().await?;
+    let old = sqlx::query!(
+        "select src, format, kind from block_list where bl_id=? and version=?",
+        bl_id,
+        version
+    )
+    .fetch_optional(&mut trans)
+    .await?;
+    let old = match old {
+        Some(old) => old,
+        None => return Ok(false),
+    };
+    sqlx::query!(
+        r"
+        update block_list
+        set src=?, format=?, kind=?, min_domains=?, max_shrink=?, name=?, category=?, enabled=?,
+            refresh_hours=?, version=version+1
+        where bl_id=?
+        ",
+        settings.src,
+        settings.format,
+        kind,
+        settings.min_domains,
+        settings.max_shrink,
+        settings.name,
+        category,
+        settings.enabled,
+        settings.refresh_hours,
+        bl_id,
+    )
+    .execute(&mut trans)
+    .await?;
+    if old.src != settings.src || old.format != settings.format || old.kind != kind {
+        sqlx::query!(
+            r"
+            update block_list
+            set retry_count=0, next_retry=null, domain_count=-1, etag=null, last_modified=null,
+                warning=null
+            where bl_id=?
+            ",
+            bl_id,
+        )
         .execute(&mut trans)
         .await?;
-    for bl in list {
+    }
+    trans.commit().await?;
+    Ok(true)
+}
+
+/// Deletes the entry if its version is still `version`, returns false otherwise
+pub async fn delete_block_list(bl_id: i64, version: i64) -> anyhow::Result<bool> {
+    Ok(sqlx::query!(
+        "delete from block_list where bl_id=? and version=?",
+        bl_id,
+        version
+    )
+    .execute(db())
+    .await?
+    .rows_affected()
+        == 1)
+}
+
+/// Replaces all the sources, the ones which are kept stay as they are, with their settings,
+/// validators and fetch status
+pub async fn save_block_list(list: impl IntoIterator<Item = DbBlockList>) -> anyhow::Result<()> {
+    let mut trans = db().begin().await?;
+    let existing = sqlx::query!("select bl_id, src from block_list")
+        .fetch_all(&mut trans)
+        .await?
+        .into_iter()
+        .map(|row| (row.src, row.bl_id))
+        .collect::<HashMap<_, _>>();
+    let list = list
+        .into_iter()
+        .map(|bl| (bl.src.clone(), bl))
+        .collect::<HashMap<_, _>>();
+    for (src, bl_id) in &existing {
+        if !list.contains_key(src) {
+            sqlx::query!("delete from block_list where bl_id=?", bl_id)
+                .execute(&mut trans)
+                .await?;
+        }
+    }
+    for bl in list.into_values() {
+        if existing.contains_key(&bl.src) {
+            continue;
+        }
         let _ = sqlx::query!(
             r"
-            insert into block_list(src, last_updated)
-            values(?, ?)
+            insert into block_list(
+                src, last_updated, hit_count, last_hit, etag, last_modified, format, kind,
+                min_domains, max_shrink, warning, name, category, enabled, refresh_hours
+            )
+            values(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             ",
             bl.src,
             bl.last_updated,
+            bl.hit_count,
+            bl.last_hit,
+            bl.etag,
+            bl.last_modified,
+            bl.format,
+            bl.kind,
+            bl.min_domains,
+            bl.max_shrink,
+            bl.warning,
+            bl.name,
+            bl.category,
+            bl.enabled,
+            bl.refresh_hours,
         )
         .execute(&mut trans)
         .await?;
@@ -43,11 +375,22 @@ pub async fn save_block_list(list: impl IntoIterator<Item = DbBlockList>) -> any
     Ok(trans.commit().await?)
 }
 
+/// Looks the name up in the db, queries are served by [`crate::block_index`] instead
 pub async fn find_blocked_domain(
     name: impl AsRef<str>,
 ) -> anyhow::Result<Option<(String, String)>> {
+    Ok(find_listed_domains(ListKind::Block, name)
+        .await?
+        .into_iter()
+        .next())
+}
+
+/// All the entries of the kind matching the name, either itself or one of its parents
+pub async fn find_listed_domains(
+    kind: ListKind,
+    name: impl AsRef<str>,
+) -> anyhow::Result<Vec<(String, String)>> {
     fn sub_names(name: &str) -> Vec<&str> {
-        let name = name.strip_suffix('.').unwrap_or(name);
         let mut result = Vec::with_capacity(name.split('.').count());
         result.push(name);
         result.extend(
@@ -58,34 +401,90 @@ pub async fn find_blocked_domain(
         result
     }
 
-    let names = sub_names(name.as_ref());
+    let name = canonical(name.as_ref());
+    let names = sub_names(&name);
     let query = format!(
-        "select domain_name, source from blocked_domains where domain_name in ({})",
+        "select domain_name, source from {} where domain_name in ({}) and {ENABLED_SOURCE}",
+        kind.table(),
         (0..names.len()).map(|_| '?').join(", ")
     );
     let mut query = sqlx::query_as(&query);
     for name in names {
         query = query.bind(name);
     }
-    Ok(query.fetch_optional(db()).await?)
+    Ok(query.fetch_all(db()).await?)
 }
 
-pub(crate) async fn blocked_domain_last_updated() -> anyhow::Result<Option<NaiveDateTime>> {
-    Ok(sqlx::query_as::<_, (i64, NaiveDateTime)>(
-        r#"select bd_id, updated from blocked_domains order by bd_id desc limit 5"#,
-    )
-    .fetch_optional(db())
+/// The domains of the disabled sources are left out of the lookups
+const ENABLED_SOURCE: &str = "source in (select src from block_list where enabled)";
+
+/// Calls `f` with every domain of the enabled sources of the kind and the `bl_id` of a source
+/// listing it, as the rows come. The domains are in byte order, once for each of their sources.
+pub(crate) async fn for_each_listed_domain(
+    kind: ListKind,
+    mut f: impl FnMut(&str, i64) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let query = format!(
+        r"
+        select rtrim(domain_name, '.') as domain, bl_id
+        from {} join block_list on src=source
+        where enabled
+        order by domain
+        ",
+        kind.table()
+    );
+    let mut rows = sqlx::query_as::<_, (String, i64)>(&query).fetch(db());
+    while let Some((domain, bl_id)) = rows.try_next().await? {
+        f(&domain, bl_id)?;
+    }
+    Ok(())
+}
+
+/// The domains credited to the source
+pub(crate) async fn load_source_domains(
+    kind: ListKind,
+    src: &str,
+) -> anyhow::Result<HashSet<String>> {
+    Ok(sqlx::query_as::<_, (String,)>(&format!(
+        "select domain_name from {} where source=?",
+        kind.table()
+    ))
+    .bind(src)
+    .fetch_all(db())
     .await?
-    .map(|(_, updated)| updated))
+    .into_iter()
+    .map(|(domain,)| domain)
+    .collect())
 }
 
-pub(crate) async fn clear_blocked_domain(
+/// Drops the domains of the sources of the kind which aren't in the block list anymore,
+/// including the ones which have changed their kind
+pub(crate) async fn delete_orphan_domains(kind: ListKind) -> anyhow::Result<u64> {
+    Ok(sqlx::query(&format!(
+        "delete from {} where source not in (select src from block_list where kind=?)",
+        kind.table()
+    ))
+    .bind(kind.as_str())
+    .execute(db())
+    .await?
+    .rows_affected())
+}
+
+pub(crate) async fn delete_listed_domain(
     trans: &mut Transaction<'_, Sqlite>,
-) -> anyhow::Result<u64> {
-    Ok(sqlx::query!("delete from blocked_domains")
-        .execute(trans)
-        .await?
-        .rows_affected())
+    kind: ListKind,
+    domain: &str,
+    src: &str,
+) -> anyhow::Result<()> {
+    sqlx::query(&format!(
+        "delete from {} where domain_name=? and source=?",
+        kind.table()
+    ))
+    .bind(domain)
+    .bind(src)
+    .execute(trans)
+    .await?;
+    Ok(())
 }
 
 pub(crate) async fn update_block_list(
@@ -94,13 +493,23 @@ pub(crate) async fn update_block_list(
 ) -> anyhow::Result<()> {
     sqlx::query!(
         r"
-        update block_list 
-        set retry_count=?, domain_count=?, last_updated=?
+        update block_list
+        set retry_count=?, next_retry=?, domain_count=?, last_updated=?, etag=?,
+            last_modified=?, invalid_count=?, warning=?, http_status=?, fetch_millis=?,
+            fetch_bytes=?
         where bl_id=?
         ",
         bl.retry_count,
+        bl.next_retry,
         bl.domain_count,
         bl.last_updated,
+        bl.etag,
+        bl.last_modified,
+        bl.invalid_count,
+        bl.warning,
+        bl.http_status,
+        bl.fetch_millis,
+        bl.fetch_bytes,
         bl.bl_id,
     )
     .execute(trans)
@@ -108,19 +517,38 @@ pub(crate) async fn update_block_list(
     Ok(())
 }
 
-pub(crate) async fn insert_blocked_domain(
+pub(crate) async fn insert_listed_domain(
     trans: &mut Transaction<'_, Sqlite>,
+    kind: ListKind,
     domain: &str,
     src: &str,
     updated: NaiveDateTime,
-) -> bool {
+) -> anyhow::Result<()> {
+    sqlx::query(&format!(
+        "insert into {}(domain_name, source, updated) values(?, ?, ?)",
+        kind.table()
+    ))
+    .bind(domain)
+    .bind(src)
+    .bind(updated)
+    .execute(trans)
+    .await?;
+    Ok(())
+}
+
+pub(crate) async fn add_source_hits(
+    trans: &mut Transaction<'_, Sqlite>,
+    src: &str,
+    count: i64,
+    last_hit: NaiveDateTime,
+) -> anyhow::Result<()> {
     sqlx::query!(
-        r"insert into blocked_domains(domain_name, source, updated) values(?, ?, ?)",
-        domain,
+        "update block_list set hit_count=hit_count+?, last_hit=? where src=?",
+        count,
+        last_hit,
         src,
-        updated,
     )
     .execute(trans)
-    .await
-    .is_ok()
+    .await?;
+    Ok(())
 }
@@ -1,5 +1,8 @@
-use crate::db::db;
+use std::collections::HashMap;
+
+use crate::db::{db, error_message};
 use chrono::NaiveDateTime;
+use sqlx::{Sqlite, Transaction};
 
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct DbFilter {
@@ -9,6 +12,15 @@ pub struct DbFilter {
     pub is_regex: bool,
     pub enabled: bool,
     pub is_allow: bool,
+    /// Canonical adblock modifiers, e.g. `important,client=10.0.0.0/8`, empty if there are none
+    pub modifiers: String,
+    /// Bumped on every change, for optimistic concurrency
+    pub version: i64,
+    /// How many queries this filter decided, see [`crate::hits`]
+    pub hit_count: i64,
+    pub last_hit: Option<NaiveDateTime>,
+    /// The filter is disabled once it's past this time
+    pub expires_at: Option<NaiveDateTime>,
 }
 
 pub async fn load_filters() -> anyhow::Result<Vec<DbFilter>> {
@@ -28,24 +40,229 @@ pub async fn load_all_filters() -> anyhow::Result<Vec<DbFilter>> {
     )
 }
 
+/// Replaces all the filters, the ones which are kept stay as they are, with their ids, versions,
+/// hit stats and expiry, unless they were enabled or disabled, or turned from block to allow
 pub async fn save_filters(filters: impl IntoIterator<Item = DbFilter>) -> anyhow::Result<()> {
     let mut trans = db().begin().await?;
-    sqlx::query!("delete from filters")
-        .execute(&mut trans)
-        .await?;
+    let existing = sqlx::query!("select f_id, expr, is_regex, modifiers from filters")
+        .fetch_all(&mut trans)
+        .await?
+        .into_iter()
+        .map(|r| ((r.expr, r.is_regex, r.modifiers), r.f_id))
+        .collect::<HashMap<_, _>>();
+    let filters = filters
+        .into_iter()
+        .map(|df| ((df.expr.clone(), df.is_regex, df.modifiers.clone()), df))
+        .collect::<HashMap<_, _>>();
+    for (key, f_id) in &existing {
+        if !filters.contains_key(key) {
+            sqlx::query!("delete from filters where f_id=?", f_id)
+                .execute(&mut trans)
+                .await?;
+        }
+    }
+    for (key, filter) in filters {
+        match existing.get(&key) {
+            Some(f_id) => {
+                sqlx::query!(
+                    r#"
+                    update filters set enabled=?, is_allow=?, version=version+1
+                    where f_id=? and (enabled!=? or is_allow!=?)
+                    "#,
+                    filter.enabled,
+                    filter.is_allow,
+                    f_id,
+                    filter.enabled,
+                    filter.is_allow,
+                )
+                .execute(&mut trans)
+                .await?;
+            }
+            None => {
+                sqlx::query!(
+                    r#"
+                    insert into filters(expr, is_regex, enabled, is_allow, modifiers)
+                    values(?, ?, ?, ?, ?)
+                    "#,
+                    filter.expr,
+                    filter.is_regex,
+                    filter.enabled,
+                    filter.is_allow,
+                    filter.modifiers,
+                )
+                .execute(&mut trans)
+                .await?;
+            }
+        }
+    }
+    Ok(trans.commit().await?)
+}
+
+/// Adds a single filter, replacing the existing one with the same expression.
+/// Returns the filter as it was before, if there was one, and as it is now.
+pub(crate) async fn insert_filter(
+    trans: &mut Transaction<'_, Sqlite>,
+    filter: &DbFilter,
+) -> anyhow::Result<(Option<DbFilter>, DbFilter)> {
+    let old = find_filter_by_key(trans, filter).await?;
+    sqlx::query!(
+        r#"
+        insert into filters(expr, is_regex, enabled, is_allow, modifiers)
+        values(?, ?, ?, ?, ?)
+        on conflict(expr, is_regex, modifiers) do update
+        set enabled=excluded.enabled, is_allow=excluded.is_allow, version=version+1
+        "#,
+        filter.expr,
+        filter.is_regex,
+        filter.enabled,
+        filter.is_allow,
+        filter.modifiers,
+    )
+    .execute(&mut *trans)
+    .await?;
+    let new = find_filter_by_key(trans, filter)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Filter {} wasn't saved", filter.expr))?;
+    Ok((old, new))
+}
+
+async fn find_filter_by_key(
+    trans: &mut Transaction<'_, Sqlite>,
+    filter: &DbFilter,
+) -> anyhow::Result<Option<DbFilter>> {
+    Ok(sqlx::query_as!(
+        DbFilter,
+        "select * from filters where expr=? and is_regex=? and modifiers=?",
+        filter.expr,
+        filter.is_regex,
+        filter.modifiers,
+    )
+    .fetch_optional(trans)
+    .await?)
+}
+
+pub async fn find_filter(f_id: i64) -> anyhow::Result<Option<DbFilter>> {
+    Ok(
+        sqlx::query_as!(DbFilter, "select * from filters where f_id=?", f_id)
+            .fetch_optional(db())
+            .await?,
+    )
+}
+
+/// Inserts all of the filters or none of them, the result of each insert is returned
+/// and the changes are committed only if every one of them succeeded
+pub async fn create_filters(filters: &[DbFilter]) -> anyhow::Result<Vec<Result<i64, String>>> {
+    let mut trans = db().begin().await?;
+    let mut results = Vec::with_capacity(filters.len());
     for filter in filters {
-        sqlx::query!(
+        let result = sqlx::query!(
             r#"
-            insert into filters(expr, is_regex, enabled, is_allow)
-            values(?, ?, ?, ?)
+            insert into filters(expr, is_regex, enabled, is_allow, modifiers, expires_at)
+            values(?, ?, ?, ?, ?, ?)
             "#,
             filter.expr,
             filter.is_regex,
             filter.enabled,
             filter.is_allow,
+            filter.modifiers,
+            filter.expires_at,
         )
         .execute(&mut trans)
-        .await?;
+        .await;
+        results.push(result.map(|r| r.last_insert_rowid()).map_err(error_message));
     }
-    Ok(trans.commit().await?)
+    if results.iter().all(Result::is_ok) {
+        trans.commit().await?;
+    } else {
+        trans.rollback().await?;
+    }
+    Ok(results)
+}
+
+/// Updates the filter if its version is still `filter.version`, returns false otherwise.
+/// Fails with a message for the user if the change clashes with another filter.
+pub async fn update_filter(filter: &DbFilter) -> anyhow::Result<Result<bool, String>> {
+    let result = sqlx::query!(
+        r#"
+        update filters
+        set expr=?, is_regex=?, enabled=?, is_allow=?, modifiers=?, expires_at=?,
+            version=version+1
+        where f_id=? and version=?
+        "#,
+        filter.expr,
+        filter.is_regex,
+        filter.enabled,
+        filter.is_allow,
+        filter.modifiers,
+        filter.expires_at,
+        filter.f_id,
+        filter.version,
+    )
+    .execute(db())
+    .await;
+    match result {
+        Ok(result) => Ok(Ok(result.rows_affected() == 1)),
+        Err(e) if e.as_database_error().is_some() => Ok(Err(error_message(e))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Deletes the filter if its version is still `version`, returns false otherwise
+pub async fn delete_filter(f_id: i64, version: i64) -> anyhow::Result<bool> {
+    Ok(sqlx::query!(
+        "delete from filters where f_id=? and version=?",
+        f_id,
+        version
+    )
+    .execute(db())
+    .await?
+    .rows_affected()
+        == 1)
+}
+
+/// Disables the enabled filters which have expired by `now`, and returns them as they were
+pub(crate) async fn disable_expired_filters(now: NaiveDateTime) -> anyhow::Result<Vec<DbFilter>> {
+    let mut trans = db().begin().await?;
+    let expired = sqlx::query_as!(
+        DbFilter,
+        "select * from filters where enabled=true and expires_at<=?",
+        now
+    )
+    .fetch_all(&mut trans)
+    .await?;
+    sqlx::query!(
+        "update filters set enabled=false, version=version+1 where enabled=true and expires_at<=?",
+        now
+    )
+    .execute(&mut trans)
+    .await?;
+    trans.commit().await?;
+    Ok(expired)
+}
+
+/// When the first of the enabled filters expires
+pub async fn next_filter_expiry() -> anyhow::Result<Option<NaiveDateTime>> {
+    Ok(sqlx::query_as::<_, (Option<NaiveDateTime>,)>(
+        "select min(expires_at) from filters where enabled=true",
+    )
+    .fetch_one(db())
+    .await?
+    .0)
+}
+
+pub(crate) async fn add_filter_hits(
+    trans: &mut Transaction<'_, Sqlite>,
+    f_id: i64,
+    count: i64,
+    last_hit: NaiveDateTime,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "update filters set hit_count=hit_count+?, last_hit=? where f_id=?",
+        count,
+        last_hit,
+        f_id,
+    )
+    .execute(trans)
+    .await?;
+    Ok(())
 }
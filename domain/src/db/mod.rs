@@ -8,24 +8,54 @@ const DB_FILE: &str = "domain.db";
 
 static DB: OnceCell<Pool<Sqlite>> = OnceCell::new();
 
+/// Schema changes for the dbs created by an older version, `PRAGMA user_version` tracks how many
+/// of them have been applied. `init_db.sql` always has the latest schema.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../../schema/migrations/001_unblock_requests.sql"),
+    include_str!("../../schema/migrations/002_filter_modifiers.sql"),
+    include_str!("../../schema/migrations/003_versions.sql"),
+    include_str!("../../schema/migrations/004_hit_stats.sql"),
+    include_str!("../../schema/migrations/005_filter_expiry.sql"),
+    include_str!("../../schema/migrations/006_blocked_domains_source.sql"),
+    include_str!("../../schema/migrations/007_block_list_validators.sql"),
+    include_str!("../../schema/migrations/008_block_list_format.sql"),
+    include_str!("../../schema/migrations/009_allow_lists.sql"),
+    include_str!("../../schema/migrations/010_block_list_safety.sql"),
+    include_str!("../../schema/migrations/011_block_list_status.sql"),
+    include_str!("../../schema/migrations/012_domain_sources.sql"),
+    include_str!("../../schema/migrations/013_block_list_labels.sql"),
+];
+
 pub mod block_list;
 pub mod filters;
+pub mod unblock_requests;
 
 pub async fn init_db() -> anyhow::Result<bool> {
+    init_db_at(DB_FILE).await
+}
+
+/// Same as [`init_db`] for a db somewhere else than the working directory
+pub async fn init_db_at(db_file: impl AsRef<Path>) -> anyhow::Result<bool> {
+    let db_file = db_file.as_ref();
+    let url = format!("sqlite://{}", db_file.display());
     let mut is_new = false;
-    if !Path::new(DB_FILE).exists() {
-        info!("Sqlite db:{DB_FILE} doesn't exit, creating it...");
-        let mut connection =
-            SqliteConnection::connect(&format!("sqlite://{DB_FILE}?mode=rwc")).await?;
+    if !db_file.exists() {
+        info!("Sqlite db:{url} doesn't exit, creating it...");
+        let mut connection = SqliteConnection::connect(&format!("{url}?mode=rwc")).await?;
         sqlx::query(include_str!("../../schema/init_db.sql"))
             .execute(&mut connection)
             .await?;
+        sqlx::query(&format!("PRAGMA user_version = {};", MIGRATIONS.len()))
+            .execute(&mut connection)
+            .await?;
         is_new = true;
+    } else {
+        migrate(&url).await?;
     }
     DB.set(
         SqlitePoolOptions::new()
             .max_connections(4)
-            .connect(&format!("sqlite://{DB_FILE}?mode=rw"))
+            .connect(&format!("{url}?mode=rw"))
             .await?,
     )
     .expect("Failed to set DB once_cell");
@@ -35,11 +65,33 @@ pub async fn init_db() -> anyhow::Result<bool> {
     Ok(is_new)
 }
 
-pub(crate) async fn vacuum() -> anyhow::Result<()> {
-    sqlx::query!("VACUUM;").execute(db()).await?;
+async fn migrate(url: &str) -> anyhow::Result<()> {
+    let mut connection = SqliteConnection::connect(&format!("{url}?mode=rw")).await?;
+    let (version,): (i64,) = sqlx::query_as("PRAGMA user_version;")
+        .fetch_one(&mut connection)
+        .await?;
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Applying migration {} to {url}", idx + 1);
+        let mut trans = connection.begin().await?;
+        sqlx::query(migration).execute(&mut trans).await?;
+        sqlx::query(&format!("PRAGMA user_version = {};", idx + 1))
+            .execute(&mut trans)
+            .await?;
+        trans.commit().await?;
+    }
     Ok(())
 }
 
 pub(crate) fn db() -> &'static Pool<Sqlite> {
     DB.get().unwrap()
 }
+
+/// Turns the failure of a single statement into a message fit for the user
+pub(crate) fn error_message(e: sqlx::Error) -> String {
+    match e.as_database_error() {
+        Some(db_error) if db_error.message().contains("UNIQUE constraint failed") => {
+            "It already exists".into()
+        }
+        _ => e.to_string(),
+    }
+}
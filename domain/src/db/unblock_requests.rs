@@ -0,0 +1,87 @@
+use crate::db::db;
+use crate::db::filters::{insert_filter, DbFilter};
+use chrono::NaiveDateTime;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct DbUnblockRequest {
+    pub ur_id: i64,
+    pub create_time: NaiveDateTime,
+    pub domain_name: String,
+    pub requester: String,
+    pub reason: Option<String>,
+    pub status: String,
+}
+
+pub const PENDING: &str = "pending";
+pub const APPROVED: &str = "approved";
+pub const REJECTED: &str = "rejected";
+
+/// Records an unblock request, returns false if one is already pending for the domain
+pub async fn insert_unblock_request(
+    domain_name: &str,
+    requester: &str,
+    reason: Option<&str>,
+) -> anyhow::Result<bool> {
+    Ok(sqlx::query!(
+        r"
+        insert or ignore into unblock_requests(domain_name, requester, reason)
+        values(?, ?, ?)
+        ",
+        domain_name,
+        requester,
+        reason,
+    )
+    .execute(db())
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+pub async fn load_unblock_requests() -> anyhow::Result<Vec<DbUnblockRequest>> {
+    Ok(sqlx::query_as!(
+        DbUnblockRequest,
+        r"select * from unblock_requests order by create_time desc"
+    )
+    .fetch_all(db())
+    .await?)
+}
+
+pub async fn find_unblock_request(ur_id: i64) -> anyhow::Result<Option<DbUnblockRequest>> {
+    Ok(sqlx::query_as!(
+        DbUnblockRequest,
+        r"select * from unblock_requests where ur_id=?",
+        ur_id
+    )
+    .fetch_optional(db())
+    .await?)
+}
+
+pub async fn update_unblock_request(ur_id: i64, status: &str) -> anyhow::Result<()> {
+    sqlx::query!(
+        r"update unblock_requests set status=? where ur_id=?",
+        status,
+        ur_id
+    )
+    .execute(db())
+    .await?;
+    Ok(())
+}
+
+/// Approves the request with the filter allowing its domain, both are saved or neither is.
+/// Returns the filter as it was before, if there was one, and as it is now.
+pub async fn approve_unblock_request(
+    ur_id: i64,
+    filter: &DbFilter,
+) -> anyhow::Result<(Option<DbFilter>, DbFilter)> {
+    let mut trans = db().begin().await?;
+    let saved = insert_filter(&mut trans, filter).await?;
+    sqlx::query!(
+        r"update unblock_requests set status=? where ur_id=?",
+        APPROVED,
+        ur_id
+    )
+    .execute(&mut trans)
+    .await?;
+    trans.commit().await?;
+    Ok(saved)
+}
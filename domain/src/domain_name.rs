@@ -0,0 +1,157 @@
+//! The canonical form the domain names are compared in, whether they come from the filters, the
+//! block lists or the queries: lowercase ASCII without the trailing dot, the internationalized
+//! labels in punycode (`bücher.example` is `xn--bcher-kva.example`), as they go on the wire.
+
+/// The canonical form of the name, `None` if it isn't a domain name
+pub fn normalize(name: &str) -> Option<String> {
+    let name = name.trim().to_lowercase();
+    let name = if name.is_ascii() && !name.contains("xn--") {
+        name
+    } else {
+        // Maps the Unicode the way the browsers do (e.g. the full-width dot `。` is a dot),
+        // and checks the punycode labels decode
+        idna::domain_to_ascii(&name).ok()?
+    };
+    if !is_domain(&name) {
+        return None;
+    }
+    match name.strip_suffix('.') {
+        Some(name) => Some(name.to_owned()),
+        None => Some(name),
+    }
+}
+
+/// The canonical form of a name to look up, the names of the queries are taken as they are
+/// if they aren't valid, they won't match anything listed anyways
+pub fn canonical(name: &str) -> String {
+    normalize(name).unwrap_or_else(|| {
+        let name = name.trim();
+        name.strip_suffix('.').unwrap_or(name).to_lowercase()
+    })
+}
+
+pub(crate) fn is_domain_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_'
+}
+
+/// Whether the name is made of valid labels, the trailing dot is allowed
+pub fn is_domain(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty() && label.len() <= 63 && label.chars().all(is_domain_char)
+        })
+}
+
+/// Whether the name is the domain or one of its sub-domains, both are expected to be canonical
+/// though the name may have the trailing dot
+pub fn is_subdomain(name: &str, domain: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    name == domain
+        || name
+            .strip_suffix(domain)
+            .map(|prefix| prefix.ends_with('.'))
+            .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{canonical, is_domain, is_subdomain, normalize};
+
+    fn check(name: &str, expected: Option<&str>) {
+        assert_eq!(normalize(name).as_deref(), expected, "{name}");
+    }
+
+    #[test]
+    fn test_ascii() {
+        check("example.com", Some("example.com"));
+        check("Ads.Example.COM", Some("ads.example.com"));
+        check("ads.example.com.", Some("ads.example.com"));
+        check("  ADS.example.com.  ", Some("ads.example.com"));
+        check("_dmarc.example.com", Some("_dmarc.example.com"));
+        check("my-cdn.example.com", Some("my-cdn.example.com"));
+        check("localhost", Some("localhost"));
+    }
+
+    #[test]
+    fn test_unicode() {
+        check("bücher.example", Some("xn--bcher-kva.example"));
+        check("BÜCHER.example.", Some("xn--bcher-kva.example"));
+        check("Bücher.Example", Some("xn--bcher-kva.example"));
+        check("münchen.de", Some("xn--mnchen-3ya.de"));
+        check("пример.рф", Some("xn--e1afmkfd.xn--p1ai"));
+        check("ПРИМЕР.РФ", Some("xn--e1afmkfd.xn--p1ai"));
+        check("例え.テスト", Some("xn--r8jz45g.xn--zckzah"));
+        // Non-transitional, the sharp s is kept rather than turned into `ss`
+        check("faß.de", Some("xn--fa-hia.de"));
+        // The full-width dot and letters
+        check("bücher。example", Some("xn--bcher-kva.example"));
+        check("ＥＸＡＭＰＬＥ.com", Some("example.com"));
+        // Only the internationalized labels are encoded
+        check(
+            "Ads.bücher.example.com",
+            Some("ads.xn--bcher-kva.example.com"),
+        );
+    }
+
+    #[test]
+    fn test_punycode() {
+        check("xn--bcher-kva.example", Some("xn--bcher-kva.example"));
+        check("XN--BCHER-KVA.Example.", Some("xn--bcher-kva.example"));
+        check("xn--e1afmkfd.xn--p1ai", Some("xn--e1afmkfd.xn--p1ai"));
+        // Both forms of the same name end up the same
+        assert_eq!(
+            normalize("bücher.example"),
+            normalize("xn--bcher-kva.example")
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        check("", None);
+        check("  ", None);
+        check(".", None);
+        check("a..com", None);
+        check(".example.com", None);
+        check("example.com..", None);
+        check("exa mple.com", None);
+        check("ex*ample.com", None);
+        check("example.com/path", None);
+        check("bü*cher.example", None);
+        check(&format!("{}.com", "a".repeat(64)), None);
+        check(&format!("{}.com", "ü".repeat(64)), None);
+        check(&["abcdefghij"; 26].join("."), None);
+    }
+
+    #[test]
+    fn test_canonical() {
+        assert_eq!(canonical("Ads.Example.com."), "ads.example.com");
+        assert_eq!(canonical("bücher.example."), "xn--bcher-kva.example");
+        assert_eq!(canonical("Weird Name."), "weird name");
+        assert_eq!(canonical("."), "");
+    }
+
+    #[test]
+    fn test_is_domain() {
+        assert!(is_domain("example.com"));
+        assert!(is_domain("example.com."));
+        assert!(is_domain("xn--bcher-kva.example"));
+        assert!(!is_domain("bücher.example"));
+        assert!(!is_domain(""));
+        assert!(!is_domain("a..b"));
+    }
+
+    #[test]
+    fn test_is_subdomain() {
+        assert!(is_subdomain("example.com", "example.com"));
+        assert!(is_subdomain("example.com.", "example.com"));
+        assert!(is_subdomain("ads.example.com.", "example.com"));
+        assert!(is_subdomain(
+            "a.xn--bcher-kva.example",
+            "xn--bcher-kva.example"
+        ));
+        assert!(!is_subdomain("myexample.com", "example.com"));
+        assert!(!is_subdomain("example.com", "ads.example.com"));
+    }
+}
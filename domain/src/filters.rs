@@ -1,76 +1,362 @@
-use itertools::{Either, Itertools};
+use std::cmp::Reverse;
+use std::net::IpAddr;
+
+use chrono::Local;
+use itertools::Itertools;
 use log::info;
 use once_cell::sync::Lazy;
-use regex::RegexSet;
+use regex::{Regex, RegexSet};
 use tokio::sync::RwLock;
 
-use crate::db::filters::load_filters;
+use crate::adblock::{Rule, RuleSet};
+use crate::db::block_list::{find_listed_domains, ListKind};
+use crate::db::filters::{disable_expired_filters, load_filters, DbFilter};
+use crate::domain_name::{canonical, is_subdomain};
 use crate::filters::trie::NameTrie;
 
+/// Enabled filters, the matchers below are built from them
+static FILTERS: Lazy<RwLock<Vec<DbFilter>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
 static DOMAIN_FILTER: Lazy<RwLock<NameTrie>> = Lazy::new(|| RwLock::new(NameTrie::default()));
 
-static ALLOWED: Lazy<RwLock<Vec<String>>> = Lazy::new(|| RwLock::new(Vec::new()));
+static ALLOWED: Lazy<RwLock<Vec<(i64, String)>>> = Lazy::new(|| RwLock::new(Vec::new()));
 static REGEX_ALLOWED: Lazy<RwLock<RegexSet>> =
     Lazy::new(|| RwLock::new(RegexSet::new(Vec::<String>::new()).unwrap()));
 
-static BLOCKED: Lazy<RwLock<Vec<String>>> = Lazy::new(|| RwLock::new(Vec::new()));
+/// Filters with adblock modifiers, e.g. `$important` or `$client=`
+static ADBLOCK_RULES: Lazy<RwLock<RuleSet>> = Lazy::new(|| RwLock::new(RuleSet::default()));
+
+static BLOCKED: Lazy<RwLock<Vec<(i64, String)>>> = Lazy::new(|| RwLock::new(Vec::new()));
 static REGEX_BLOCKED: Lazy<RwLock<RegexSet>> =
     Lazy::new(|| RwLock::new(RegexSet::new(Vec::<String>::new()).unwrap()));
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FilterKind {
+    Domain,
+    AllowedRegex,
+    BlockedRegex,
+    Adblock,
+}
+
+impl FilterKind {
+    const ALL: [FilterKind; 4] = [
+        FilterKind::Domain,
+        FilterKind::AllowedRegex,
+        FilterKind::BlockedRegex,
+        FilterKind::Adblock,
+    ];
+
+    fn of(filter: &DbFilter) -> FilterKind {
+        match (
+            filter.modifiers.is_empty(),
+            filter.is_regex,
+            filter.is_allow,
+        ) {
+            (false, _, _) => FilterKind::Adblock,
+            (true, false, _) => FilterKind::Domain,
+            (true, true, true) => FilterKind::AllowedRegex,
+            (true, true, false) => FilterKind::BlockedRegex,
+        }
+    }
+}
+
 pub async fn reload_filters() -> anyhow::Result<()> {
-    let filters = load_filters().await?;
-    let trie = NameTrie::create(
-        filters
-            .iter()
-            .filter(|df| !df.is_regex)
-            .map(|df| (&df.expr, df.is_allow)),
-    );
-    info!("Creating a name trie of size: {}", trie.count());
-    *DOMAIN_FILTER.write().await = trie;
+    let mut filters = FILTERS.write().await;
+    *filters = load_filters().await?;
+    rebuild(&filters, &FilterKind::ALL).await;
+    Ok(())
+}
 
-    let (allowed, blocked): (Vec<_>, Vec<_>) = filters
-        .into_iter()
-        .filter(|df| df.is_regex)
-        .partition_map(|df| {
-            if df.is_allow {
-                Either::Left(df.expr)
-            } else {
-                Either::Right(df.expr)
+/// Disables the filters which are past their expiry, and reloads the filters if there were any.
+/// Returns the expired filters.
+pub async fn expire_filters() -> anyhow::Result<Vec<DbFilter>> {
+    let expired = disable_expired_filters(Local::now().naive_local()).await?;
+    if !expired.is_empty() {
+        info!("{} filter(s) have expired", expired.len());
+        reload_filters().await?;
+    }
+    Ok(expired)
+}
+
+/// Applies the changes made to a few filters without going back to the db, only the matchers
+/// those filters belong to are rebuilt. `old` has the filters as they were before the change,
+/// and `new` as they are after it, deleted filters are only in `old`.
+pub async fn update_filters(old: &[DbFilter], new: &[DbFilter]) {
+    let mut filters = FILTERS.write().await;
+    let mut kinds = Vec::new();
+    for filter in old.iter().chain(new) {
+        filters.retain(|df| df.f_id != filter.f_id);
+        kinds.push(FilterKind::of(filter));
+    }
+    for filter in new.iter().filter(|df| df.enabled) {
+        filters.insert(0, filter.clone());
+    }
+    kinds.sort_by_key(|kind| *kind as u8);
+    kinds.dedup();
+    rebuild(&filters, &kinds).await;
+}
+
+async fn rebuild(filters: &[DbFilter], kinds: &[FilterKind]) {
+    let of_kind = |kind| filters.iter().filter(move |df| FilterKind::of(df) == kind);
+    for kind in kinds {
+        match kind {
+            FilterKind::Domain => {
+                let trie =
+                    NameTrie::create(of_kind(*kind).map(|df| (&df.expr, df.is_allow, df.f_id)));
+                info!("Creating a name trie of size: {}", trie.count());
+                *DOMAIN_FILTER.write().await = trie;
+            }
+            FilterKind::AllowedRegex => {
+                let allowed = of_kind(*kind)
+                    .map(|df| (df.f_id, df.expr.clone()))
+                    .collect::<Vec<_>>();
+                info!("Allowed regex filters: {allowed:?}");
+                if let Ok(regex) = RegexSet::new(allowed.iter().map(|(_, expr)| expr)) {
+                    *ALLOWED.write().await = allowed;
+                    *REGEX_ALLOWED.write().await = regex;
+                }
             }
+            FilterKind::BlockedRegex => {
+                let blocked = of_kind(*kind)
+                    .map(|df| (df.f_id, df.expr.clone()))
+                    .collect::<Vec<_>>();
+                info!("Blocked regex filters: {blocked:?}");
+                if let Ok(regex) = RegexSet::new(blocked.iter().map(|(_, expr)| expr)) {
+                    *BLOCKED.write().await = blocked;
+                    *REGEX_BLOCKED.write().await = regex;
+                }
+            }
+            FilterKind::Adblock => {
+                let rules = RuleSet::create(of_kind(*kind));
+                info!("Loaded {} filters with modifiers", rules.len());
+                *ADBLOCK_RULES.write().await = rules;
+            }
+        }
+    }
+}
+
+/// Returns whether the domain is allowed and why. Every filter matching the domain is ranked
+/// and the first one decides:
+/// 1. important rules beat the others
+/// 2. then the higher `$priority`, the rules without one have `0`
+/// 3. then the order of [`PRECEDENCE`], the parent domain beats its sub-domains in the trie
+/// 4. then the older filter
+///
+/// Filters with modifiers only apply when the client and dns type match. The ids of the deciding
+/// filters are returned with the decision.
+pub async fn check_filters(
+    domain: impl AsRef<str>,
+    client: Option<IpAddr>,
+    dns_type: Option<&str>,
+) -> Option<(bool, String, Vec<i64>)> {
+    let name = canonical(domain.as_ref());
+    let domain = &fqdn(&name);
+    let mut verdicts = Vec::new();
+    let adblock_rules = ADBLOCK_RULES.read().await;
+    for rule in adblock_rules.matching(domain, client, dns_type) {
+        verdicts.push(Verdict {
+            important: rule.important(),
+            priority: rule.priority(),
+            precedence: rule_precedence(rule.important(), rule.is_allow),
+            is_allow: rule.is_allow,
+            reason: format!("Adblock rule: {}", rule.text),
+            f_ids: vec![rule.id],
         });
-    info!("Allowed regex filters: {allowed:?}, Blocked regex filters: {blocked:?}");
-    if let Ok(regex) = RegexSet::new(&allowed) {
-        *ALLOWED.write().await = allowed;
-        *REGEX_ALLOWED.write().await = regex;
     }
-    if let Ok(regex) = RegexSet::new(&blocked) {
-        *BLOCKED.write().await = blocked;
-        *REGEX_BLOCKED.write().await = regex;
+
+    if let Some((is_allow, reason, f_id)) = DOMAIN_FILTER.read().await.check(&name) {
+        verdicts.push(Verdict::plain(
+            3,
+            is_allow,
+            format!("Domain match: {reason}"),
+            vec![f_id],
+        ));
     }
-    Ok(())
+
+    let regexes = [
+        (4, true, &REGEX_ALLOWED, &ALLOWED, "Allowed regex"),
+        (5, false, &REGEX_BLOCKED, &BLOCKED, "Blocked regex"),
+    ];
+    for (precedence, is_allow, regex, filters, label) in regexes {
+        let matched = regex.read().await.matches(domain);
+        if matched.matched_any() {
+            let guard = filters.read().await;
+            let matched = matched.into_iter().map(|i| &guard[i]).collect::<Vec<_>>();
+            let reason = matched.iter().map(|(_, expr)| expr).join(", ");
+            let f_ids = matched.iter().map(|(f_id, _)| *f_id).collect();
+            verdicts.push(Verdict::plain(
+                precedence,
+                is_allow,
+                format!("{label}: {reason}"),
+                f_ids,
+            ));
+        }
+    }
+
+    verdicts
+        .into_iter()
+        .min_by_key(Verdict::rank)
+        .map(|verdict| (verdict.is_allow, verdict.reason, verdict.f_ids))
+}
+
+/// A filter matching the domain, or all the matching regexes of a kind
+struct Verdict {
+    important: bool,
+    priority: i64,
+    /// Index into [`PRECEDENCE`]
+    precedence: usize,
+    is_allow: bool,
+    reason: String,
+    f_ids: Vec<i64>,
 }
 
-pub async fn check_filters(domain: impl AsRef<str>) -> Option<(bool, String)> {
-    let domain = domain.as_ref();
-    if let Some((allowed, reason)) = DOMAIN_FILTER.read().await.check(domain) {
-        return Some((allowed, format!("Domain match: {reason}")));
+impl Verdict {
+    /// A filter without modifiers
+    fn plain(precedence: usize, is_allow: bool, reason: String, f_ids: Vec<i64>) -> Self {
+        Verdict {
+            important: false,
+            priority: 0,
+            precedence,
+            is_allow,
+            reason,
+            f_ids,
+        }
     }
 
-    let allow_match = REGEX_ALLOWED.read().await.matches(domain);
-    if allow_match.len() > 0 {
-        let guard = ALLOWED.read().await;
-        let reason = allow_match.into_iter().map(|i| &guard[i]).join(", ");
-        return Some((true, format!("Allowed regex: {reason}")));
+    /// The lowest ranked verdict decides, see [`check_filters`]
+    fn rank(&self) -> (bool, Reverse<i64>, usize, i64) {
+        let oldest = self.f_ids.iter().copied().min().unwrap_or_default();
+        (
+            !self.important,
+            Reverse(self.priority),
+            self.precedence,
+            oldest,
+        )
     }
+}
 
-    let block_match = REGEX_BLOCKED.read().await.matches(domain);
-    if block_match.len() > 0 {
-        let guard = BLOCKED.read().await;
-        let reason = block_match.into_iter().map(|i| &guard[i]).join(", ");
-        return Some((false, format!("Blocked regex: {reason}")));
+fn rule_precedence(important: bool, is_allow: bool) -> usize {
+    match (important, is_allow) {
+        (true, true) => 0,
+        (true, false) => 1,
+        (false, true) => 2,
+        (false, false) => 6,
     }
+}
+
+/// The regexes were written against the names of the queries, which come with the trailing dot
+fn fqdn(name: &str) -> String {
+    format!("{name}.")
+}
+
+/// The tiers of the rules, they break the ties of importance and priority in [`check_filters`].
+/// The allowlists and then the blocklists are only consulted if none of the filters apply.
+pub const PRECEDENCE: &[&str] = &[
+    "important allow",
+    "important block",
+    "conditional allow",
+    "domain",
+    "allowed regex",
+    "blocked regex",
+    "conditional block",
+    "allowlist",
+    "blocklist",
+];
 
-    None
+#[derive(Debug, Clone)]
+pub struct RuleMatch {
+    /// Index into [`PRECEDENCE`]
+    pub precedence: usize,
+    /// `$priority` of the rule, `0` for the rules without one and the list entries
+    pub priority: i64,
+    pub rule: String,
+    pub is_allow: bool,
+    /// False for rules with modifiers which match the name, but not the client or dns type
+    pub applies: bool,
+    /// `0` for the list entries
+    pub f_id: i64,
+}
+
+/// Every enabled filter and blocklist entry matching the domain, ranked the way
+/// [`check_filters`] ranks them, with the list entries after the filters.
+/// Domain filters are ordered from the parent to the sub-domain, as the parent wins.
+pub async fn explain_filters(
+    domain: &str,
+    client: Option<IpAddr>,
+    dns_type: Option<&str>,
+) -> anyhow::Result<Vec<RuleMatch>> {
+    let name = canonical(domain);
+    let domain = &fqdn(&name);
+    let mut matches = Vec::new();
+    for filter in load_filters().await? {
+        if !filter.modifiers.is_empty() {
+            let rule = match Rule::from_filter(&filter) {
+                Ok(rule) if rule.matches_pattern(domain) => rule,
+                _ => continue,
+            };
+            matches.push(RuleMatch {
+                precedence: rule_precedence(rule.important(), rule.is_allow),
+                priority: rule.priority(),
+                applies: rule.applies(domain, client, dns_type),
+                rule: rule.text,
+                is_allow: rule.is_allow,
+                f_id: filter.f_id,
+            });
+        } else if filter.is_regex {
+            let matched = Regex::new(&filter.expr)
+                .map(|regex| regex.is_match(domain))
+                .unwrap_or(false);
+            if matched {
+                matches.push(RuleMatch {
+                    precedence: if filter.is_allow { 4 } else { 5 },
+                    priority: 0,
+                    rule: filter.expr,
+                    is_allow: filter.is_allow,
+                    applies: true,
+                    f_id: filter.f_id,
+                });
+            }
+        } else if is_subdomain(domain, &filter.expr) {
+            matches.push(RuleMatch {
+                precedence: 3,
+                priority: 0,
+                rule: filter.expr,
+                is_allow: filter.is_allow,
+                applies: true,
+                f_id: filter.f_id,
+            });
+        }
+    }
+    for (precedence, kind) in [(7, ListKind::Allow), (8, ListKind::Block)] {
+        for (listed, source) in find_listed_domains(kind, &name).await? {
+            matches.push(RuleMatch {
+                precedence,
+                priority: 0,
+                rule: format!("{listed} listed in '{source}'"),
+                is_allow: kind == ListKind::Allow,
+                applies: true,
+                f_id: 0,
+            });
+        }
+    }
+    // The filters come before the lists whatever their priority, the important ones first.
+    // The oldest filter breaks the ties as in `Verdict::rank`, but the parent domain wins.
+    matches.sort_by_key(|m| {
+        let labels = if m.precedence == 3 {
+            m.rule.matches('.').count()
+        } else {
+            0
+        };
+        (
+            m.precedence >= 7,
+            m.precedence > 1,
+            Reverse(m.priority),
+            m.precedence,
+            labels,
+            m.f_id,
+        )
+    });
+    Ok(matches)
 }
 
 mod trie {
@@ -79,6 +365,8 @@ mod trie {
     use itertools::Itertools;
     use log::debug;
 
+    use crate::domain_name::canonical;
+
     #[derive(Clone, Debug, Default)]
     pub struct NameTrie {
         names: HashMap<String, Name>,
@@ -87,27 +375,28 @@ mod trie {
     #[derive(Clone, Debug)]
     struct Name {
         children: NameTrie,
-        is_allow: Option<bool>,
+        /// Whether the filter on this name allows it, with the filter's id
+        is_allow: Option<(bool, i64)>,
     }
 
     impl NameTrie {
-        pub fn create(list: impl IntoIterator<Item = (impl AsRef<str>, bool)>) -> Self {
+        pub fn create(list: impl IntoIterator<Item = (impl AsRef<str>, bool, i64)>) -> Self {
             let mut trie = NameTrie::default();
-            for (domain, is_allow) in list {
-                let domain = domain.as_ref();
-                let sub_names = Self::sub_names(domain);
+            for (domain, is_allow, f_id) in list {
+                let domain = canonical(domain.as_ref());
+                let sub_names = Self::sub_names(&domain);
                 debug!("Inserting into trie {domain}/{is_allow} => {sub_names:?}");
-                trie.insert(0, &sub_names, is_allow);
+                trie.insert(0, &sub_names, (is_allow, f_id));
             }
             trie
         }
 
-        fn insert(&mut self, idx: usize, sub_names: &[&str], is_allow: bool) {
+        fn insert(&mut self, idx: usize, sub_names: &[&str], is_allow: (bool, i64)) {
             if idx == sub_names.len() {
                 return;
             }
 
-            if self.names.get(sub_names[idx]).is_none() {
+            if !self.names.contains_key(sub_names[idx]) {
                 self.names.insert(
                     sub_names[idx].to_owned(),
                     Name {
@@ -139,7 +428,7 @@ mod trie {
             count
         }
 
-        pub fn check(&self, name: impl AsRef<str>) -> Option<(bool, String)> {
+        pub fn check(&self, name: impl AsRef<str>) -> Option<(bool, String, i64)> {
             let sub_names = Self::sub_names(name.as_ref());
             let mut path = Vec::with_capacity(sub_names.len());
             let mut names = &self.names;
@@ -154,8 +443,8 @@ mod trie {
                 path.push(sub_name);
 
                 let name = &names[sub_name];
-                if let Some(is_allow) = name.is_allow {
-                    return Some((is_allow, path.into_iter().rev().join(".")));
+                if let Some((is_allow, f_id)) = name.is_allow {
+                    return Some((is_allow, path.into_iter().rev().join("."), f_id));
                 }
                 names = &name.children.names;
             }
@@ -177,14 +466,17 @@ mod trie {
 #[cfg(test)]
 mod test {
     use super::trie::NameTrie;
+    use super::{check_filters, rebuild, FilterKind};
+    use crate::adblock::parse_rule;
+    use crate::db::filters::DbFilter;
 
     #[test]
     fn test_create() {
         let trie = NameTrie::create([
-            ("www.amazon.com", false),
-            ("amazon.com", true),
-            ("www.facebook.com", false),
-            ("my-cdn.google.com", false),
+            ("www.amazon.com", false, 1),
+            ("amazon.com", true, 2),
+            ("www.facebook.com", false, 3),
+            ("my-cdn.google.com", false, 4),
         ]);
         println!("{trie:#?}");
         println!("Count {}", trie.count());
@@ -193,10 +485,10 @@ mod test {
     #[test]
     fn test_check() {
         let trie = NameTrie::create([
-            ("www.amazon.com", false),
-            ("amazon.com", true),
-            ("www.facebook.com", false),
-            ("my-cdn.google.com", false),
+            ("www.amazon.com", false, 1),
+            ("amazon.com", true, 2),
+            ("www.facebook.com", false, 3),
+            ("my-cdn.google.com", false, 4),
         ]);
         println!("{:?}", trie.check("my-cdn.google.com"));
         println!("{:?}", trie.check("my-cdn.amazon.com"));
@@ -204,4 +496,142 @@ mod test {
         println!("{:?}", trie.check("facebook.com"));
         println!("{:?}", trie.check("loda.lahsun.www.facebook.com"));
     }
+
+    #[test]
+    fn test_unicode() {
+        let trie = NameTrie::create([("Bücher.Example.", false, 1), ("ADS.example.com", false, 2)]);
+        assert_eq!(
+            trie.check("shop.xn--bcher-kva.example"),
+            Some((false, "xn--bcher-kva.example".into(), 1))
+        );
+        assert_eq!(
+            trie.check("ads.example.com"),
+            Some((false, "ads.example.com".into(), 2))
+        );
+    }
+
+    /// Rebuilds the matchers from the rules, which are allow rules if they start with `@@`,
+    /// and checks the name. The rules get their ids in order, the first one is the oldest.
+    async fn decide(rules: &[&str], name: &str, dns_type: &str) -> Option<(bool, String)> {
+        let filters = rules
+            .iter()
+            .zip(1..)
+            .map(|(rule, f_id)| DbFilter {
+                f_id,
+                ..parse_rule(rule, false).unwrap().unwrap()
+            })
+            .collect::<Vec<_>>();
+        rebuild(&filters, &FilterKind::ALL).await;
+        check_filters(name, None, Some(dns_type))
+            .await
+            .map(|(is_allow, reason, _)| (is_allow, reason))
+    }
+
+    /// The matchers are shared, so all the cases run in a single test
+    #[tokio::test]
+    async fn test_conflicts() {
+        let allowed = |reason: &str| Some((true, reason.to_owned()));
+        let blocked = |reason: &str| Some((false, reason.to_owned()));
+
+        // The parent domain beats its sub-domains
+        assert_eq!(
+            decide(&["@@amazon.com", "www.amazon.com"], "www.amazon.com.", "A").await,
+            allowed("Domain match: amazon.com")
+        );
+        // Domain filters, then the allowed regexes, then the blocked ones
+        assert_eq!(
+            decide(&["* ^ads\\.", "@@ads.example.com"], "ads.example.com.", "A").await,
+            allowed("Domain match: ads.example.com")
+        );
+        assert_eq!(
+            decide(&["* ^ads\\.", "@@/example\\.com/"], "ads.example.com.", "A").await,
+            allowed("Allowed regex: example\\.com")
+        );
+        // Conditional allow rules beat the domain filters, the conditional block rules don't
+        assert_eq!(
+            decide(&["ads.com", "@@||ads.com^$dnstype=A"], "ads.com.", "A").await,
+            allowed("Adblock rule: @@||ads.com^$dnstype=A")
+        );
+        assert_eq!(
+            decide(&["@@ads.com", "||ads.com^$dnstype=A"], "ads.com.", "A").await,
+            allowed("Domain match: ads.com")
+        );
+        // A higher priority beats the tiers
+        assert_eq!(
+            decide(
+                &["@@example.com", "||ads.example.com^$priority=1"],
+                "ads.example.com.",
+                "A"
+            )
+            .await,
+            blocked("Adblock rule: ||ads.example.com^$priority=1")
+        );
+        assert_eq!(
+            decide(&["@@||ads.com^$priority=-1", "* ^ads\\."], "ads.com.", "A").await,
+            blocked("Blocked regex: ^ads\\.")
+        );
+        // Important beats any priority, the important allow rules beat the important block ones
+        // unless their priority is lower
+        assert_eq!(
+            decide(
+                &["@@||ads.com^$priority=10", "||ads.com^$important"],
+                "ads.com.",
+                "A"
+            )
+            .await,
+            blocked("Adblock rule: ||ads.com^$important")
+        );
+        assert_eq!(
+            decide(
+                &["||ads.com^$important", "@@||ads.com^$important"],
+                "ads.com.",
+                "A"
+            )
+            .await,
+            allowed("Adblock rule: @@||ads.com^$important")
+        );
+        assert_eq!(
+            decide(
+                &["@@||ads.com^$important", "||ads.com^$important,priority=1"],
+                "ads.com.",
+                "A"
+            )
+            .await,
+            blocked("Adblock rule: ||ads.com^$important,priority=1")
+        );
+        // A rule which doesn't apply to the query doesn't take part
+        assert_eq!(
+            decide(
+                &["@@ads.com", "||ads.com^$important,dnstype=AAAA"],
+                "ads.com.",
+                "A"
+            )
+            .await,
+            allowed("Domain match: ads.com")
+        );
+        // The older of the equally ranked rules decides
+        assert_eq!(
+            decide(
+                &[
+                    "||ads.com^$dnstype=A",
+                    "||ads.com^$priority=0,client=~10.0.0.1"
+                ],
+                "ads.com.",
+                "A"
+            )
+            .await,
+            blocked("Adblock rule: ||ads.com^$dnstype=A")
+        );
+        // The names are compared in their canonical form
+        assert_eq!(
+            decide(
+                &["||Bücher.example^$priority=2", "@@example"],
+                "SHOP.xn--BCHER-kva.example.",
+                "A"
+            )
+            .await,
+            blocked("Adblock rule: ||xn--bcher-kva.example^$priority=2")
+        );
+        assert_eq!(decide(&["@@example.com"], "example.org.", "A").await, None);
+    }
 }
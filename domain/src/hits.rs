@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{Local, NaiveDateTime};
+use log::*;
+use once_cell::sync::Lazy;
+use tokio::time;
+
+use crate::db::block_list::add_source_hits;
+use crate::db::db;
+use crate::db::filters::add_filter_hits;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Hits which haven't been written to the db yet
+static PENDING: Lazy<Mutex<PendingHits>> = Lazy::new(|| Mutex::new(PendingHits::default()));
+
+/// What a decision on a query is credited to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hit {
+    /// Ids of the filters, all the matching regexes are credited
+    Filters(Vec<i64>),
+    /// Sources of the listed domain, all the lists listing it are credited
+    Sources(Vec<String>),
+}
+
+#[derive(Debug, Default)]
+struct PendingHits {
+    filters: HashMap<i64, Hits>,
+    sources: HashMap<String, Hits>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Hits {
+    count: i64,
+    last_hit: NaiveDateTime,
+}
+
+impl Hits {
+    fn add(&mut self, count: i64, last_hit: NaiveDateTime) {
+        self.count += count;
+        self.last_hit = self.last_hit.max(last_hit);
+    }
+}
+
+impl PendingHits {
+    fn add_filter(&mut self, f_id: i64, count: i64, last_hit: NaiveDateTime) {
+        self.filters
+            .entry(f_id)
+            .or_insert(Hits { count: 0, last_hit })
+            .add(count, last_hit);
+    }
+
+    fn add_source(&mut self, source: &str, count: i64, last_hit: NaiveDateTime) {
+        // Avoids allocating the key on every hit
+        match self.sources.get_mut(source) {
+            Some(hits) => hits.add(count, last_hit),
+            None => {
+                self.sources
+                    .insert(source.to_owned(), Hits { count, last_hit });
+            }
+        }
+    }
+}
+
+/// Counts a decision, it's kept in memory until the next flush
+pub fn record_hit(hit: &Hit) {
+    let now = Local::now().naive_local();
+    let mut pending = PENDING.lock().unwrap();
+    match hit {
+        Hit::Filters(f_ids) => f_ids
+            .iter()
+            .for_each(|f_id| pending.add_filter(*f_id, 1, now)),
+        Hit::Sources(sources) => sources
+            .iter()
+            .for_each(|source| pending.add_source(source, 1, now)),
+    }
+}
+
+/// Writes the pending hits to the db, they are put back if that fails
+pub async fn flush_hits() -> anyhow::Result<()> {
+    let pending = mem::take(&mut *PENDING.lock().unwrap());
+    if pending.filters.is_empty() && pending.sources.is_empty() {
+        return Ok(());
+    }
+    if let Err(e) = write_hits(&pending).await {
+        let mut current = PENDING.lock().unwrap();
+        for (f_id, hits) in pending.filters {
+            current.add_filter(f_id, hits.count, hits.last_hit);
+        }
+        for (source, hits) in pending.sources {
+            current.add_source(&source, hits.count, hits.last_hit);
+        }
+        return Err(e);
+    }
+    debug!(
+        "Flushed the hits of {} filters and {} sources",
+        pending.filters.len(),
+        pending.sources.len()
+    );
+    Ok(())
+}
+
+async fn write_hits(pending: &PendingHits) -> anyhow::Result<()> {
+    let mut trans = db().begin().await?;
+    for (f_id, hits) in &pending.filters {
+        add_filter_hits(&mut trans, *f_id, hits.count, hits.last_hit).await?;
+    }
+    for (source, hits) in &pending.sources {
+        add_source_hits(&mut trans, source, hits.count, hits.last_hit).await?;
+    }
+    trans.commit().await?;
+    Ok(())
+}
+
+pub async fn start_hits_flusher() -> anyhow::Result<()> {
+    let mut interval = time::interval(FLUSH_INTERVAL);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(e) = flush_hits().await {
+            warn!("Failed to flush the filter hits: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, Local};
+
+    use super::PendingHits;
+
+    #[test]
+    fn test_add() {
+        let now = Local::now().naive_local();
+        let earlier = now - Duration::minutes(5);
+        let mut pending = PendingHits::default();
+        pending.add_filter(1, 1, now);
+        pending.add_filter(1, 3, earlier);
+        pending.add_source("list", 2, earlier);
+        assert_eq!(pending.filters[&1].count, 4);
+        assert_eq!(pending.filters[&1].last_hit, now);
+        assert_eq!(pending.sources["list"].count, 2);
+        assert_eq!(pending.sources["list"].last_hit, earlier);
+    }
+}
@@ -1,11 +1,24 @@
 use log::*;
 
+pub use block_index::{
+    find_allowed_domain, find_blocked_domain, reload_block_index, reload_source_labels,
+    source_labels, source_overlaps,
+};
 use db::init_db;
-pub use filters::{check_filters, reload_filters};
+pub use filters::{
+    check_filters, expire_filters, explain_filters, reload_filters, update_filters, RuleMatch,
+    PRECEDENCE,
+};
+pub use hits::{flush_hits, record_hit, start_hits_flusher, Hit};
 
+pub mod adblock;
+pub mod block_index;
 pub mod block_list;
 pub mod db;
+pub mod domain_name;
 mod filters;
+pub mod hits;
+pub mod list_format;
 
 pub async fn init() -> anyhow::Result<()> {
     info!("Initializing domain db...");
@@ -14,5 +27,8 @@ pub async fn init() -> anyhow::Result<()> {
     info!("Initializing filters...");
     reload_filters().await?;
 
+    info!("Loading blocked and allowed domains...");
+    reload_block_index().await?;
+
     Ok(())
 }
@@ -0,0 +1,645 @@
+//! Parsers for the formats block lists are published in. The format of a source is detected from
+//! its content unless it's set explicitly, the lines which can't be parsed are counted.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::domain_name;
+
+/// How many of the leading lines are looked at to detect the format, the blank ones aside
+pub const DETECT_LINES: usize = 1000;
+
+/// How many of the invalid lines are kept to be logged
+const INVALID_SAMPLES: usize = 5;
+
+/// Names the hosts files map to the loopback addresses, they are never blocked
+const HOSTS_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    /// `0.0.0.0 example.com`, or a bare `example.com` per line
+    Hosts,
+    /// `address=/example.com/0.0.0.0` or `server=/example.com/`
+    Dnsmasq,
+    /// `||example.com^`, the hosts lines AdGuard DNS accepts are understood as well
+    Adguard,
+    /// A zone file of a response policy zone, e.g. `example.com CNAME .`
+    Rpz,
+    /// `*.example.com`, or a bare `example.com` per line
+    Wildcard,
+}
+
+impl ListFormat {
+    pub const ALL: [ListFormat; 5] = [
+        ListFormat::Hosts,
+        ListFormat::Dnsmasq,
+        ListFormat::Adguard,
+        ListFormat::Rpz,
+        ListFormat::Wildcard,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListFormat::Hosts => "hosts",
+            ListFormat::Dnsmasq => "dnsmasq",
+            ListFormat::Adguard => "adguard",
+            ListFormat::Rpz => "rpz",
+            ListFormat::Wildcard => "wildcard",
+        }
+    }
+
+    /// Picks the format most of the distinctive lines are in, `Hosts` if there are none
+    pub fn detect<S: AsRef<str>>(lines: &[S]) -> ListFormat {
+        let mut votes = [0; ListFormat::ALL.len()];
+        lines
+            .iter()
+            .map(|line| line.as_ref().trim())
+            .filter(|line| !line.is_empty())
+            .take(DETECT_LINES)
+            .filter_map(line_format)
+            .for_each(|format| votes[format as usize] += 1);
+        ListFormat::ALL
+            .into_iter()
+            .zip(votes)
+            .filter(|(_, count)| *count > 0)
+            // The first one wins a tie
+            .min_by_key(|(_, count)| std::cmp::Reverse(*count))
+            .map(|(format, _)| format)
+            .unwrap_or(ListFormat::Hosts)
+    }
+}
+
+impl fmt::Display for ListFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ListFormat::ALL
+            .into_iter()
+            .find(|format| format.as_str().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown format: {s}, expected one of {}",
+                    ListFormat::ALL.map(|format| format.as_str()).join(", ")
+                )
+            })
+    }
+}
+
+/// The format a line is distinctive of, hosts lines and bare domains fit most of them
+fn line_format(line: &str) -> Option<ListFormat> {
+    let line = line.to_ascii_lowercase();
+    if ["address=/", "server=/", "local=/"]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+    {
+        Some(ListFormat::Dnsmasq)
+    } else if line.starts_with("||") || line.starts_with("@@") || line.starts_with('!') {
+        Some(ListFormat::Adguard)
+    } else if line.starts_with("$ttl")
+        || line.starts_with("$origin")
+        || line
+            .split_whitespace()
+            .any(|token| ["soa", "cname"].contains(&token))
+    {
+        Some(ListFormat::Rpz)
+    } else if line.starts_with("*.") {
+        Some(ListFormat::Wildcard)
+    } else {
+        None
+    }
+}
+
+/// The domains of a source
+#[derive(Debug, Default)]
+pub struct ParsedList {
+    pub domains: HashSet<String>,
+    /// How many lines couldn't be parsed
+    pub invalid: usize,
+    /// The first few of the lines which couldn't be parsed
+    pub invalid_samples: Vec<String>,
+}
+
+impl ParsedList {
+    /// Adds what another document of the same source has
+    pub fn merge(&mut self, other: ParsedList) {
+        self.domains.extend(other.domains);
+        self.invalid += other.invalid;
+        let room = INVALID_SAMPLES.saturating_sub(self.invalid_samples.len());
+        self.invalid_samples
+            .extend(other.invalid_samples.into_iter().take(room));
+    }
+}
+
+/// Parses the lines in `format`, it's detected from the lines if `None`
+pub fn parse_list<S: AsRef<str>>(
+    format: Option<ListFormat>,
+    lines: &[S],
+) -> (ListFormat, ParsedList) {
+    let format = format.unwrap_or_else(|| ListFormat::detect(lines));
+    let mut parser = ListParser::new(format);
+    for line in lines {
+        parser.push(line.as_ref());
+    }
+    (format, parser.finish())
+}
+
+/// Parses a list a line at a time, for the lists too large to be held as lines
+pub struct ListParser {
+    parser: Parser,
+    parsed: ParsedList,
+}
+
+impl ListParser {
+    pub fn new(format: ListFormat) -> Self {
+        ListParser {
+            parser: Parser::new(format),
+            parsed: ParsedList::default(),
+        }
+    }
+
+    pub fn push(&mut self, line: &str) {
+        let parsed = &mut self.parsed;
+        match self.parser.parse(line) {
+            Line::Domains(domains) => parsed.domains.extend(domains),
+            Line::Ignored => {}
+            Line::Invalid => {
+                parsed.invalid += 1;
+                if parsed.invalid_samples.len() < INVALID_SAMPLES {
+                    parsed.invalid_samples.push(line.trim().to_owned());
+                }
+            }
+        }
+    }
+
+    pub fn finish(self) -> ParsedList {
+        self.parsed
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Line {
+    Domains(Vec<String>),
+    /// Blank lines, comments and the entries which don't block anything
+    Ignored,
+    Invalid,
+}
+
+impl Line {
+    fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Line {
+        names
+            .into_iter()
+            .map(normalize)
+            .collect::<Option<Vec<_>>>()
+            .filter(|domains| !domains.is_empty())
+            .map_or(Line::Invalid, Line::Domains)
+    }
+}
+
+/// The canonical form of the name, `None` if it's not a domain.
+/// A single label or an IP address would block way more than intended, so they are rejected.
+fn normalize(name: &str) -> Option<String> {
+    let name = domain_name::normalize(name)?;
+    let is_valid = name.contains('.')
+        && name.parse::<IpAddr>().is_err()
+        && !name.rsplit('.').next()?.chars().all(|c| c.is_ascii_digit());
+    is_valid.then_some(name)
+}
+
+struct Parser {
+    format: ListFormat,
+    /// `$ORIGIN` of the zone file
+    origin: Option<String>,
+    /// Within the parentheses of a multi-line record
+    in_parens: bool,
+}
+
+impl Parser {
+    fn new(format: ListFormat) -> Self {
+        Parser {
+            format,
+            origin: None,
+            in_parens: false,
+        }
+    }
+
+    fn parse(&mut self, line: &str) -> Line {
+        match self.format {
+            ListFormat::Hosts => parse_hosts(line),
+            ListFormat::Dnsmasq => parse_dnsmasq(line),
+            ListFormat::Adguard => parse_adguard(line),
+            ListFormat::Rpz => self.parse_rpz(line),
+            ListFormat::Wildcard => parse_wildcard(line),
+        }
+    }
+
+    fn parse_rpz(&mut self, line: &str) -> Line {
+        let line = strip_comment(line, ';').to_ascii_lowercase();
+        if self.in_parens {
+            self.in_parens = !line.contains(')');
+            return Line::Ignored;
+        }
+        if line.contains('(') && !line.contains(')') {
+            self.in_parens = true;
+        }
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        match tokens.as_slice() {
+            [] => return Line::Ignored,
+            ["$origin", origin, ..] => {
+                self.origin = Some(origin.trim_end_matches('.').to_owned());
+                return Line::Ignored;
+            }
+            [directive, ..] if directive.starts_with('$') => return Line::Ignored,
+            _ => {}
+        }
+
+        // Owner, optional TTL and class, then the type and its data
+        let Some(type_idx) = tokens.iter().position(|token| is_record_type(token)) else {
+            return Line::Invalid;
+        };
+        let owner = match type_idx {
+            0 => return Line::Ignored,
+            _ if tokens[0] == "@" || tokens[0] == "in" || tokens[0].parse::<u32>().is_ok() => {
+                // The record of the zone itself, or one continuing the previous owner
+                return Line::Ignored;
+            }
+            _ => tokens[0],
+        };
+        let rdata = tokens.get(type_idx + 1).copied().unwrap_or_default();
+        match tokens[type_idx] {
+            "cname" if rdata == "rpz-passthru." => return Line::Ignored,
+            "cname" | "a" | "aaaa" => {}
+            _ => return Line::Ignored,
+        }
+
+        let owner = match (owner.strip_suffix('.'), &self.origin) {
+            (Some(absolute), Some(origin)) => absolute
+                .strip_suffix(origin.as_str())
+                .and_then(|name| name.strip_suffix('.'))
+                .unwrap_or(absolute),
+            (Some(absolute), None) => absolute,
+            (None, _) => owner,
+        };
+        let owner = owner.strip_prefix("*.").unwrap_or(owner);
+        // The triggers on IPs and name servers can't be turned into domains
+        if owner.split('.').any(|label| label.starts_with("rpz-")) {
+            return Line::Invalid;
+        }
+        Line::from_names([owner])
+    }
+}
+
+fn is_record_type(token: &str) -> bool {
+    [
+        "a", "aaaa", "cname", "dname", "mx", "ns", "soa", "srv", "txt",
+    ]
+    .contains(&token)
+}
+
+fn strip_comment(line: &str, marker: char) -> &str {
+    line.split(marker).next().unwrap_or_default().trim()
+}
+
+fn parse_hosts(line: &str) -> Line {
+    let line = strip_comment(line, '#');
+    let tokens = line.split_whitespace().collect::<Vec<_>>();
+    match tokens.as_slice() {
+        [] => Line::Ignored,
+        [ip, names @ ..] if !names.is_empty() && ip.parse::<IpAddr>().is_ok() => {
+            let names = names
+                .iter()
+                .filter(|name| !HOSTS_NAMES.contains(&name.to_ascii_lowercase().as_str()))
+                // e.g. `0.0.0.0 0.0.0.0`
+                .filter(|name| name.parse::<IpAddr>().is_err())
+                .copied()
+                .collect::<Vec<_>>();
+            if names.is_empty() {
+                Line::Ignored
+            } else {
+                Line::from_names(names)
+            }
+        }
+        [name] => Line::from_names([*name]),
+        _ => Line::Invalid,
+    }
+}
+
+fn parse_dnsmasq(line: &str) -> Line {
+    let line = strip_comment(line, '#');
+    if line.is_empty() {
+        return Line::Ignored;
+    }
+    let Some((key, value)) = line.split_once('=') else {
+        return Line::Invalid;
+    };
+    let key = key.trim().to_ascii_lowercase();
+    if !["address", "server", "local"].contains(&key.as_str()) {
+        // Other options of the config file
+        return Line::Ignored;
+    }
+    // `/domain/.../target`, the target is empty for `server` and `local` blocking the domains
+    let Some(parts) = value
+        .trim()
+        .strip_prefix('/')
+        .map(|v| v.split('/').collect::<Vec<_>>())
+    else {
+        return Line::Invalid;
+    };
+    let Some((target, domains)) = parts
+        .split_last()
+        .filter(|(_, domains)| !domains.is_empty())
+    else {
+        return Line::Invalid;
+    };
+    if key != "address" && !target.is_empty() {
+        // Forwarded to another server rather than blocked
+        return Line::Ignored;
+    }
+    Line::from_names(domains.iter().copied())
+}
+
+fn parse_adguard(line: &str) -> Line {
+    let line = line.trim();
+    if line.is_empty()
+        || line.starts_with('!')
+        || line.starts_with('#')
+        || line.starts_with('[')
+        // Cosmetic rules of the browser extensions
+        || ["##", "#@#", "#?#", "#$#"].iter().any(|marker| line.contains(marker))
+        // Exceptions aren't supported in a block list, the filters can allow a domain
+        || line.starts_with("@@")
+    {
+        return Line::Ignored;
+    }
+    let Some(rule) = line.strip_prefix("||") else {
+        return parse_hosts(line);
+    };
+    let (pattern, modifiers) = rule.split_once('$').unwrap_or((rule, ""));
+    // Modifiers other than `important` narrow the rule down, a whole domain can't be blocked
+    if !modifiers.is_empty() && !modifiers.eq_ignore_ascii_case("important") {
+        return Line::Invalid;
+    }
+    let pattern = pattern.strip_suffix('|').unwrap_or(pattern);
+    let domain = pattern.strip_suffix('^').unwrap_or(pattern);
+    Line::from_names([domain])
+}
+
+fn parse_wildcard(line: &str) -> Line {
+    let line = strip_comment(line, '#');
+    match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [] => Line::Ignored,
+        [name] => Line::from_names([name.strip_prefix("*.").unwrap_or(name)]),
+        _ => Line::Invalid,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_list, Line, ListFormat, Parser};
+
+    fn domains(domains: &[&str]) -> Line {
+        Line::Domains(domains.iter().map(|d| d.to_string()).collect())
+    }
+
+    #[test]
+    fn test_detect() {
+        let cases: &[(&[&str], ListFormat)] = &[
+            (
+                &["# hosts", "0.0.0.0 ads.example.com", "127.0.0.1 localhost"],
+                ListFormat::Hosts,
+            ),
+            (&["ads.example.com", "tracker.net"], ListFormat::Hosts),
+            (
+                &["address=/ads.example.com/0.0.0.0", "server=/tracker.net/"],
+                ListFormat::Dnsmasq,
+            ),
+            (
+                &["! Title: DNS filter", "||ads.example.com^", "tracker.net"],
+                ListFormat::Adguard,
+            ),
+            (
+                &[
+                    "$TTL 300",
+                    "@ SOA localhost. root.localhost. 1 1h 15m 30d 2h",
+                    "ads.example.com CNAME .",
+                ],
+                ListFormat::Rpz,
+            ),
+            (&["*.ads.example.com", "tracker.net"], ListFormat::Wildcard),
+            (&[], ListFormat::Hosts),
+        ];
+        for (lines, format) in cases {
+            assert_eq!(ListFormat::detect(lines), *format, "{lines:?}");
+        }
+    }
+
+    #[test]
+    fn test_format_names() {
+        for format in ListFormat::ALL {
+            assert_eq!(format.as_str().parse::<ListFormat>(), Ok(format));
+        }
+        assert_eq!("AdGuard".parse::<ListFormat>(), Ok(ListFormat::Adguard));
+        assert!("hosts.txt".parse::<ListFormat>().is_err());
+    }
+
+    #[test]
+    fn test_parse_lines() {
+        let cases: &[(ListFormat, &str, Line)] = &[
+            (
+                ListFormat::Hosts,
+                "0.0.0.0 Ads.Example.com",
+                domains(&["ads.example.com"]),
+            ),
+            (
+                ListFormat::Hosts,
+                "0.0.0.0 a.com b.com # two",
+                domains(&["a.com", "b.com"]),
+            ),
+            (ListFormat::Hosts, "tracker.net", domains(&["tracker.net"])),
+            (ListFormat::Hosts, "127.0.0.1 localhost", Line::Ignored),
+            (ListFormat::Hosts, "0.0.0.0 0.0.0.0", Line::Ignored),
+            (
+                ListFormat::Hosts,
+                "::1 ip6-localhost ip6-loopback",
+                Line::Ignored,
+            ),
+            (ListFormat::Hosts, "# comment", Line::Ignored),
+            (ListFormat::Hosts, "127.0.0.1", Line::Invalid),
+            (ListFormat::Hosts, "com", Line::Invalid),
+            (
+                ListFormat::Hosts,
+                "ads.example.com tracker.net",
+                Line::Invalid,
+            ),
+            (ListFormat::Hosts, "0.0.0.0 ads/example.com", Line::Invalid),
+            (
+                ListFormat::Dnsmasq,
+                "address=/ads.example.com/0.0.0.0",
+                domains(&["ads.example.com"]),
+            ),
+            (
+                ListFormat::Dnsmasq,
+                "address=/a.com/b.com/#",
+                domains(&["a.com", "b.com"]),
+            ),
+            (
+                ListFormat::Dnsmasq,
+                "server=/tracker.net/",
+                domains(&["tracker.net"]),
+            ),
+            (
+                ListFormat::Dnsmasq,
+                "local=/tracker.net/",
+                domains(&["tracker.net"]),
+            ),
+            (
+                ListFormat::Dnsmasq,
+                "server=/lan.net/192.168.1.1",
+                Line::Ignored,
+            ),
+            (ListFormat::Dnsmasq, "cache-size=1000", Line::Ignored),
+            (
+                ListFormat::Dnsmasq,
+                "address=ads.example.com",
+                Line::Invalid,
+            ),
+            (ListFormat::Dnsmasq, "ads.example.com", Line::Invalid),
+            (
+                ListFormat::Adguard,
+                "||ads.example.com^",
+                domains(&["ads.example.com"]),
+            ),
+            (
+                ListFormat::Adguard,
+                "||ads.example.com^$important",
+                domains(&["ads.example.com"]),
+            ),
+            (
+                ListFormat::Adguard,
+                "||tracker.net^|",
+                domains(&["tracker.net"]),
+            ),
+            (
+                ListFormat::Adguard,
+                "0.0.0.0 tracker.net",
+                domains(&["tracker.net"]),
+            ),
+            (ListFormat::Adguard, "! comment", Line::Ignored),
+            (ListFormat::Adguard, "[Adblock Plus 2.0]", Line::Ignored),
+            (ListFormat::Adguard, "@@||cdn.example.com^", Line::Ignored),
+            (ListFormat::Adguard, "example.com##.banner", Line::Ignored),
+            (ListFormat::Adguard, "||ads.*^", Line::Invalid),
+            (
+                ListFormat::Adguard,
+                "||ads.example.com^$client=10.0.0.1",
+                Line::Invalid,
+            ),
+            (ListFormat::Adguard, "/^ad[0-9]+\\./", Line::Invalid),
+            (
+                ListFormat::Wildcard,
+                "*.ads.example.com",
+                domains(&["ads.example.com"]),
+            ),
+            (
+                ListFormat::Wildcard,
+                "tracker.net # comment",
+                domains(&["tracker.net"]),
+            ),
+            (ListFormat::Wildcard, "*.com", Line::Invalid),
+            (ListFormat::Wildcard, "ads.*.example.com", Line::Invalid),
+            (
+                ListFormat::Hosts,
+                "0.0.0.0 Bücher.example xn--e1afmkfd.xn--p1ai",
+                domains(&["xn--bcher-kva.example", "xn--e1afmkfd.xn--p1ai"]),
+            ),
+            (
+                ListFormat::Adguard,
+                "||ПРИМЕР.рф^",
+                domains(&["xn--e1afmkfd.xn--p1ai"]),
+            ),
+            (
+                ListFormat::Dnsmasq,
+                "address=/münchen.de/0.0.0.0",
+                domains(&["xn--mnchen-3ya.de"]),
+            ),
+            (ListFormat::Hosts, "0.0.0.0 bü cher.example", Line::Invalid),
+        ];
+        for (format, line, expected) in cases {
+            assert_eq!(
+                Parser::new(*format).parse(line),
+                *expected,
+                "{format} {line}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_rpz() {
+        let zone = [
+            "$TTL 2h",
+            "$ORIGIN rpz.example.org.",
+            "@ IN SOA localhost. root.localhost. (",
+            "    2024010101 ; serial",
+            "    1h 15m 30d 2h )",
+            "  IN NS localhost.",
+            "ads.example.com CNAME . ; nxdomain",
+            "*.tracker.net.rpz.example.org. 300 IN CNAME *.",
+            "allowed.example.com CNAME rpz-passthru.",
+            "redirected.example.com A 10.0.0.1",
+            "32.1.0.0.10.rpz-ip CNAME .",
+            "garbage",
+        ];
+        let (format, parsed) = parse_list(None, &zone);
+        assert_eq!(format, ListFormat::Rpz);
+        let mut found = parsed.domains.into_iter().collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(
+            found,
+            vec!["ads.example.com", "redirected.example.com", "tracker.net"]
+        );
+        assert_eq!(parsed.invalid, 2);
+        assert_eq!(
+            parsed.invalid_samples,
+            vec!["32.1.0.0.10.rpz-ip CNAME .", "garbage"]
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let (_, mut parsed) = parse_list(None, &["||ads.example.com^", "||bad*^"]);
+        let (format, other) = parse_list(None, &["0.0.0.0 tracker.net", "a b c"]);
+        assert_eq!(format, ListFormat::Hosts);
+        parsed.merge(other);
+        assert_eq!(parsed.domains.len(), 2);
+        assert_eq!(parsed.invalid, 2);
+        assert_eq!(parsed.invalid_samples, vec!["||bad*^", "a b c"]);
+    }
+
+    #[test]
+    fn test_override() {
+        let lines = ["||ads.example.com^", "tracker.net"];
+        let (format, parsed) = parse_list(Some(ListFormat::Hosts), &lines);
+        assert_eq!(format, ListFormat::Hosts);
+        assert_eq!(parsed.domains.len(), 1);
+        assert_eq!(parsed.invalid, 1);
+    }
+}
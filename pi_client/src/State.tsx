@@ -64,6 +64,15 @@ export interface DashboardData {
     queries: { [key: string]: number },
     top_approved: { [key: string]: number },
     top_rejected: { [key: string]: number },
+    rejected_by_category: { [key: string]: number },
+    rule_hits: RuleHits[],
+}
+
+export interface RuleHits {
+    rule: string,
+    kind: "allow" | "block" | "list",
+    hit_count: number,
+    last_hit?: number,
 }
 
 export interface DnsQuery {
@@ -76,6 +85,8 @@ export interface DnsQuery {
     filtered?: boolean,
     reason?: string,
     resp_time: number,
+    transport?: string,
+    category?: string,
 }
 
 export const INITIAL_STATE: AppState = {
@@ -141,6 +141,52 @@ export default function Dashboard(): JSX.Element {
                         </div>
                     </div>
                 </div>
+                {Object.keys(dashboardData.rejected_by_category ?? {}).length > 0 && <div className="row">
+                    <div className="col col-lg-4 col-md-6 col-sm-12">
+                        <div className="card">
+                            <div className="card-header">
+                                Blocked by Category
+                            </div>
+                            <div className="card-body">
+                                <ReactApexChart
+                                    options={{ ...PIE_OPTIONS, labels: Object.keys(dashboardData.rejected_by_category) }}
+                                    series={Object.values(dashboardData.rejected_by_category)}
+                                    type="pie"
+                                    height={370} />
+                            </div>
+                        </div>
+                    </div>
+                </div>}
+                <div className="row">
+                    <div className="col col-lg-12 col-md-12 col-sm-12">
+                        <div className="card">
+                            <div className="card-header">
+                                Filter &amp; Block List Hits (all time)
+                            </div>
+                            <div className="card-body">
+                                <table className="table table-striped table-sm">
+                                    <thead>
+                                        <tr>
+                                            <th>Rule</th>
+                                            <th>Kind</th>
+                                            <th>Hits</th>
+                                            <th>Last Hit</th>
+                                        </tr>
+                                    </thead>
+                                    <tbody>
+                                        {(dashboardData.rule_hits ?? []).map((hits, idx) => <tr key={idx}
+                                            className={hits.hit_count === 0 ? "text-muted" : ""}>
+                                            <td>{hits.rule}</td>
+                                            <td>{hits.kind}</td>
+                                            <td>{hits.hit_count}</td>
+                                            <td>{hits.last_hit != null ? new Date(hits.last_hit).toLocaleString() : "Never"}</td>
+                                        </tr>)}
+                                    </tbody>
+                                </table>
+                            </div>
+                        </div>
+                    </div>
+                </div>
             </>}
         </section >
     );
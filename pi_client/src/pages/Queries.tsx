@@ -1,4 +1,4 @@
-import React, { useContext, useEffect } from 'react';
+import React, { useContext, useEffect, useState } from 'react';
 import { Loader } from '../Icons';
 import { AppContext, QUERY_SIZE, DnsQuery } from '../State';
 import { loadQuery } from '../dataFetcher';
@@ -6,12 +6,17 @@ import { loadQuery } from '../dataFetcher';
 export default function Queries(): JSX.Element {
     const { state, dispatch } = useContext(AppContext);
     const { status, querySize, queries, errorMsg } = state;
+    const [category, setCategory] = useState<string>();
     useEffect(() => {
         if (queries == null) {
             loadQuery(dispatch, querySize);
         }
     }, []);
 
+    // The categories of the lists the queries were blocked by
+    const categories = Array.from(new Set((queries ?? []).flatMap(q => q.category != null ? [q.category] : []))).sort();
+    const shownQueries = category == null ? queries : queries?.filter(q => q.category === category);
+
     return (
         <section className="h-100">
             <header>
@@ -26,6 +31,17 @@ export default function Queries(): JSX.Element {
                     </span>
                 )}
                 </p>
+                {categories.length > 0 && <p className="filter-date-range">
+                    Blocked by:
+                    <a href="#" className={category == null ? "selected" : ""}
+                        onClick={() => setCategory(undefined)}> All </a>
+                    {categories.map((curr, idx) =>
+                    <span key={idx}>
+                        | <a href="#" className={category === curr ? "selected" : ""}
+                            onClick={() => setCategory(curr)}> {curr} </a>
+                    </span>
+                )}
+                </p>}
             </header>
             {status === "LOADING" && <div className="full-screen-center"><Loader /></div>}
             {status === "ERROR" && <div className="full-screen-center">
@@ -34,7 +50,7 @@ export default function Queries(): JSX.Element {
                     {errorMsg != null && <p>{errorMsg}</p>}
                 </div>
             </div>}
-            {status === "DONE" && queries != null && <>
+            {status === "DONE" && shownQueries != null && <>
                 <div className="row">
                     <div className="col">
                         <table className="table table-sm queries">
@@ -43,13 +59,15 @@ export default function Queries(): JSX.Element {
                                     <th>Time</th>
                                     <th>Name</th>
                                     <th>Type</th>
+                                    <th>Transport</th>
                                     <th style={{width:"20%"}}>Response</th>
                                     <th>Reason</th>
+                                    <th>Category</th>
                                     <th>Latency</th>
                                 </tr>
                             </thead>
                             <tbody>
-                                {tableContent(queries)}
+                                {tableContent(shownQueries)}
                             </tbody>
                         </table>
                     </div>
@@ -60,15 +78,17 @@ export default function Queries(): JSX.Element {
 }
 
 function tableContent(queries: DnsQuery[]) {
-    return queries.map(({ id, req_time, req_type, name, responded, filtered, reason, resp_time, reply }) => {
+    return queries.map(({ id, req_time, req_type, name, responded, filtered, reason, resp_time, reply, transport, category }) => {
         const filterClass = filtered === true ? "approved" : filtered === false ? "blocked" : "";
         const respondedClass = responded === false ? "no-response" : "";
         return (<tr key={id} className={`${filterClass} ${respondedClass}`}>
             <td>{new Date(req_time).toISOString()}</td>
             <td>{name}</td>
             <td>{req_type}</td>
+            <td>{transport?.toUpperCase()}</td>
             <td className="text-truncate" style={{maxWidth: 0}} title={reply}>{reply}</td>
             <td>{reason}</td>
+            <td>{category}</td>
             <td className="text-right">{resp_time} ms</td>
         </tr>);
     });
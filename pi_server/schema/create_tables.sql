@@ -8,7 +8,9 @@ create table dns_requests (
     filtered BOOLEAN,
     reason TEXT,
     responded BOOLEAN NOT NULL,
-    resp_ms INTEGER NOT NULL
+    resp_ms INTEGER NOT NULL,
+    transport TEXT,
+    category TEXT
 );
 create INDEX dns_req_time_idx on dns_requests(req_time);
 
@@ -0,0 +1 @@
+alter table dns_requests add column transport TEXT;
@@ -7,6 +7,7 @@ use sqlx::types::chrono::NaiveDateTime;
 use trust_dns_proto::op::Message;
 
 use crate::db::POOL;
+use crate::dns::Transport;
 use crate::web::ws_dns_req;
 use crate::Timer;
 
@@ -24,6 +25,9 @@ pub struct DnsRequest {
     pub reason: Option<String>,
     pub responded: bool,
     pub resp_ms: i64,
+    pub transport: Option<String>,
+    /// Of the lists the request was blocked or allowed by
+    pub category: Option<String>,
 }
 
 pub async fn fetch_dns_reqs(limit: u32) -> anyhow::Result<Vec<DnsRequest>> {
@@ -39,17 +43,20 @@ pub async fn fetch_dns_reqs(limit: u32) -> anyhow::Result<Vec<DnsRequest>> {
     Ok(res)
 }
 
-#[allow(deprecated)]
+#[allow(deprecated, clippy::too_many_arguments)]
 pub async fn save_request(
     req_time: NaiveDateTime,
     msg: &Message,
     filtered: Option<bool>,
     reason: Option<String>,
+    category: Option<String>,
     responded: bool,
     resp_ms: i64,
     addr: SocketAddr,
+    transport: Transport,
 ) -> anyhow::Result<i64> {
     let requester = addr.to_string();
+    let transport = transport.as_str();
     let req_type = msg.queries().first().map(|q| q.query_type().to_string());
     let request = msg.queries().first().map(|q| q.name().to_string());
     let response = msg
@@ -62,8 +69,8 @@ pub async fn save_request(
     let req_id = sqlx::query!(
         r#"
         insert into 
-        dns_requests(req_time, requester, req_type, request, response, filtered, reason, responded, resp_ms)
-        values(?, ?, ?, ?, ?, ?, ?, ?, ?)
+        dns_requests(req_time, requester, req_type, request, response, filtered, reason, responded, resp_ms, transport, category)
+        values(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         "#,
         req_time,
         requester,
@@ -73,7 +80,9 @@ pub async fn save_request(
         filtered,
         reason,
         responded,
-        resp_ms
+        resp_ms,
+        transport,
+        category
     )
     .execute(POOL.get().unwrap())
     .await?
@@ -91,6 +100,8 @@ pub async fn save_request(
         reason,
         responded,
         resp_ms,
+        transport: Some(transport.to_owned()),
+        category,
     });
     Ok(req_id)
 }
@@ -178,3 +189,20 @@ pub async fn agg_by_filtered(
     log::info!("Request aggregation time {}", start.t());
     Ok(res)
 }
+
+/// How many requests the lists of every category have blocked
+pub async fn agg_by_category(from: NaiveDateTime) -> anyhow::Result<Vec<(String, i64)>> {
+    let start = Instant::now();
+    let res = sqlx::query_as(
+        r#"
+        select category, count(req_id) cnt from dns_requests where req_time >= ? and filtered = false
+        and responded = true and category is not null
+        group by category order by cnt desc
+        "#,
+    )
+    .bind(from)
+    .fetch_all(POOL.get().unwrap())
+    .await?;
+    log::info!("Category aggregation time {}", start.t());
+    Ok(res)
+}
@@ -12,6 +12,13 @@ pub mod sys_info;
 
 static POOL: OnceCell<SqlitePool> = OnceCell::new();
 
+/// Schema changes for the dbs created by an older version, `PRAGMA user_version` tracks how many
+/// of them have been applied. `create_tables.sql` always has the latest schema.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../../schema/migrations/001_transport.sql"),
+    include_str!("../../schema/migrations/002_request_category.sql"),
+];
+
 pub async fn init_db() -> anyhow::Result<()> {
     let PiConfig {
         db_path,
@@ -23,6 +30,8 @@ pub async fn init_db() -> anyhow::Result<()> {
     if !Path::new(db_path).exists() {
         log::info!("DB hasn't been created yet, creating it...");
         create_db(db_path).await?;
+    } else {
+        migrate(db_path).await?;
     }
 
     let pool = SqlitePoolOptions::new()
@@ -46,10 +55,30 @@ async fn create_db(db_path: &str) -> anyhow::Result<()> {
     let _ = sqlx::query(include_str!("../../schema/create_tables.sql"))
         .execute(&mut con)
         .await?;
+    sqlx::query(&format!("PRAGMA user_version = {};", MIGRATIONS.len()))
+        .execute(&mut con)
+        .await?;
     log::info!("Created a new database file '{}'", db_path);
     Ok(())
 }
 
+async fn migrate(db_path: &str) -> anyhow::Result<()> {
+    let mut con = SqliteConnection::connect(&format!("sqlite://{}?mode=rw", db_path)).await?;
+    let (version,): (i64,) = sqlx::query_as("PRAGMA user_version;")
+        .fetch_one(&mut con)
+        .await?;
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        log::info!("Applying migration {} to {}", idx + 1, db_path);
+        let mut trans = con.begin().await?;
+        sqlx::query(migration).execute(&mut trans).await?;
+        sqlx::query(&format!("PRAGMA user_version = {};", idx + 1))
+            .execute(&mut trans)
+            .await?;
+        trans.commit().await?;
+    }
+    Ok(())
+}
+
 async fn clean_old_entries() {
     async fn delete() -> anyhow::Result<()> {
         let overflow = Local::now().naive_local() - chrono::Duration::days(30);
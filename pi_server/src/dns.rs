@@ -1,24 +1,54 @@
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 
 use anyhow::Context;
 use chrono::Local;
-use domain::db::block_list::find_blocked_domain;
+use domain::db::block_list::ListCategory;
+use domain::{find_allowed_domain, find_blocked_domain, record_hit, source_labels, Hit};
 use futures_util::StreamExt;
+use itertools::Itertools;
 use log::{debug, error, info, warn};
+use once_cell::sync::OnceCell;
 use tokio::net::UdpSocket;
-use trust_dns_client::client::AsyncClient;
+use trust_dns_client::client::{AsyncClient, ClientHandle};
 use trust_dns_proto::op::{Message, MessageType};
-use trust_dns_proto::rr::{RData, Record, RecordType};
-use trust_dns_proto::serialize::binary::BinEncodable;
+use trust_dns_proto::rr::{DNSClass, Name, RData, Record, RecordType};
 use trust_dns_proto::udp::{UdpClientStream, UdpStream};
 use trust_dns_proto::xfer::{DnsRequest, DnsResponse, SerialMessage};
-use trust_dns_proto::{BufDnsStreamHandle, DnsHandle, DnsStreamHandle};
+use trust_dns_proto::{DnsHandle, DnsStreamHandle};
 
 use crate::cloudflared;
 use crate::db::dns_requests::save_request;
+use crate::dnstap::{self, DnstapMessage, MessageType as DnstapType, SocketProtocol};
+use crate::rebind::RebindAction;
+use crate::zones;
 use crate::{PiConfig, Timer, PI_CONFIG};
 
+static UPSTREAM: OnceCell<AsyncClient> = OnceCell::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Dot,
+    Doh,
+}
+
+impl Transport {
+    pub fn is_encrypted(self) -> bool {
+        matches!(self, Transport::Dot | Transport::Doh)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Dot => "dot",
+            Transport::Doh => "doh",
+        }
+    }
+}
+
 pub async fn start_dns_server() -> anyhow::Result<()> {
     let PiConfig {
         cloudflared_port,
@@ -32,6 +62,9 @@ pub async fn start_dns_server() -> anyhow::Result<()> {
 
     let connection = UdpClientStream::<UdpSocket>::new(([127, 0, 0, 1], *cloudflared_port).into());
     let (cloudflare_client, req_sender) = AsyncClient::connect(connection).await?;
+    UPSTREAM
+        .set(cloudflare_client)
+        .map_err(|_| anyhow::anyhow!("Failed to set upstream dns client"))?;
 
     info!("Starting DNS request sender");
     tokio::spawn(req_sender);
@@ -56,34 +89,60 @@ pub async fn start_dns_server() -> anyhow::Result<()> {
                 message
             }
         };
-        let client = cloudflare_client.clone();
-        let sender = stream_handle.with_remote_addr(message.addr());
+        let mut sender = stream_handle.with_remote_addr(message.addr());
         tokio::spawn(async move {
-            let addr = message.addr();
-            if let Err(e) = process_dns_request(message, client, sender).await {
-                warn!("Failed to process message from {addr}: {e}");
+            let (bytes, addr) = message.into_parts();
+            match process_message(&bytes, addr, server_addr, Transport::Udp).await {
+                Ok(Some(reply)) => match sender.send(SerialMessage::new(reply, addr)) {
+                    Ok(_) => debug!("Successfully replied back to {addr}"),
+                    Err(e) => error!("Failed to send the response back: {e}"),
+                },
+                Ok(None) => error!("Response is empty, can't reply back"),
+                Err(e) => warn!("Failed to process message from {addr}: {e}"),
             }
         });
     }
     Ok(())
 }
 
-async fn process_dns_request(
-    message: SerialMessage,
-    async_client: AsyncClient,
-    stream_handle: BufDnsStreamHandle,
-) -> anyhow::Result<()> {
+/// Runs a wire format message through the filters, local zones and upstream, logs it and
+/// returns the wire format reply. Shared by all the transports the server listens on.
+pub async fn process_message(
+    bytes: &[u8],
+    addr: SocketAddr,
+    server_addr: SocketAddr,
+    transport: Transport,
+) -> anyhow::Result<Option<Vec<u8>>> {
     let start = Instant::now();
     let req_time = Local::now().naive_local();
+    let query_time = SystemTime::now();
+    if dnstap::is_enabled() {
+        dnstap::log_message(DnstapMessage {
+            message_type: DnstapType::ClientQuery,
+            protocol: transport.into(),
+            query_addr: Some(addr),
+            response_addr: server_addr,
+            query_time,
+            response_time: None,
+            payload: bytes,
+        });
+    }
+    let client = UPSTREAM
+        .get()
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Upstream dns client isn't initialized yet"))?;
     let mut processor = MessageProcessor {
-        client: async_client,
-        sender: stream_handle,
-        addr: message.addr(),
-        request: message.to_message()?,
+        client,
+        addr,
+        server_addr,
+        query_time,
+        transport,
+        request: Message::from_vec(bytes)?,
         responses: Vec::with_capacity(1),
         allowed: None,
+        category: None,
     };
-    processor.process().await;
+    let reply = processor.process().await;
     info!("Time taken to process dns request: {}", start.elapsed().t());
 
     let (reason, allowed) = processor
@@ -99,69 +158,158 @@ async fn process_dns_request(
     } else {
         &processor.responses[0]
     };
+    let category = processor.category.map(|c| c.to_string());
     save_request(
-        req_time,
-        log_res,
-        allowed,
-        reason,
-        responded,
-        resp_ms,
-        message.addr(),
+        req_time, log_res, allowed, reason, category, responded, resp_ms, addr, transport,
     )
     .await?;
-    Ok(())
+    Ok(reply)
+}
+
+/// Asks the upstream directly, without the filters or any of the policies applied
+pub async fn query_upstream(name: Name, record_type: RecordType) -> anyhow::Result<DnsResponse> {
+    let mut client = UPSTREAM
+        .get()
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Upstream dns client isn't initialized yet"))?;
+    Ok(client.query(name, DNSClass::IN, record_type).await?)
+}
+
+/// Runs the name through the filters first, then the allowed and the blocked domains
+/// Returns why the name is allowed or blocked, and what the decision is credited to
+pub async fn check_domain(
+    name: &str,
+    client: Option<IpAddr>,
+    dns_type: Option<&str>,
+) -> Option<(String, bool, Hit)> {
+    // The filters of the user beat the allowlists, which beat the blocklists
+    if let Some((allow, reason, f_ids)) = domain::check_filters(name, client, dns_type).await {
+        return Some((reason, allow, Hit::Filters(f_ids)));
+    }
+    if let Some((domain, sources)) = find_allowed_domain(name).await {
+        return Some((
+            format!("Allowed domain: {domain} {}", listed_in(&sources).await),
+            true,
+            Hit::Sources(sources),
+        ));
+    }
+    let (domain, sources) = find_blocked_domain(name).await?;
+    Some((
+        format!("Blocked domain: {domain} {}", listed_in(&sources).await),
+        false,
+        Hit::Sources(sources),
+    ))
+}
+
+/// The decision on a message from those on its questions, in their order. The first blocked
+/// question blocks the whole message, a blocked name can't get through by being asked along with
+/// an allowed one. Otherwise the first allowed question gives the reason.
+fn decide_message(
+    verdicts: impl IntoIterator<Item = Option<(String, bool, Hit)>>,
+) -> Option<(String, bool, Hit)> {
+    let mut allowed = None;
+    for verdict in verdicts.into_iter().flatten() {
+        if !verdict.1 {
+            return Some(verdict);
+        }
+        allowed = allowed.or(Some(verdict));
+    }
+    allowed
+}
+
+/// Names the sources as they are shown, with their category
+async fn listed_in(sources: &[String]) -> String {
+    let (names, category) = source_labels(sources).await;
+    let names = names.iter().map(|name| format!("'{name}'")).join(", ");
+    match category {
+        Some(category) => format!("listed in {names} ({category})"),
+        None => format!("listed in {names}"),
+    }
+}
+
+/// The category of the sources a decision is credited to
+async fn hit_category(hit: &Hit) -> Option<ListCategory> {
+    match hit {
+        Hit::Sources(sources) => source_labels(sources).await.1,
+        Hit::Filters(_) => None,
+    }
 }
 
 struct MessageProcessor {
     client: AsyncClient,
-    sender: BufDnsStreamHandle,
     addr: SocketAddr,
+    server_addr: SocketAddr,
+    query_time: SystemTime,
+    transport: Transport,
     request: Message,
     responses: Vec<DnsResponse>,
     allowed: Option<(String, bool)>,
+    /// Of the lists the request was blocked or allowed by
+    category: Option<ListCategory>,
 }
 
 impl MessageProcessor {
-    async fn process(&mut self) {
-        self.allowed = self.allow_request().await;
-        if self
-            .allowed
-            .as_ref()
-            .map(|(_, allowed)| *allowed)
-            .unwrap_or(true)
-        {
-            self.forward_to_cloudflare().await;
+    async fn process(&mut self) -> Option<Vec<u8>> {
+        if let Some((response, reason)) = zones::resolve(&self.request).await {
+            self.allowed = Some((reason, true));
+            self.responses.push(DnsResponse::from(response));
         } else {
-            self.create_fake_response();
+            if let Some((reason, allowed, category)) = self.allow_request().await {
+                self.allowed = Some((reason, allowed));
+                self.category = category;
+            }
+            if self
+                .allowed
+                .as_ref()
+                .map(|(_, allowed)| *allowed)
+                .unwrap_or(true)
+            {
+                self.forward_to_cloudflare().await;
+                self.protect_rebinding();
+            } else {
+                self.create_fake_response();
+            }
         }
-        self.reply_back();
         self.log_msg();
+        self.reply()
     }
 
-    async fn allow_request(&self) -> Option<(String, bool)> {
+    async fn allow_request(&self) -> Option<(String, bool, Option<ListCategory>)> {
         let start = Instant::now();
-        let mut block_reason = None;
+        let mut verdicts = Vec::with_capacity(self.request.queries().len());
         for query in self.request.queries() {
             let name = query.name().to_lowercase().to_string();
-            if let Some((allow, reason)) = domain::check_filters(&name).await {
-                if allow {
-                    return Some((reason, allow));
-                } else {
-                    block_reason = Some(reason);
-                }
-            }
-            if let Some((domain, source)) = find_blocked_domain(name).await.ok().flatten() {
-                block_reason = Some(format!("Blocked domain: {domain} listed in '{source}'"));
-            }
+            let dns_type = query.query_type().to_string();
+            verdicts.push(check_domain(&name, Some(self.addr.ip()), Some(&dns_type)).await);
         }
         info!("Time taken to run filters: {}", start.elapsed().t());
-        block_reason.map(|reason| (reason, false))
+        let (reason, allowed, hit) = decide_message(verdicts)?;
+        record_hit(&hit);
+        Some((reason, allowed, hit_category(&hit).await))
     }
 
     async fn forward_to_cloudflare(&mut self) {
         let start = Instant::now();
         let id = self.request.id();
-        let request = DnsRequest::new(self.request.clone(), Default::default());
+        let forwarder_addr =
+            SocketAddr::from(([127, 0, 0, 1], PI_CONFIG.get().unwrap().cloudflared_port));
+        let mut request = self.request.clone();
+        PI_CONFIG.get().unwrap().edns.prepare_request(&mut request);
+        let forward_time = SystemTime::now();
+        if dnstap::is_enabled() {
+            if let Ok(payload) = request.to_vec() {
+                dnstap::log_message(DnstapMessage {
+                    message_type: DnstapType::ForwarderQuery,
+                    protocol: SocketProtocol::Udp,
+                    query_addr: None,
+                    response_addr: forwarder_addr,
+                    query_time: forward_time,
+                    response_time: None,
+                    payload: &payload,
+                });
+            }
+        }
+        let request = DnsRequest::new(request, Default::default());
         let mut res_stream = self.client.send(request);
         while let Some(response) = res_stream.next().await {
             let mut res = match response {
@@ -171,25 +319,68 @@ impl MessageProcessor {
                 }
                 Ok(res) => res,
             };
+            if dnstap::is_enabled() {
+                if let Ok(payload) = res.to_vec() {
+                    dnstap::log_message(DnstapMessage {
+                        message_type: DnstapType::ForwarderResponse,
+                        protocol: SocketProtocol::Udp,
+                        query_addr: None,
+                        response_addr: forwarder_addr,
+                        query_time: forward_time,
+                        response_time: Some(SystemTime::now()),
+                        payload: &payload,
+                    });
+                }
+            }
             res.set_id(id); // Somehow the id has changed
             self.responses.push(res);
         }
         info!("Time taken to forward dns request: {}", start.elapsed().t());
     }
 
+    fn protect_rebinding(&mut self) {
+        let config = match &PI_CONFIG.get().unwrap().rebind_protection {
+            Some(config) => config,
+            None => return,
+        };
+        let reasons = self
+            .responses
+            .iter_mut()
+            .filter_map(|res| config.check(res))
+            .collect::<Vec<_>>();
+        if let Some(reason) = reasons.into_iter().next() {
+            warn!("{reason}");
+            // Stripping only drops the private addresses, the query itself is still answered
+            let blocked = config.action == RebindAction::Block;
+            if blocked {
+                self.responses.clear();
+                self.create_fake_response();
+            }
+            self.allowed = Some((reason, !blocked));
+        }
+    }
+
     fn create_fake_response(&mut self) {
         let mut response = DnsResponse::from(self.request.clone());
         response.set_message_type(MessageType::Response);
         response.set_recursion_available(true);
         response.set_authentic_data(false);
+        // With the block page enabled, browsers land on the Pi and see why the domain is blocked
+        let block_page = PI_CONFIG.get().unwrap().block_page.as_ref();
+        let ipv4 = block_page
+            .map(|bp| bp.ipv4)
+            .unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let ipv6 = block_page
+            .and_then(|bp| bp.ipv6)
+            .unwrap_or(Ipv6Addr::UNSPECIFIED);
         for query in self.request.queries() {
             let mut record = Record::default();
             record.set_name(query.name().clone());
             record.set_rr_type(query.query_type());
             record.set_dns_class(query.query_class());
             let rdata = match query.query_type() {
-                RecordType::A => Some(RData::A(Ipv4Addr::UNSPECIFIED)),
-                RecordType::AAAA => Some(RData::AAAA(Ipv6Addr::UNSPECIFIED)),
+                RecordType::A => Some(RData::A(ipv4)),
+                RecordType::AAAA => Some(RData::AAAA(ipv6)),
                 _ => None,
             };
             record.set_data(rdata);
@@ -199,22 +390,29 @@ impl MessageProcessor {
         self.responses.push(response);
     }
 
-    fn reply_back(&mut self) {
-        if !self.responses.is_empty() {
-            let payload = self
-                .responses
-                .iter()
-                .filter_map(|res| res.to_bytes().ok())
-                .flatten()
-                .collect::<Vec<_>>();
-            let response = SerialMessage::new(payload, self.addr);
-            match self.sender.send(response) {
-                Ok(_) => debug!("Successfully replied back to {}", self.addr),
-                Err(e) => error!("Failed to send the response back: {e}"),
-            };
-        } else {
-            error!("Response is empty, can't reply back");
+    fn reply(&mut self) -> Option<Vec<u8>> {
+        if self.responses.is_empty() {
+            return None;
+        }
+        let edns = &PI_CONFIG.get().unwrap().edns;
+        let payload = self
+            .responses
+            .iter_mut()
+            .filter_map(|res| edns.to_wire(&self.request, res, self.transport).ok())
+            .flatten()
+            .collect::<Vec<_>>();
+        if dnstap::is_enabled() {
+            dnstap::log_message(DnstapMessage {
+                message_type: DnstapType::ClientResponse,
+                protocol: self.transport.into(),
+                query_addr: Some(self.addr),
+                response_addr: self.server_addr,
+                query_time: self.query_time,
+                response_time: Some(SystemTime::now()),
+                payload: &payload,
+            });
         }
+        Some(payload)
     }
 
     fn log_msg(&self) {
@@ -224,3 +422,35 @@ impl MessageProcessor {
         );
     }
 }
+
+#[cfg(test)]
+mod test {
+    use domain::Hit;
+
+    use super::decide_message;
+
+    fn verdict(reason: &str, allowed: bool) -> Option<(String, bool, Hit)> {
+        Some((reason.to_owned(), allowed, Hit::Filters(vec![])))
+    }
+
+    #[test]
+    fn test_decide_message() {
+        let reason = |verdicts: Vec<_>| decide_message(verdicts).map(|(reason, _, _)| reason);
+        assert_eq!(reason(vec![]), None);
+        assert_eq!(reason(vec![None, None]), None);
+        assert_eq!(reason(vec![None, verdict("a", true)]), Some("a".into()));
+        // A block isn't overridden by the questions after it, nor by the allowed ones before it
+        assert_eq!(
+            reason(vec![verdict("a", false), verdict("b", false)]),
+            Some("a".into())
+        );
+        assert_eq!(
+            reason(vec![verdict("a", true), None, verdict("b", false)]),
+            Some("b".into())
+        );
+        assert_eq!(
+            reason(vec![verdict("a", true), verdict("b", true)]),
+            Some("a".into())
+        );
+    }
+}
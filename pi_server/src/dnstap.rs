@@ -0,0 +1,584 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::*;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time;
+
+use crate::dns::Transport;
+use crate::PI_CONFIG;
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+const MB: u64 = 1024 * 1024;
+/// Frames waiting to be written, more than that are dropped rather than held in memory while the
+/// reader is stalled
+const QUEUE_SIZE: usize = 10_000;
+
+static DNSTAP_SENDER: OnceCell<Sender<Vec<u8>>> = OnceCell::new();
+static DROPPED_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DnstapConfig {
+    /// Frame Streams reader listening on a unix socket, e.g. `dnstap -u /tmp/dnstap.sock`
+    pub socket_path: Option<String>,
+    /// File to write the frames to, used only when `socket_path` isn't set
+    pub file_path: Option<String>,
+    pub file_size_mb: Option<u64>,
+    pub file_count: Option<u32>,
+    pub identity: Option<String>,
+}
+
+/// Dnstap message types as defined in dnstap.proto
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+/// Dnstap socket protocols as defined in dnstap.proto
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketProtocol {
+    Udp = 1,
+    Tcp = 2,
+    Dot = 3,
+    Doh = 4,
+}
+
+impl From<Transport> for SocketProtocol {
+    fn from(transport: Transport) -> Self {
+        match transport {
+            Transport::Udp => SocketProtocol::Udp,
+            Transport::Tcp => SocketProtocol::Tcp,
+            Transport::Dot => SocketProtocol::Dot,
+            Transport::Doh => SocketProtocol::Doh,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DnstapMessage<'a> {
+    pub message_type: MessageType,
+    pub protocol: SocketProtocol,
+    /// Left out when it isn't known, e.g. the port the upstream client sends from
+    pub query_addr: Option<SocketAddr>,
+    pub response_addr: SocketAddr,
+    pub query_time: SystemTime,
+    pub response_time: Option<SystemTime>,
+    pub payload: &'a [u8],
+}
+
+pub async fn start_dnstap() -> anyhow::Result<()> {
+    let config = match &PI_CONFIG.get().unwrap().dnstap {
+        Some(config) if config.socket_path.is_some() || config.file_path.is_some() => config,
+        _ => {
+            info!("dnstap output is not configured");
+            return Ok(());
+        }
+    };
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    DNSTAP_SENDER
+        .set(sender)
+        .map_err(|_| anyhow::anyhow!("Failed to set dnstap sender"))?;
+
+    write_frames(config, receiver).await
+}
+
+pub fn is_enabled() -> bool {
+    DNSTAP_SENDER.get().is_some()
+}
+
+pub fn log_message(msg: DnstapMessage<'_>) {
+    if let Some(sender) = DNSTAP_SENDER.get() {
+        let identity = PI_CONFIG
+            .get()
+            .and_then(|config| config.dnstap.as_ref())
+            .and_then(|config| config.identity.as_deref());
+        if sender.try_send(msg.encode(identity)).is_err() {
+            let dropped = DROPPED_FRAMES.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("dnstap output is falling behind, {dropped} frames dropped so far");
+            }
+        }
+    }
+}
+
+/// How many frames were dropped as the output couldn't keep up
+pub fn dropped_frames() -> u64 {
+    DROPPED_FRAMES.load(Ordering::Relaxed)
+}
+
+async fn write_frames(
+    config: &DnstapConfig,
+    mut receiver: Receiver<Vec<u8>>,
+) -> anyhow::Result<()> {
+    loop {
+        let mut output = match Output::open(config).await {
+            Ok(output) => output,
+            Err(e) => {
+                warn!("Failed to open dnstap output, retrying in {RETRY_DELAY:?}: {e}");
+                // Drop whatever comes in while the reader is away, instead of buffering it forever
+                let drain = async { while receiver.recv().await.is_some() {} };
+                if time::timeout(RETRY_DELAY, drain).await.is_ok() {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        loop {
+            match receiver.recv().await {
+                Some(frame) => {
+                    if let Err(e) = output.write(&frame).await {
+                        warn!("Failed to write dnstap frame: {e}");
+                        break;
+                    }
+                }
+                None => return output.close().await,
+            }
+        }
+    }
+}
+
+enum Output {
+    Socket(UnixStream),
+    File {
+        file: File,
+        path: String,
+        size: u64,
+        max_size: u64,
+        count: u32,
+    },
+}
+
+impl Output {
+    async fn open(config: &DnstapConfig) -> anyhow::Result<Self> {
+        if let Some(socket_path) = &config.socket_path {
+            info!("Connecting dnstap output to unix socket: {socket_path}");
+            let mut stream = UnixStream::connect(socket_path).await?;
+            open_bidirectional(&mut stream).await?;
+            Ok(Output::Socket(stream))
+        } else if let Some(path) = &config.file_path {
+            info!("Writing dnstap output to file: {path}");
+            // Frames captured before a restart or a write error are kept in the rotated files
+            let count = config.file_count.unwrap_or(5);
+            if Path::new(path).exists() {
+                rotate_files(path, count).await?;
+            }
+            let mut file = File::create(path).await?;
+            let start = control_frame(CONTROL_START, true);
+            file.write_all(&start).await?;
+            Ok(Output::File {
+                file,
+                path: path.clone(),
+                size: start.len() as u64,
+                max_size: config.file_size_mb.unwrap_or(10) * MB,
+                count,
+            })
+        } else {
+            Err(anyhow::anyhow!("Neither socket_path nor file_path is set"))
+        }
+    }
+
+    async fn write(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        let frame = data_frame(payload);
+        match self {
+            Output::Socket(stream) => stream.write_all(&frame).await?,
+            Output::File {
+                file,
+                path,
+                size,
+                max_size,
+                count,
+            } => {
+                if *size + frame.len() as u64 > *max_size {
+                    file.write_all(&control_frame(CONTROL_STOP, false)).await?;
+                    file.flush().await?;
+                    rotate_files(path, *count).await?;
+                    *file = File::create(&path).await?;
+                    let start = control_frame(CONTROL_START, true);
+                    file.write_all(&start).await?;
+                    *size = start.len() as u64;
+                }
+                file.write_all(&frame).await?;
+                *size += frame.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    async fn close(self) -> anyhow::Result<()> {
+        match self {
+            Output::Socket(mut stream) => {
+                stream
+                    .write_all(&control_frame(CONTROL_STOP, false))
+                    .await?;
+                read_control_frame(&mut stream, CONTROL_FINISH).await?;
+            }
+            Output::File { mut file, .. } => {
+                file.write_all(&control_frame(CONTROL_STOP, false)).await?;
+                file.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renames `path` to `path.1`, `path.1` to `path.2` and so on, dropping the oldest one
+async fn rotate_files(path: &str, count: u32) -> anyhow::Result<()> {
+    for idx in (1..count).rev() {
+        let from = if idx == 1 {
+            path.to_owned()
+        } else {
+            format!("{path}.{}", idx - 1)
+        };
+        if Path::new(&from).exists() {
+            fs::rename(&from, format!("{path}.{idx}")).await?;
+        }
+    }
+    Ok(())
+}
+
+// Frame Streams protocol, see https://farsightsec.github.io/fstrm/
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+
+async fn open_bidirectional<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> anyhow::Result<()> {
+    stream
+        .write_all(&control_frame(CONTROL_READY, true))
+        .await?;
+    read_control_frame(stream, CONTROL_ACCEPT).await?;
+    stream
+        .write_all(&control_frame(CONTROL_START, true))
+        .await?;
+    Ok(())
+}
+
+fn data_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.extend((payload.len() as u32).to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+fn control_frame(control_type: u32, with_content_type: bool) -> Vec<u8> {
+    let mut control = Vec::with_capacity(12 + CONTENT_TYPE.len());
+    control.extend(control_type.to_be_bytes());
+    if with_content_type {
+        control.extend(CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        control.extend((CONTENT_TYPE.len() as u32).to_be_bytes());
+        control.extend(CONTENT_TYPE);
+    }
+    let mut frame = Vec::with_capacity(control.len() + 8);
+    frame.extend(0u32.to_be_bytes());
+    frame.extend((control.len() as u32).to_be_bytes());
+    frame.extend(control);
+    frame
+}
+
+async fn read_control_frame<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    expected: u32,
+) -> anyhow::Result<()> {
+    if stream.read_u32().await? != 0 {
+        return Err(anyhow::anyhow!(
+            "Expected a control frame, got a data frame"
+        ));
+    }
+    let len = stream.read_u32().await? as usize;
+    let mut control = vec![0u8; len];
+    stream.read_exact(&mut control).await?;
+    match control.get(..4) {
+        Some(control_type) if control_type == expected.to_be_bytes() => Ok(()),
+        control_type => Err(anyhow::anyhow!(
+            "Expected control frame {expected}, got {control_type:?}"
+        )),
+    }
+}
+
+impl<'a> DnstapMessage<'a> {
+    /// Encodes the message as a protobuf `dnstap.Dnstap`
+    fn encode(&self, identity: Option<&str>) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(self.payload.len() + 64);
+        proto::varint_field(&mut msg, 1, self.message_type as u64);
+        let family = match self.response_addr.ip() {
+            IpAddr::V4(_) => 1,
+            IpAddr::V6(_) => 2,
+        };
+        proto::varint_field(&mut msg, 2, family);
+        proto::varint_field(&mut msg, 3, self.protocol as u64);
+        if let Some(query_addr) = self.query_addr {
+            proto::bytes_field(&mut msg, 4, &ip_bytes(query_addr.ip()));
+        }
+        proto::bytes_field(&mut msg, 5, &ip_bytes(self.response_addr.ip()));
+        if let Some(query_addr) = self.query_addr {
+            proto::varint_field(&mut msg, 6, query_addr.port() as u64);
+        }
+        proto::varint_field(&mut msg, 7, self.response_addr.port() as u64);
+
+        let (secs, nanos) = unix_time(self.query_time);
+        proto::varint_field(&mut msg, 8, secs);
+        proto::fixed32_field(&mut msg, 9, nanos);
+        match self.message_type {
+            MessageType::ClientQuery | MessageType::ForwarderQuery => {
+                proto::bytes_field(&mut msg, 10, self.payload);
+            }
+            MessageType::ClientResponse | MessageType::ForwarderResponse => {
+                let (secs, nanos) = unix_time(self.response_time.unwrap_or_else(SystemTime::now));
+                proto::varint_field(&mut msg, 12, secs);
+                proto::fixed32_field(&mut msg, 13, nanos);
+                proto::bytes_field(&mut msg, 14, self.payload);
+            }
+        }
+
+        let mut dnstap = Vec::with_capacity(msg.len() + 32);
+        if let Some(identity) = identity {
+            proto::bytes_field(&mut dnstap, 1, identity.as_bytes());
+        }
+        proto::bytes_field(&mut dnstap, 2, env!("CARGO_PKG_VERSION").as_bytes());
+        proto::bytes_field(&mut dnstap, 14, &msg);
+        // Dnstap.Type.MESSAGE
+        proto::varint_field(&mut dnstap, 15, 1);
+        dnstap
+    }
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn unix_time(time: SystemTime) -> (u64, u32) {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (duration.as_secs(), duration.subsec_nanos())
+}
+
+/// Just enough of protobuf wire format to write dnstap messages
+mod proto {
+    const VARINT: u32 = 0;
+    const LENGTH_DELIMITED: u32 = 2;
+    const FIXED32: u32 = 5;
+
+    pub fn varint(buf: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        buf.push(value as u8);
+    }
+
+    fn key(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+        varint(buf, ((field << 3) | wire_type) as u64);
+    }
+
+    pub fn varint_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+        key(buf, field, VARINT);
+        varint(buf, value);
+    }
+
+    pub fn fixed32_field(buf: &mut Vec<u8>, field: u32, value: u32) {
+        key(buf, field, FIXED32);
+        buf.extend(value.to_le_bytes());
+    }
+
+    pub fn bytes_field(buf: &mut Vec<u8>, field: u32, value: &[u8]) {
+        key(buf, field, LENGTH_DELIMITED);
+        varint(buf, value.len() as u64);
+        buf.extend(value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::SystemTime;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    /// Reads a protobuf message into field number => raw values, good enough to check dnstap
+    fn decode(mut buf: &[u8]) -> HashMap<u32, Vec<u8>> {
+        fn varint(buf: &mut &[u8]) -> u64 {
+            let (mut value, mut shift) = (0, 0);
+            loop {
+                let byte = buf[0];
+                *buf = &buf[1..];
+                value |= ((byte & 0x7f) as u64) << shift;
+                if byte < 0x80 {
+                    return value;
+                }
+                shift += 7;
+            }
+        }
+
+        let mut fields = HashMap::new();
+        while !buf.is_empty() {
+            let key = varint(&mut buf);
+            let value = match key & 0x7 {
+                0 => varint(&mut buf).to_le_bytes().to_vec(),
+                2 => {
+                    let len = varint(&mut buf) as usize;
+                    let (value, rest) = buf.split_at(len);
+                    buf = rest;
+                    value.to_vec()
+                }
+                5 => {
+                    let (value, rest) = buf.split_at(4);
+                    buf = rest;
+                    value.to_vec()
+                }
+                wire_type => panic!("Unexpected wire type {wire_type}"),
+            };
+            fields.insert((key >> 3) as u32, value);
+        }
+        fields
+    }
+
+    async fn read_frame(stream: &mut UnixStream) -> (bool, Vec<u8>) {
+        let len = stream.read_u32().await.unwrap();
+        let is_control = len == 0;
+        let len = if is_control {
+            stream.read_u32().await.unwrap()
+        } else {
+            len
+        };
+        let mut frame = vec![0u8; len as usize];
+        stream.read_exact(&mut frame).await.unwrap();
+        (is_control, frame)
+    }
+
+    #[tokio::test]
+    async fn test_socket_frames() {
+        let socket_path = std::env::temp_dir().join(format!("dnstap-{}.sock", std::process::id()));
+        std::fs::remove_file(&socket_path).ok();
+        let listener = UnixListener::bind(&socket_path).unwrap();
+
+        let config = DnstapConfig {
+            socket_path: Some(socket_path.display().to_string()),
+            ..Default::default()
+        };
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let writer = tokio::spawn(async move { write_frames(&config, receiver).await });
+
+        let (mut reader, _) = listener.accept().await.unwrap();
+        let (is_control, ready) = read_frame(&mut reader).await;
+        assert!(is_control);
+        assert_eq!(&ready[..4], &CONTROL_READY.to_be_bytes());
+        assert!(ready.ends_with(CONTENT_TYPE));
+        reader
+            .write_all(&control_frame(CONTROL_ACCEPT, true))
+            .await
+            .unwrap();
+        let (is_control, start) = read_frame(&mut reader).await;
+        assert!(is_control);
+        assert_eq!(&start[..4], &CONTROL_START.to_be_bytes());
+
+        let payload = [0xab; 29];
+        let msg = DnstapMessage {
+            message_type: MessageType::ClientResponse,
+            protocol: SocketProtocol::Udp,
+            query_addr: Some("192.168.1.10:53000".parse().unwrap()),
+            response_addr: "192.168.1.5:53".parse().unwrap(),
+            query_time: SystemTime::now(),
+            response_time: Some(SystemTime::now()),
+            payload: &payload,
+        };
+        sender.try_send(msg.encode(Some("pi"))).unwrap();
+
+        let (is_control, data) = read_frame(&mut reader).await;
+        assert!(!is_control);
+        let dnstap = decode(&data);
+        assert_eq!(dnstap[&1], b"pi");
+        assert_eq!(dnstap[&15][0], 1);
+        let message = decode(&dnstap[&14]);
+        assert_eq!(message[&1][0], MessageType::ClientResponse as u8);
+        assert_eq!(message[&2][0], 1);
+        assert_eq!(message[&3][0], SocketProtocol::Udp as u8);
+        assert_eq!(message[&4], [192, 168, 1, 10]);
+        assert_eq!(message[&5], [192, 168, 1, 5]);
+        assert_eq!(message[&14], payload);
+        assert!(message.contains_key(&8) && message.contains_key(&12));
+
+        drop(sender);
+        let (is_control, stop) = read_frame(&mut reader).await;
+        assert!(is_control);
+        assert_eq!(&stop[..4], &CONTROL_STOP.to_be_bytes());
+        reader
+            .write_all(&control_frame(CONTROL_FINISH, false))
+            .await
+            .unwrap();
+        writer.await.unwrap().unwrap();
+        std::fs::remove_file(&socket_path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_file_kept_on_reopen() {
+        let dir = std::env::temp_dir().join(format!("dnstap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dnstap.fstrm");
+        let config = DnstapConfig {
+            file_path: Some(path.display().to_string()),
+            ..Default::default()
+        };
+        std::fs::write(&path, b"captured").unwrap();
+        let output = Output::open(&config).await.unwrap();
+        output.close().await.unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("dnstap.fstrm.1")).unwrap(),
+            b"captured"
+        );
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_forwarder_frame() {
+        let payload = [0xcd; 17];
+        let msg = DnstapMessage {
+            message_type: MessageType::ForwarderQuery,
+            protocol: SocketProtocol::Udp,
+            query_addr: None,
+            response_addr: "127.0.0.1:5053".parse().unwrap(),
+            query_time: SystemTime::now(),
+            response_time: None,
+            payload: &payload,
+        };
+        let dnstap = decode(&msg.encode(None));
+        assert!(!dnstap.contains_key(&1));
+        let message = decode(&dnstap[&14]);
+        assert_eq!(message[&1][0], 7);
+        assert_eq!(message[&2][0], 1);
+        assert!(!message.contains_key(&4) && !message.contains_key(&6));
+        assert_eq!(message[&5], [127, 0, 0, 1]);
+        assert_eq!(message[&7][..2], 5053u16.to_le_bytes());
+        assert_eq!(message[&10], payload);
+
+        let msg = DnstapMessage {
+            message_type: MessageType::ForwarderResponse,
+            response_time: Some(SystemTime::now()),
+            ..msg
+        };
+        let message = decode(&decode(&msg.encode(None))[&14]);
+        assert_eq!(message[&1][0], 8);
+        assert_eq!(message[&14], payload);
+    }
+}
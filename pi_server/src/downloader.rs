@@ -1,29 +1,54 @@
-use futures_util::{stream, Stream, StreamExt};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use async_compression::tokio::bufread::{GzipDecoder, XzDecoder};
+use bytes::Bytes;
+use futures_util::stream::BoxStream;
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
 use log::*;
 use once_cell::sync::OnceCell;
-use reqwest::{header, Client};
+use reqwest::{header, Client, StatusCode, Url};
 use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::{fs, task, time};
+use tokio_util::io::{ReaderStream, StreamReader, SyncIoBridge};
 
-use trust_dns_proto::rr::Name;
-
-use domain::block_list::update_blocked_domains;
+use domain::block_list::{update_blocked_domains, ByteCount, Fetched, HttpStatusError, Validators};
+use domain::db::block_list::load_block_list;
 
 const USER_AGENT_VAL: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/15.5 Safari/605.1.15";
 
-static SENDER: OnceCell<UnboundedSender<()>> = OnceCell::new();
+/// How much of an archive is read ahead of the lines being parsed
+const ZIP_CHUNK_SIZE: usize = 64 * 1024;
+
+const LOCAL_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+static SENDER: OnceCell<UnboundedSender<Vec<String>>> = OnceCell::new();
+
+type Chunks = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+type Lines = BoxStream<'static, anyhow::Result<String>>;
 
 pub async fn start_download_loop() -> anyhow::Result<()> {
     let (sender, receiver) = mpsc::unbounded_channel();
     SENDER.set(sender).unwrap();
 
-    update_blocked_domains(receiver, download).await
+    update_blocked_domains(receiver, fetch).await
 }
 
-pub fn signal_blocked_domain_refresh() {
+/// Refreshes the sources right away, e.g. once they're added or changed.
+/// The domains of the removed ones are dropped.
+pub fn signal_blocked_domain_refresh(srcs: Vec<String>) {
     if let Some(sender) = SENDER.get() {
-        info!("Sending a refresh signal to bocked domains.");
+        info!("Sending a refresh signal to bocked domains of {srcs:?}");
         sender
-            .send(())
+            .send(srcs)
             .map_err(|_| warn!("Failed to send the signal"))
             .ok();
     } else {
@@ -44,72 +69,353 @@ fn client() -> Client {
         .expect("Failed to create http client")
 }
 
-async fn download(url: String) -> anyhow::Result<impl Stream<Item = String> + Unpin> {
-    let response = client().get(&url).send().await?;
+/// Reads the lines of a local source, or downloads them for any other one
+async fn fetch(src: String, validators: Validators) -> anyhow::Result<Fetched<Lines>> {
+    if is_local(&src) {
+        read_local(src, validators).await
+    } else {
+        download(src, validators).await
+    }
+}
+
+/// Downloads the lines of the list unless it's unchanged since the content `validators` came from,
+/// they are parsed by [`domain::list_format`]. A compressed list is decompressed as it streams in.
+async fn download(url: String, validators: Validators) -> anyhow::Result<Fetched<Lines>> {
+    let mut request = client().get(&url).header(header::ACCEPT_ENCODING, "gzip");
+    if let Some(etag) = &validators.etag {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+    }
+    let response = request.send().await?;
     let status = response.status();
+    if status == StatusCode::NOT_MODIFIED {
+        return Ok(Fetched::NotModified(Some(status.as_u16())));
+    }
     let cl = response.content_length();
     info!("Downloading {url}({status}) with content-length: {cl:?}");
     if !status.is_success() {
-        return Err(anyhow::anyhow!(
-            "Failed to download {url}, status code: {status}"
-        ));
-    }
-
-    Ok(stream::unfold(
-        (response, Vec::new(), 0),
-        |(mut response, mut buff, mut size)| async move {
-            let lines = if let Ok(Some(chunk)) = response.chunk().await {
-                buff.extend(chunk);
-                find_valid_domain(string_lines(&mut buff, false))
-            } else if !buff.is_empty() {
-                find_valid_domain(string_lines(&mut buff, true))
-            } else {
-                info!("Downloaded {size} domains!");
-                return None;
+        return Err(HttpStatusError(status.as_u16()))
+            .with_context(|| format!("Failed to download {url}"));
+    }
+
+    let header_value = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &header::HeaderValue| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let validators = Validators {
+        etag: header_value(header::ETAG),
+        last_modified: header_value(header::LAST_MODIFIED),
+    };
+    let content_type = header_value(header::CONTENT_TYPE);
+    check_content_type(content_type.as_deref())?;
+    let compression = Compression::detect(
+        response.url(),
+        header_value(header::CONTENT_ENCODING).as_deref(),
+        content_type.as_deref(),
+    )?;
+    if let Some(compression) = compression {
+        info!("Decompressing {url} as {compression:?}");
+    }
+    let bytes = ByteCount::default();
+    let body = response.bytes_stream().map_err(io::Error::other).boxed();
+    let body = count_bytes(body, &bytes);
+    Ok(Fetched::Modified(
+        vec![lines(decompress(body, compression))],
+        validators,
+        Some(status.as_u16()),
+        bytes,
+    ))
+}
+
+pub fn is_local(src: &str) -> bool {
+    src.starts_with("file://")
+}
+
+pub fn local_path(src: &str) -> anyhow::Result<PathBuf> {
+    Url::parse(src)?
+        .to_file_path()
+        .map_err(|_| anyhow::anyhow!("{src} isn't a local path"))
+}
+
+type LocalFile = (PathBuf, u64, Option<SystemTime>);
+
+/// The file with its size and modification time, or the files of the directory sorted by their
+/// paths. Hidden files are left out.
+async fn local_files(path: &Path) -> io::Result<Vec<LocalFile>> {
+    let meta = fs::metadata(path).await?;
+    if !meta.is_dir() {
+        return Ok(vec![(path.to_owned(), meta.len(), meta.modified().ok())]);
+    }
+    let mut files = Vec::new();
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        // Follows the symlinks
+        let meta = fs::metadata(&path).await?;
+        if meta.is_file() {
+            files.push((path, meta.len(), meta.modified().ok()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Stands in for the ETag of a local source, it changes with any of its files
+fn fingerprint(files: &[LocalFile]) -> String {
+    let mut hasher = DefaultHasher::new();
+    files.hash(&mut hasher);
+    format!("local:{:x}", hasher.finish())
+}
+
+/// Reads every file of the source as a document of its own, unless none of them has changed
+async fn read_local(src: String, validators: Validators) -> anyhow::Result<Fetched<Lines>> {
+    let path = local_path(&src)?;
+    let files = local_files(&path)
+        .await
+        .with_context(|| format!("Failed to list {}", path.display()))?;
+    let fingerprint = fingerprint(&files);
+    if validators.etag.as_deref() == Some(fingerprint.as_str()) {
+        return Ok(Fetched::NotModified(None));
+    }
+    info!("Reading {} files of {src}", files.len());
+
+    let bytes = ByteCount::default();
+    let documents = files
+        .into_iter()
+        .map(|(path, _, _)| {
+            let compression = Compression::from_path(&path.to_string_lossy());
+            // Opened once its lines are read, rather than all the files at once
+            let chunks = stream::once(fs::File::open(path))
+                .map_ok(ReaderStream::new)
+                .try_flatten()
+                .boxed();
+            lines(decompress(count_bytes(chunks, &bytes), compression))
+        })
+        .collect();
+    let validators = Validators {
+        etag: Some(fingerprint),
+        last_modified: None,
+    };
+    Ok(Fetched::Modified(documents, validators, None, bytes))
+}
+
+/// Refreshes the blocked domains once the files of a local source change
+pub async fn start_local_source_watcher() -> anyhow::Result<()> {
+    // The changes which have been signaled, the refresh might not have picked them up yet
+    let mut signaled = HashMap::new();
+    loop {
+        time::sleep(LOCAL_CHECK_INTERVAL).await;
+        let block_list = match load_block_list().await {
+            Ok(block_list) => block_list,
+            Err(e) => {
+                warn!("Failed to load the block list: {e}");
+                continue;
+            }
+        };
+        let mut changed = Vec::new();
+        // The ones which haven't been read yet are due for the refresh already
+        for bl in block_list.into_iter().filter(|bl| bl.domain_count >= 0) {
+            if !is_local(&bl.src) {
+                continue;
+            }
+            let files = match local_path(&bl.src) {
+                Ok(path) => local_files(&path).await.ok(),
+                Err(_) => None,
+            };
+            let Some(fingerprint) = files.as_deref().map(fingerprint) else {
+                continue;
+            };
+            if bl.etag.as_ref() != Some(&fingerprint) && signaled.get(&bl.src) != Some(&fingerprint)
+            {
+                info!("{} has changed", bl.src);
+                changed.push(bl.src.clone());
+                signaled.insert(bl.src, fingerprint);
+            }
+        }
+        if !changed.is_empty() {
+            signal_blocked_domain_refresh(changed);
+        }
+    }
+}
+
+/// Adds the size of the chunks to `count` as they're read
+fn count_bytes(chunks: Chunks, count: &ByteCount) -> Chunks {
+    let count = count.clone();
+    chunks
+        .inspect_ok(move |chunk| {
+            count.fetch_add(chunk.len(), Ordering::Relaxed);
+        })
+        .boxed()
+}
+
+/// Splits the chunks into lines, a failure ends the lines after being passed on
+fn lines(chunks: Chunks) -> Lines {
+    stream::unfold(
+        (Some(chunks), Vec::new(), 0),
+        |(chunks, mut buff, mut size)| async move {
+            let mut chunks = chunks?;
+            let (lines, chunks) = match chunks.next().await {
+                Some(Ok(chunk)) => {
+                    buff.extend(chunk);
+                    let lines = string_lines(&mut buff, false).map(Ok).collect::<Vec<_>>();
+                    (lines, Some(chunks))
+                }
+                Some(Err(e)) => (vec![Err(e.into())], None),
+                None => {
+                    let lines = string_lines(&mut buff, true).map(Ok).collect::<Vec<_>>();
+                    info!("Downloaded {} lines!", size + lines.len());
+                    (lines, None)
+                }
             };
             size += lines.len();
-            Some((stream::iter(lines), (response, buff, size)))
+            Some((stream::iter(lines), (chunks, buff, size)))
         },
     )
     .flatten()
-    .boxed())
+    .boxed()
 }
 
-fn find_valid_domain(lines: impl Iterator<Item = String>) -> Vec<String> {
-    // Can't use filter/map as it causes borrow issue
-    let mut result = Vec::with_capacity(lines.size_hint().0);
-    for line in lines {
-        let mut line = line.trim();
-        if line.is_empty() || line.starts_with('#') {
-            continue;
+/// Rejects the content which can't be a list, mostly the error pages served with a success status
+fn check_content_type(content_type: Option<&str>) -> anyhow::Result<()> {
+    let content_type = match content_type.and_then(|t| t.split(';').next()) {
+        Some(t) => t.trim().to_ascii_lowercase(),
+        None => return Ok(()),
+    };
+    let is_list = match content_type.as_str() {
+        "text/html" | "application/xhtml+xml" => false,
+        t if t.starts_with("text/") => true,
+        "application/octet-stream" | "binary/octet-stream" => true,
+        "application/gzip" | "application/x-gzip" | "application/x-xz" => true,
+        "application/zip" | "application/x-zip-compressed" => true,
+        _ => false,
+    };
+    if !is_list {
+        anyhow::bail!("Unexpected content type: {content_type}");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Xz,
+    Zip,
+}
+
+impl Compression {
+    /// Looks at the `Content-Encoding`, then the `Content-Type` and the extension of the file.
+    /// Only the first one found is used, as servers tend to set both for a `.gz` file.
+    fn detect(
+        url: &Url,
+        encoding: Option<&str>,
+        content_type: Option<&str>,
+    ) -> anyhow::Result<Option<Compression>> {
+        match encoding.map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("identity") => {}
+            Some("gzip" | "x-gzip") => return Ok(Some(Compression::Gzip)),
+            Some("xz") => return Ok(Some(Compression::Xz)),
+            Some(encoding) => anyhow::bail!("Unsupported content encoding: {encoding}"),
         }
-        if let Some(idx) = line.find('#') {
-            line = line[..idx].trim();
-            if line.is_empty() {
-                continue;
-            }
+
+        let content_type = content_type
+            .and_then(|t| t.split(';').next())
+            .map(|t| t.trim().to_ascii_lowercase());
+        let compression = match content_type.as_deref() {
+            Some("application/gzip" | "application/x-gzip") => Some(Compression::Gzip),
+            Some("application/x-xz") => Some(Compression::Xz),
+            Some("application/zip" | "application/x-zip-compressed") => Some(Compression::Zip),
+            Some(t) if t.starts_with("text/") => None,
+            // Mostly `application/octet-stream`, which tells nothing
+            _ => Compression::from_path(url.path()),
+        };
+        Ok(compression)
+    }
+
+    fn from_path(path: &str) -> Option<Compression> {
+        let path = path.to_ascii_lowercase();
+        [
+            (".gz", Compression::Gzip),
+            (".xz", Compression::Xz),
+            (".zip", Compression::Zip),
+        ]
+        .into_iter()
+        .find(|(ext, _)| path.ends_with(ext))
+        .map(|(_, compression)| compression)
+    }
+}
+
+fn decompress(body: Chunks, compression: Option<Compression>) -> Chunks {
+    match compression {
+        None => body,
+        Some(Compression::Gzip) => {
+            let mut decoder = GzipDecoder::new(StreamReader::new(body));
+            decoder.multiple_members(true);
+            ReaderStream::new(decoder).boxed()
         }
-        result.extend(
-            line.split_whitespace()
-                .map(str::trim)
-                .filter(|s| !s.is_empty())
-                .filter(|line| !["localhost", "0.0.0.0"].contains(line))
-                .filter_map(|name| Name::from_utf8(name).ok().map(|name| name.to_utf8())),
-        );
+        Some(Compression::Xz) => ReaderStream::new(XzDecoder::new(StreamReader::new(body))).boxed(),
+        Some(Compression::Zip) => unzip(body),
     }
-    result
+}
+
+/// Reads the files of the archive one after the other as it streams in,
+/// the archive can't be read from its central directory without having all of it
+fn unzip(body: Chunks) -> Chunks {
+    let (sender, receiver) = mpsc::channel(4);
+    let mut reader = SyncIoBridge::new(StreamReader::new(body));
+    task::spawn_blocking(move || {
+        let mut extract = || -> io::Result<()> {
+            while let Some(mut file) = zip::read::read_zipfile_from_stream(&mut reader)? {
+                if file.is_dir() {
+                    continue;
+                }
+                info!("Extracting {} from the archive", file.name());
+                let mut buff = vec![0; ZIP_CHUNK_SIZE];
+                loop {
+                    let len = file.read(&mut buff)?;
+                    if len == 0 {
+                        break;
+                    }
+                    if sender
+                        .blocking_send(Ok(Bytes::copy_from_slice(&buff[..len])))
+                        .is_err()
+                    {
+                        // Nobody is reading the lines anymore
+                        return Ok(());
+                    }
+                }
+                // The last line of a file shouldn't run into the first one of the next file
+                let _ = sender.blocking_send(Ok(Bytes::from_static(b"\n")));
+            }
+            Ok(())
+        };
+        if let Err(e) = extract() {
+            let _ = sender.blocking_send(Err(e));
+        }
+    });
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    })
+    .boxed()
 }
 
 fn string_lines(buff: &mut Vec<u8>, is_last: bool) -> impl Iterator<Item = String> + '_ {
     itertools::unfold(buff, move |buff| {
         if let Some(idx) = buff.iter().position(|&c| c == b'\n') {
             let range = ..(idx + 1);
-            let line = String::from_utf8_lossy(&buff[range]).trim().to_lowercase();
+            let line = String::from_utf8_lossy(&buff[range]).trim().to_owned();
             buff.drain(range);
             return Some(line);
         }
         if is_last && !buff.is_empty() {
-            let line = String::from_utf8_lossy(buff).trim().to_lowercase();
+            let line = String::from_utf8_lossy(buff).trim().to_owned();
             buff.clear();
             return Some(line);
         }
@@ -119,9 +425,23 @@ fn string_lines(buff: &mut Vec<u8>, is_last: bool) -> impl Iterator<Item = Strin
 
 #[cfg(test)]
 mod test {
-    use crate::downloader::find_valid_domain;
+    use std::io::{Cursor, Write};
+    use std::sync::atomic::Ordering;
 
-    use super::string_lines;
+    use bytes::Bytes;
+    use domain::block_list::{Fetched, HttpStatusError, Validators};
+    use domain::list_format::{parse_list, ListFormat};
+    use futures_util::{stream, StreamExt, TryStreamExt};
+    use reqwest::Url;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::{
+        check_content_type, download, is_local, lines, read_local, string_lines, unzip,
+        Compression, Lines,
+    };
 
     #[test]
     fn test_r() {
@@ -135,8 +455,229 @@ mod test {
     #[test]
     fn test_name() {
         let mut arr = include_bytes!("../block_list.txt").to_vec();
-        let lines = find_valid_domain(string_lines(&mut arr, true));
-        println!("Lines: {}", lines.len());
-        println!("{} / {}", lines[0], lines.last().unwrap());
+        let lines = string_lines(&mut arr, true).collect::<Vec<_>>();
+        let (format, parsed) = parse_list(None, &lines);
+        assert_eq!(format, ListFormat::Hosts);
+        println!("Lines: {}, domains: {}", lines.len(), parsed.domains.len());
+        println!("Invalid: {} {:?}", parsed.invalid, parsed.invalid_samples);
+    }
+
+    #[test]
+    fn test_unicode_lines() {
+        let mut arr =
+            "0.0.0.0 Bücher.Example\r\n0.0.0.0 ADS.example.com.\n0.0.0.0 xn--e1afmkfd.xn--p1ai"
+                .as_bytes()
+                .to_vec();
+        let lines = string_lines(&mut arr, true).collect::<Vec<_>>();
+        let (_, parsed) = parse_list(Some(ListFormat::Hosts), &lines);
+        let mut domains = parsed.domains.into_iter().collect::<Vec<_>>();
+        domains.sort();
+        assert_eq!(
+            domains,
+            [
+                "ads.example.com",
+                "xn--bcher-kva.example",
+                "xn--e1afmkfd.xn--p1ai"
+            ]
+        );
+    }
+
+    /// Serves the list with an ETag and a Last-Modified, and a 304 to the requests matching either
+    async fn stand_in_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0; 4096];
+                let len = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..len]).to_lowercase();
+                let response = if request.starts_with("get /missing") {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_owned()
+                } else if request.contains("if-none-match: \"v1\"")
+                    || request.contains("if-modified-since: wed, 21 oct 2015 07:28:00 gmt")
+                {
+                    "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_owned()
+                } else {
+                    let body = "0.0.0.0 ads.example.com\ntracker.net # comment\n";
+                    format!(
+                        "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\n\
+                        Last-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\n\
+                        Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                };
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{addr}/list.txt")
+    }
+
+    #[tokio::test]
+    async fn test_conditional_download() {
+        let url = stand_in_server().await;
+        let fetched = download(url.clone(), Validators::default()).await.unwrap();
+        let (documents, validators, bytes) = read_documents(fetched).await;
+        assert_eq!(
+            documents,
+            vec![vec!["0.0.0.0 ads.example.com", "tracker.net # comment"]]
+        );
+        assert_eq!(bytes, 46);
+        assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+
+        for validators in [
+            validators.clone(),
+            Validators {
+                etag: None,
+                ..validators
+            },
+        ] {
+            let fetched = download(url.clone(), validators).await.unwrap();
+            assert!(matches!(fetched, Fetched::NotModified(Some(304))));
+        }
+
+        let missing = url.replace("list.txt", "missing");
+        let error = download(missing, Validators::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error.downcast_ref(), Some(HttpStatusError(404))));
+    }
+
+    async fn read_documents(fetched: Fetched<Lines>) -> (Vec<Vec<String>>, Validators, usize) {
+        match fetched {
+            Fetched::Modified(documents, validators, _, bytes) => {
+                let mut read = Vec::new();
+                for lines in documents {
+                    read.push(lines.try_collect::<Vec<_>>().await.unwrap());
+                }
+                (read, validators, bytes.load(Ordering::Relaxed))
+            }
+            Fetched::NotModified(_) => panic!("Nothing has been read yet"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_source() {
+        let dir = std::env::temp_dir().join(format!("local-source-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("b.txt"), "||tracker.net^\n").unwrap();
+        std::fs::write(dir.join("a.txt"), "0.0.0.0 ads.example.com\n").unwrap();
+        std::fs::write(dir.join(".hidden"), "hidden.example.com\n").unwrap();
+        let src = Url::from_directory_path(&dir).unwrap().to_string();
+        assert!(is_local(&src));
+
+        let fetched = read_local(src.clone(), Validators::default())
+            .await
+            .unwrap();
+        let (documents, validators, bytes) = read_documents(fetched).await;
+        assert_eq!(
+            documents,
+            vec![vec!["0.0.0.0 ads.example.com"], vec!["||tracker.net^"]]
+        );
+        assert_eq!(bytes, 24 + 15);
+        let fetched = read_local(src.clone(), validators.clone()).await.unwrap();
+        assert!(matches!(fetched, Fetched::NotModified(None)));
+
+        std::fs::write(dir.join("c.txt"), "example.org\n").unwrap();
+        let fetched = read_local(src.clone(), validators).await.unwrap();
+        assert_eq!(read_documents(fetched).await.0.len(), 3);
+
+        let file = Url::from_file_path(dir.join("a.txt")).unwrap().to_string();
+        let fetched = read_local(file, Validators::default()).await.unwrap();
+        assert_eq!(read_documents(fetched).await.0.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(read_local(src, Validators::default()).await.is_err());
+    }
+
+    #[test]
+    fn test_compression() {
+        let detect = |url: &str, encoding, content_type| {
+            Compression::detect(&Url::parse(url).unwrap(), encoding, content_type).unwrap()
+        };
+        let gz = "https://example.com/hosts.gz";
+        assert_eq!(detect(gz, None, None), Some(Compression::Gzip));
+        assert_eq!(
+            detect("https://example.com/HOSTS.XZ", None, None),
+            Some(Compression::Xz)
+        );
+        assert_eq!(detect("https://example.com/hosts.txt", None, None), None);
+        assert_eq!(
+            detect(
+                "https://example.com/hosts",
+                None,
+                Some("application/zip; charset=binary")
+            ),
+            Some(Compression::Zip)
+        );
+        assert_eq!(
+            detect(
+                "https://example.com/hosts",
+                Some("gzip"),
+                Some("text/plain")
+            ),
+            Some(Compression::Gzip)
+        );
+        // A `.gz` served with the encoding is decompressed once
+        assert_eq!(
+            detect(gz, Some("gzip"), Some("application/gzip")),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(detect(gz, Some("identity"), Some("text/plain")), None);
+        assert!(Compression::detect(&Url::parse(gz).unwrap(), Some("br"), None).is_err());
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert!(check_content_type(None).is_ok());
+        assert!(check_content_type(Some("text/plain; charset=utf-8")).is_ok());
+        assert!(check_content_type(Some("application/octet-stream")).is_ok());
+        assert!(check_content_type(Some("application/gzip")).is_ok());
+        assert!(check_content_type(Some("Text/HTML; charset=utf-8")).is_err());
+        assert!(check_content_type(Some("application/json")).is_err());
+        assert!(check_content_type(Some("image/png")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unzip() {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .add_directory("lists", FileOptions::default())
+            .unwrap();
+        writer
+            .start_file("lists/a.txt", FileOptions::default())
+            .unwrap();
+        writer
+            .write_all(b"0.0.0.0 ads.example.com\n0.0.0.0 a.com")
+            .unwrap();
+        writer
+            .start_file("lists/b.txt", FileOptions::default())
+            .unwrap();
+        writer.write_all(b"tracker.net\n").unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        // Small chunks, so the entries span over several of them
+        let chunks = archive
+            .chunks(7)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        let extracted = lines(unzip(stream::iter(chunks).boxed()))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            extracted,
+            vec![
+                "0.0.0.0 ads.example.com",
+                "0.0.0.0 a.com",
+                "tracker.net",
+                ""
+            ]
+        );
+
+        let truncated = stream::iter(vec![Ok(Bytes::copy_from_slice(&archive[..40]))]).boxed();
+        let result = lines(unzip(truncated)).try_collect::<Vec<_>>().await;
+        assert!(result.is_err());
     }
 }
@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::error::ProtoResult;
+use trust_dns_proto::op::Message;
+use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+
+use crate::dns::Transport;
+
+/// UDP payload size we advertise, as recommended by DNS flag day 2020
+const SERVER_UDP_PAYLOAD: u16 = 1232;
+/// Block size for padding responses, RFC 8467
+const PADDING_BLOCK: usize = 468;
+const ECS_IPV4_PREFIX: u8 = 24;
+const ECS_IPV6_PREFIX: u8 = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientSubnet {
+    Forward,
+    Strip,
+    /// Truncate the address to /24 (IPv4) or /56 (IPv6)
+    Anonymize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EdnsConfig {
+    pub client_subnet: ClientSubnet,
+    pub strip_dnssec_ok: bool,
+    pub honor_udp_size: bool,
+    pub padding: bool,
+}
+
+impl Default for EdnsConfig {
+    fn default() -> Self {
+        EdnsConfig {
+            client_subnet: ClientSubnet::Forward,
+            strip_dnssec_ok: false,
+            honor_udp_size: true,
+            padding: true,
+        }
+    }
+}
+
+impl EdnsConfig {
+    /// Applies the policy to a client's request before it's forwarded upstream
+    pub fn prepare_request(&self, request: &mut Message) {
+        if request.edns().is_none() {
+            return;
+        }
+        let edns = request.edns_mut();
+        if self.strip_dnssec_ok {
+            edns.set_dnssec_ok(false);
+        }
+        // Padding is hop-by-hop, it makes no sense to forward the client's
+        edns.options_mut().remove(EdnsCode::Padding);
+
+        let subnet = match edns.option(EdnsCode::Subnet) {
+            Some(EdnsOption::Unknown(_, data)) => data.clone(),
+            _ => return,
+        };
+        match self.client_subnet {
+            ClientSubnet::Forward => {}
+            ClientSubnet::Strip => edns.options_mut().remove(EdnsCode::Subnet),
+            ClientSubnet::Anonymize => match anonymize_subnet(&subnet) {
+                Some(subnet) => edns.options_mut().insert(subnet),
+                None => edns.options_mut().remove(EdnsCode::Subnet),
+            },
+        }
+    }
+
+    /// Serializes the response for the client, honoring its EDNS parameters
+    pub fn to_wire(
+        &self,
+        request: &Message,
+        response: &mut Message,
+        transport: Transport,
+    ) -> ProtoResult<Vec<u8>> {
+        if let Some(req_edns) = request.edns() {
+            let dnssec_ok = req_edns.dnssec_ok() && !self.strip_dnssec_ok;
+            let edns = response.edns_mut();
+            edns.set_max_payload(SERVER_UDP_PAYLOAD);
+            edns.set_dnssec_ok(dnssec_ok);
+            edns.options_mut().remove(EdnsCode::Padding);
+        }
+        let mut bytes = response.to_vec()?;
+
+        if transport == Transport::Udp && self.honor_udp_size {
+            let max_size = request.max_payload() as usize;
+            if bytes.len() > max_size {
+                log::info!(
+                    "Response size {} is more than client's limit {max_size}, truncating",
+                    bytes.len()
+                );
+                response.take_answers();
+                response.take_name_servers();
+                response.take_additionals();
+                response.set_truncated(true);
+                bytes = response.to_vec()?;
+            }
+        }
+
+        if transport.is_encrypted() && self.padding && request.edns().is_some() {
+            // The option itself takes 4 bytes for code and length
+            let padding = (PADDING_BLOCK - (bytes.len() + 4) % PADDING_BLOCK) % PADDING_BLOCK;
+            response
+                .edns_mut()
+                .options_mut()
+                .insert(EdnsOption::Unknown(
+                    EdnsCode::Padding.into(),
+                    vec![0; padding],
+                ));
+            bytes = response.to_vec()?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// ECS option is FAMILY(2) SOURCE PREFIX(1) SCOPE PREFIX(1) ADDRESS(source prefix bits)
+fn anonymize_subnet(data: &[u8]) -> Option<EdnsOption> {
+    if data.len() < 4 {
+        return None;
+    }
+    let family = u16::from_be_bytes([data[0], data[1]]);
+    let max_prefix = match family {
+        1 => ECS_IPV4_PREFIX,
+        2 => ECS_IPV6_PREFIX,
+        _ => return None,
+    };
+    let prefix = data[2].min(max_prefix);
+    let addr_len = prefix.div_ceil(8) as usize;
+    let mut address = data[4..].iter().copied().take(addr_len).collect::<Vec<_>>();
+    address.resize(addr_len, 0);
+    if prefix % 8 != 0 {
+        if let Some(last) = address.last_mut() {
+            *last &= 0xffu8 << (8 - prefix % 8);
+        }
+    }
+
+    let mut subnet = Vec::with_capacity(4 + addr_len);
+    subnet.extend(family.to_be_bytes());
+    subnet.push(prefix);
+    subnet.push(0);
+    subnet.extend(address);
+    Some(EdnsOption::Unknown(EdnsCode::Subnet.into(), subnet))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{Edns, Message, Query};
+    use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+    use super::{anonymize_subnet, EdnsConfig, PADDING_BLOCK};
+    use crate::dns::Transport;
+
+    fn request(max_payload: Option<u16>) -> Message {
+        let mut request = Message::new();
+        let name = Name::from_str("example.com.").unwrap();
+        request.add_query(Query::query(name, RecordType::A));
+        if let Some(max_payload) = max_payload {
+            let mut edns = Edns::new();
+            edns.set_max_payload(max_payload);
+            request.set_edns(edns);
+        }
+        request
+    }
+
+    fn response(answers: u8) -> Message {
+        let mut response = Message::new();
+        let name = Name::from_str("example.com.").unwrap();
+        response.add_query(Query::query(name.clone(), RecordType::A));
+        for i in 0..answers {
+            let ip = Ipv4Addr::new(10, 0, 0, i);
+            response.add_answer(Record::from_rdata(name.clone(), 300, RData::A(ip)));
+        }
+        response
+    }
+
+    fn is_truncated(bytes: &[u8]) -> bool {
+        bytes[2] & 0x02 != 0
+    }
+
+    fn padding(response: &Message) -> Option<usize> {
+        match response.edns()?.option(EdnsCode::Padding)? {
+            EdnsOption::Unknown(_, data) => Some(data.len()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_truncation() {
+        let config = EdnsConfig::default();
+        // 16 bytes for each A record with a compressed name, well over 512 bytes
+        let req = request(Some(512));
+        let mut res = response(40);
+        let bytes = config.to_wire(&req, &mut res, Transport::Udp).unwrap();
+        assert!(bytes.len() <= 512);
+        assert!(is_truncated(&bytes));
+        assert!(res.answers().is_empty());
+
+        // Fits the client's limit
+        let req = request(Some(1232));
+        let mut res = response(40);
+        let bytes = config.to_wire(&req, &mut res, Transport::Udp).unwrap();
+        assert!(!is_truncated(&bytes));
+        assert_eq!(res.answers().len(), 40);
+
+        // 512 bytes without EDNS
+        let req = request(None);
+        let mut res = response(40);
+        let bytes = config.to_wire(&req, &mut res, Transport::Udp).unwrap();
+        assert!(is_truncated(&bytes));
+
+        // No limit over TCP
+        let req = request(Some(512));
+        let mut res = response(40);
+        let bytes = config.to_wire(&req, &mut res, Transport::Tcp).unwrap();
+        assert!(bytes.len() > 512);
+        assert!(!is_truncated(&bytes));
+
+        let config = EdnsConfig {
+            honor_udp_size: false,
+            ..Default::default()
+        };
+        let mut res = response(40);
+        let bytes = config.to_wire(&req, &mut res, Transport::Udp).unwrap();
+        assert!(!is_truncated(&bytes));
+    }
+
+    #[test]
+    fn test_padding() {
+        let config = EdnsConfig::default();
+        for answers in [0, 1, 20, 40] {
+            for transport in [Transport::Dot, Transport::Doh] {
+                let mut res = response(answers);
+                let bytes = config
+                    .to_wire(&request(Some(1232)), &mut res, transport)
+                    .unwrap();
+                assert_eq!(bytes.len() % PADDING_BLOCK, 0, "{answers} {transport:?}");
+                assert!(padding(&res).unwrap() < PADDING_BLOCK);
+            }
+        }
+
+        // Only on the encrypted transports, when the client uses EDNS
+        let mut res = response(1);
+        let bytes = config
+            .to_wire(&request(Some(1232)), &mut res, Transport::Udp)
+            .unwrap();
+        assert_ne!(bytes.len() % PADDING_BLOCK, 0);
+        assert_eq!(padding(&res), None);
+
+        let mut res = response(1);
+        config
+            .to_wire(&request(None), &mut res, Transport::Dot)
+            .unwrap();
+        assert_eq!(padding(&res), None);
+
+        let config = EdnsConfig {
+            padding: false,
+            ..Default::default()
+        };
+        let mut res = response(1);
+        config
+            .to_wire(&request(Some(1232)), &mut res, Transport::Dot)
+            .unwrap();
+        assert_eq!(padding(&res), None);
+    }
+
+    #[test]
+    fn test_anonymize() {
+        let ecs = anonymize_subnet(&[0, 1, 32, 0, 192, 168, 10, 25]);
+        assert_eq!(
+            ecs,
+            Some(EdnsOption::Unknown(8, vec![0, 1, 24, 0, 192, 168, 10]))
+        );
+
+        let ecs = anonymize_subnet(&[0, 1, 20, 0, 10, 20, 0xff]);
+        assert_eq!(
+            ecs,
+            Some(EdnsOption::Unknown(8, vec![0, 1, 20, 0, 10, 20, 0xf0]))
+        );
+
+        let mut v6 = vec![0, 2, 64, 0];
+        v6.extend([0x20, 0x01, 0x0d, 0xb8, 0x12, 0x34, 0x56, 0x78]);
+        let ecs = anonymize_subnet(&v6);
+        let expected = vec![0, 2, 56, 0, 0x20, 0x01, 0x0d, 0xb8, 0x12, 0x34, 0x56];
+        assert_eq!(ecs, Some(EdnsOption::Unknown(8, expected)));
+
+        assert_eq!(anonymize_subnet(&[0, 3, 8, 0, 1]), None);
+    }
+}
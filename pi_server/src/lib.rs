@@ -6,15 +6,26 @@ use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use tokio::fs;
 
+use crate::dnstap::DnstapConfig;
+use crate::edns::EdnsConfig;
+use crate::rebind::RebindConfig;
+use crate::tcp::DotConfig;
+use crate::web::BlockPageConfig;
+
 pub use timer::Timer;
 
 pub mod cloudflared;
 pub mod db;
 pub mod dns;
+pub mod dnstap;
 pub mod downloader;
+pub mod edns;
+pub mod rebind;
 pub mod sysinfo;
+pub mod tcp;
 mod timer;
 pub mod web;
+pub mod zones;
 
 pub static PI_CONFIG: OnceCell<PiConfig> = OnceCell::new();
 
@@ -30,6 +41,13 @@ pub struct PiConfig {
     pub log_config: String,
     pub block_list: String,
     pub dht22_pin: Option<u32>,
+    pub dnstap: Option<DnstapConfig>,
+    pub zone_files: Option<Vec<String>>,
+    pub rebind_protection: Option<RebindConfig>,
+    #[serde(default)]
+    pub edns: EdnsConfig,
+    pub block_page: Option<BlockPageConfig>,
+    pub dot: Option<DotConfig>,
 }
 
 impl PiConfig {
@@ -45,6 +63,12 @@ impl PiConfig {
             log_config: "log4rs.yml".into(),
             block_list: "block_list.txt".into(),
             dht22_pin: None,
+            dnstap: None,
+            zone_files: None,
+            rebind_protection: None,
+            edns: EdnsConfig::default(),
+            block_page: None,
+            dot: None,
         }
     }
 
@@ -4,9 +4,12 @@ use tikv_jemallocator::Jemalloc;
 use pi_server::cloudflared::init_cloudflare;
 use pi_server::db::init_db;
 use pi_server::dns::start_dns_server;
-use pi_server::downloader::start_download_loop;
+use pi_server::dnstap::start_dnstap;
+use pi_server::downloader::{start_download_loop, start_local_source_watcher};
 use pi_server::sysinfo::load_sys_info;
-use pi_server::web::{start_web_server, ws_sender};
+use pi_server::tcp::{start_dot_server, start_tcp_server};
+use pi_server::web::{start_block_page_server, start_filter_expiry, start_web_server, ws_sender};
+use pi_server::zones::start_zone_watcher;
 use pi_server::{PiConfig, PI_CONFIG};
 
 #[cfg(not(target_os = "windows"))]
@@ -26,10 +29,18 @@ async fn main() -> anyhow::Result<()> {
     if let Err(e) = tokio::try_join!(
         cloudflared.start_daemon(),
         start_dns_server(),
+        start_tcp_server(),
+        start_dot_server(),
+        start_dnstap(),
+        start_zone_watcher(),
         start_web_server(),
+        start_block_page_server(),
         load_sys_info(),
         ws_sender(),
         start_download_loop(),
+        start_local_source_watcher(),
+        domain::start_hits_flusher(),
+        start_filter_expiry(),
     ) {
         println!("Something went wrong: {e:?}");
         log::error!("Failed to start the app: {e:?}");
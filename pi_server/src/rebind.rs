@@ -0,0 +1,240 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::op::Message;
+use trust_dns_proto::rr::{RData, Record};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RebindAction {
+    /// Remove the private addresses from the answer, keep the rest
+    Strip,
+    /// Reply with the usual blocked response instead
+    Block,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RebindConfig {
+    pub action: RebindAction,
+    /// Domains (and their sub-domains) which are allowed to resolve to private addresses
+    pub local_domains: Vec<String>,
+}
+
+impl RebindConfig {
+    /// Looks for private A/AAAA records in an upstream answer, returns the reason if any is found.
+    /// With [`RebindAction::Strip`] those records are removed from the answer as well.
+    /// Only the query name decides whether the answer is local, the owner names of the records
+    /// come from the upstream, e.g. a CNAME chain can lead anywhere.
+    pub fn check(&self, response: &mut Message) -> Option<String> {
+        if response
+            .queries()
+            .iter()
+            .any(|q| self.is_local(&q.name().to_lowercase().to_string()))
+        {
+            return None;
+        }
+
+        let private = response
+            .answers()
+            .iter()
+            .filter_map(private_ip)
+            .unique()
+            .collect::<Vec<_>>();
+        if private.is_empty() {
+            return None;
+        }
+
+        let name = response
+            .queries()
+            .first()
+            .map(|q| q.name().to_string())
+            .unwrap_or_default();
+        let private = private.into_iter().join(", ");
+        match self.action {
+            RebindAction::Strip => {
+                let answers = response
+                    .take_answers()
+                    .into_iter()
+                    .filter(|record| private_ip(record).is_none())
+                    .collect();
+                response.insert_answers(answers);
+                Some(format!(
+                    "Rebinding protection: stripped {private} from {name}"
+                ))
+            }
+            RebindAction::Block => Some(format!(
+                "Rebinding protection: {name} resolved to {private}"
+            )),
+        }
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        self.local_domains.iter().any(|domain| {
+            let domain = domain.trim().trim_end_matches('.');
+            name == domain || name.ends_with(&format!(".{domain}"))
+        })
+    }
+}
+
+fn private_ip(record: &Record) -> Option<IpAddr> {
+    let ip = match record.data()? {
+        RData::A(ip) => IpAddr::V4(*ip),
+        RData::AAAA(ip) => IpAddr::V6(*ip),
+        _ => return None,
+    };
+    is_private(ip).then_some(ip)
+}
+
+/// RFC 1918, loopback and link-local ranges, plus their IPv6 counterparts
+pub fn is_private(ip: IpAddr) -> bool {
+    fn is_private_v4(ip: Ipv4Addr) -> bool {
+        ip.is_private() || ip.is_loopback() || ip.is_link_local()
+    }
+
+    fn is_private_v6(ip: Ipv6Addr) -> bool {
+        let first = ip.segments()[0];
+        ip.is_loopback()
+            || (first & 0xfe00) == 0xfc00 // Unique local, fc00::/7
+            || (first & 0xffc0) == 0xfe80 // Link local, fe80::/10
+            || ip.to_ipv4_mapped().map(is_private_v4).unwrap_or(false)
+    }
+
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => is_private_v6(ip),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use trust_dns_proto::op::{Message, Query};
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+    use super::{is_private, RebindAction, RebindConfig};
+
+    fn config(action: RebindAction) -> RebindConfig {
+        RebindConfig {
+            action,
+            local_domains: vec!["home.arpa".into(), "plex.direct.".into()],
+        }
+    }
+
+    fn response(name: &str, answers: &[(&str, RData)]) -> Message {
+        let mut response = Message::new();
+        response.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        for (owner, rdata) in answers {
+            let owner = Name::from_str(owner).unwrap();
+            response.add_answer(Record::from_rdata(owner, 300, rdata.clone()));
+        }
+        response
+    }
+
+    fn a(ip: &str) -> RData {
+        RData::A(ip.parse().unwrap())
+    }
+
+    fn cname(name: &str) -> RData {
+        RData::CNAME(Name::from_str(name).unwrap())
+    }
+
+    #[test]
+    fn test_private() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.10.10",
+            "::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(is_private(ip.parse::<IpAddr>().unwrap()), "{ip}");
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "2606:4700::1111", "::ffff:1.1.1.1"] {
+            assert!(!is_private(ip.parse::<IpAddr>().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_local() {
+        let config = config(RebindAction::Strip);
+        assert!(config.is_local("nas.home.arpa."));
+        assert!(config.is_local("home.arpa"));
+        assert!(config.is_local("x.plex.direct."));
+        assert!(!config.is_local("evilhome.arpa."));
+        assert!(!config.is_local("example.com."));
+    }
+
+    #[test]
+    fn test_strip() {
+        let config = config(RebindAction::Strip);
+        let mut res = response(
+            "example.com.",
+            &[
+                ("example.com.", a("93.184.216.34")),
+                ("example.com.", a("10.0.0.1")),
+            ],
+        );
+        let reason = config.check(&mut res).unwrap();
+        assert!(reason.contains("10.0.0.1"), "{reason}");
+        assert_eq!(res.answers().len(), 1);
+        assert_eq!(res.answers()[0].data(), Some(&a("93.184.216.34")));
+
+        let mut res = response("example.com.", &[("example.com.", a("93.184.216.34"))]);
+        assert_eq!(config.check(&mut res), None);
+        assert_eq!(res.answers().len(), 1);
+
+        let mut res = response("nas.home.arpa.", &[("nas.home.arpa.", a("192.168.1.20"))]);
+        assert_eq!(config.check(&mut res), None);
+        assert_eq!(res.answers().len(), 1);
+
+        // The owner name of the A record is local, but the query isn't
+        let mut res = response(
+            "evil.com.",
+            &[
+                ("evil.com.", cname("192-168-1-1.x.plex.direct.")),
+                ("192-168-1-1.x.plex.direct.", a("192.168.1.1")),
+            ],
+        );
+        let reason = config.check(&mut res).unwrap();
+        assert!(reason.contains("192.168.1.1"), "{reason}");
+        assert_eq!(res.answers().len(), 1);
+        assert_eq!(
+            res.answers()[0].data(),
+            Some(&cname("192-168-1-1.x.plex.direct."))
+        );
+    }
+
+    #[test]
+    fn test_block() {
+        let config = config(RebindAction::Block);
+        let mut res = response(
+            "evil.com.",
+            &[
+                ("evil.com.", cname("192-168-1-1.x.plex.direct.")),
+                ("192-168-1-1.x.plex.direct.", a("192.168.1.1")),
+            ],
+        );
+        let reason = config.check(&mut res).unwrap();
+        assert!(reason.contains("evil.com."), "{reason}");
+        // The caller replaces the whole answer, it's left as it is
+        assert_eq!(res.answers().len(), 2);
+
+        let mut res = response(
+            "192-168-1-1.x.plex.direct.",
+            &[("192-168-1-1.x.plex.direct.", a("192.168.1.1"))],
+        );
+        assert_eq!(config.check(&mut res), None);
+
+        let ip = "fd00::1".parse().unwrap();
+        let mut res = response("example.com.", &[("example.com.", RData::AAAA(ip))]);
+        assert!(config.check(&mut res).unwrap().contains("fd00::1"));
+    }
+}
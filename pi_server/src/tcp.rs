@@ -0,0 +1,280 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use log::*;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::dns::{process_message, Transport};
+use crate::PI_CONFIG;
+
+/// Connections without any query for this long are closed, RFC 7766 recommends a few seconds
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Open TCP and TLS connections, the next ones wait in the listen backlog
+const MAX_CONNECTIONS: usize = 256;
+/// Queries of a connection being processed at once, reading stops until one of them is answered
+const MAX_IN_FLIGHT: usize = 16;
+
+static CONNECTIONS: Lazy<Arc<Semaphore>> = Lazy::new(|| Arc::new(Semaphore::new(MAX_CONNECTIONS)));
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DotConfig {
+    /// PEM file with the certificate chain, leaf certificate first
+    pub cert_path: String,
+    /// PEM file with the private key (PKCS#8, RSA or EC)
+    pub key_path: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+fn default_port() -> u16 {
+    853
+}
+
+/// Plain DNS over TCP on the same port as UDP, clients retry here when a response is truncated
+pub async fn start_tcp_server() -> anyhow::Result<()> {
+    let dns_port = PI_CONFIG.get().unwrap().dns_port;
+    let listener = TcpListener::bind((IpAddr::from([0, 0, 0, 0]), dns_port))
+        .await
+        .with_context(|| format!("Failed to bind tcp port: {dns_port}"))?;
+    let server_addr = listener.local_addr()?;
+    info!("Listening for DNS over TCP at: {server_addr}");
+    loop {
+        let permit = connection_permit().await;
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                tokio::spawn(async move {
+                    serve_connection(stream, addr, server_addr, Transport::Tcp).await;
+                    drop(permit);
+                });
+            }
+            Err(e) => warn!("Failed to accept tcp connection: {e}"),
+        }
+    }
+}
+
+pub async fn start_dot_server() -> anyhow::Result<()> {
+    let config = match &PI_CONFIG.get().unwrap().dot {
+        Some(config) => config,
+        None => {
+            info!("DNS over TLS is disabled");
+            return Ok(());
+        }
+    };
+    let modified = cert_modified(config).await;
+    let acceptor = load_acceptor(config)
+        .await
+        .with_context(|| format!("Failed to load certificate {}", config.cert_path))?;
+    let acceptor = Arc::new(RwLock::new(acceptor));
+    tokio::spawn(watch_certificate(config, acceptor.clone(), modified));
+
+    let listener = TcpListener::bind((IpAddr::from([0, 0, 0, 0]), config.port))
+        .await
+        .with_context(|| format!("Failed to bind tls port: {}", config.port))?;
+    let server_addr = listener.local_addr()?;
+    info!("Listening for DNS over TLS at: {server_addr}");
+    loop {
+        let permit = connection_permit().await;
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept tls connection: {e}");
+                continue;
+            }
+        };
+        let acceptor = acceptor.read().unwrap().clone();
+        tokio::spawn(async move {
+            match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => serve_connection(stream, addr, server_addr, Transport::Dot).await,
+                Ok(Err(e)) => debug!("TLS handshake with {addr} failed: {e}"),
+                Err(_) => debug!("TLS handshake with {addr} timed out"),
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn connection_permit() -> OwnedSemaphorePermit {
+    CONNECTIONS
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("Connection semaphore is never closed")
+}
+
+/// Swaps in the new certificate once the files change, e.g. after a certbot renewal.
+/// Existing connections keep using the old one until they are closed.
+async fn watch_certificate(
+    config: &'static DotConfig,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+    mut modified: Option<(SystemTime, SystemTime)>,
+) {
+    loop {
+        time::sleep(CERT_CHECK_INTERVAL).await;
+        let current = cert_modified(config).await;
+        if current == modified {
+            continue;
+        }
+        // The files are retried on the next check if the renewal hasn't finished writing them yet
+        match load_acceptor(config).await {
+            Ok(new_acceptor) => {
+                info!("Reloaded TLS certificate from {}", config.cert_path);
+                *acceptor.write().unwrap() = new_acceptor;
+                modified = current;
+            }
+            Err(e) => error!("Failed to reload TLS certificate, keeping the old one: {e}"),
+        }
+    }
+}
+
+async fn cert_modified(config: &DotConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = fs::metadata(&config.cert_path)
+        .await
+        .ok()?
+        .modified()
+        .ok()?;
+    let key = fs::metadata(&config.key_path).await.ok()?.modified().ok()?;
+    Some((cert, key))
+}
+
+async fn load_acceptor(config: &DotConfig) -> anyhow::Result<TlsAcceptor> {
+    let cert_pem = fs::read(&config.cert_path).await?;
+    let certs = rustls_pemfile::certs(&mut &*cert_pem)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No certificate in {}", config.cert_path));
+    }
+
+    let key_pem = fs::read(&config.key_path).await?;
+    let key = rustls_pemfile::read_all(&mut &*key_pem)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("No private key in {}", config.key_path))?;
+
+    let mut tls = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    tls.alpn_protocols = vec![b"dot".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(tls)))
+}
+
+/// Reads length prefixed messages off the stream until the client closes it or goes idle.
+/// Queries are processed concurrently, so the replies may go out of order as RFC 7766 allows.
+async fn serve_connection<S>(
+    stream: S,
+    addr: SocketAddr,
+    server_addr: SocketAddr,
+    transport: Transport,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    debug!("New {} connection from {addr}", transport.as_str());
+    let (mut reader, mut writer) = io::split(stream);
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(MAX_IN_FLIGHT);
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let write_replies = tokio::spawn(async move {
+        while let Some(reply) = receiver.recv().await {
+            if let Err(e) = write_message(&mut writer, &reply).await {
+                debug!("Failed to write reply to {addr}: {e}");
+                break;
+            }
+        }
+        writer.shutdown().await.ok();
+    });
+
+    loop {
+        let bytes = match time::timeout(IDLE_TIMEOUT, read_message(&mut reader)).await {
+            Ok(Ok(Some(bytes))) => bytes,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => {
+                debug!("Failed to read message from {addr}: {e}");
+                break;
+            }
+            Err(_) => {
+                debug!("Closing idle connection from {addr}");
+                break;
+            }
+        };
+        // A client pipelining faster than it reads the replies is held back here
+        let permit = match in_flight.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => break,
+        };
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            match process_message(&bytes, addr, server_addr, transport).await {
+                Ok(Some(reply)) => {
+                    sender.send(reply).await.ok();
+                }
+                Ok(None) => error!("Response is empty, can't reply back"),
+                Err(e) => warn!("Failed to process message from {addr}: {e}"),
+            }
+            drop(permit);
+        });
+    }
+    // Replies still in flight get written before the connection is closed
+    drop(sender);
+    write_replies.await.ok();
+}
+
+/// DNS over TCP prefixes every message with its length as two bytes, RFC 1035 4.2.2
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let len = match reader.read_u16().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty message"));
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes).await?;
+    Ok(Some(bytes))
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Message is too long"))?;
+    writer.write_u16(len).await?;
+    writer.write_all(bytes).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_message, write_message};
+
+    #[tokio::test]
+    async fn test_framing() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        write_message(&mut client, b"first").await.unwrap();
+        write_message(&mut client, &[7; 300]).await.unwrap();
+        drop(client);
+
+        assert_eq!(
+            read_message(&mut server).await.unwrap(),
+            Some(b"first".to_vec())
+        );
+        assert_eq!(read_message(&mut server).await.unwrap(), Some(vec![7; 300]));
+        assert_eq!(read_message(&mut server).await.unwrap(), None);
+    }
+}
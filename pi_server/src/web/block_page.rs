@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use axum::extract::{ConnectInfo, Path};
+use axum::http::HeaderMap;
+use axum::response::{Html, IntoResponse};
+use axum::routing::{get, post};
+use axum::{Form, Json, Router, Server};
+use chrono::Local;
+use http::header;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use domain::db::filters::DbFilter;
+use domain::db::unblock_requests::{
+    approve_unblock_request, find_unblock_request, insert_unblock_request, load_unblock_requests,
+    update_unblock_request, DbUnblockRequest, PENDING, REJECTED,
+};
+use domain::domain_name::normalize;
+use domain::update_filters;
+
+use crate::dns::check_domain;
+use crate::web::websocket::{send_ws_msg, WsMessage};
+use crate::web::WebError;
+use crate::PI_CONFIG;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockPageConfig {
+    /// Address of the Pi, blocked A queries are answered with it
+    pub ipv4: Ipv4Addr,
+    /// Blocked AAAA queries are answered with it, `::` if not set
+    pub ipv6: Option<Ipv6Addr>,
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+fn default_port() -> u16 {
+    80
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WebUnblockRequest {
+    id: i64,
+    time: u64,
+    domain: String,
+    requester: String,
+    reason: Option<String>,
+    status: String,
+}
+
+impl WebUnblockRequest {
+    fn from(ur: DbUnblockRequest) -> Self {
+        WebUnblockRequest {
+            id: ur.ur_id,
+            time: ur.create_time.timestamp_millis() as u64,
+            domain: ur.domain_name,
+            requester: ur.requester,
+            reason: ur.reason,
+            status: ur.status,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnblockDecision {
+    approve: bool,
+}
+
+pub async fn start_block_page_server() -> anyhow::Result<()> {
+    let port = match &PI_CONFIG.get().unwrap().block_page {
+        Some(config) => config.port,
+        None => {
+            info!("Block page is disabled");
+            return Ok(());
+        }
+    };
+
+    let app = Router::new()
+        .route("/unblock", post(request_unblock))
+        .fallback(get(block_page));
+    info!("Starting block page server at port {port}");
+    Server::bind(&([0, 0, 0, 0], port).into())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+    Ok(())
+}
+
+async fn block_page(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let domain = blocked_host(&headers);
+    let reason = match &domain {
+        Some(domain) => match check_domain(&format!("{domain}."), Some(addr.ip()), None).await {
+            Some((reason, false, _)) => reason,
+            _ => "Not blocked anymore, try reloading the page".into(),
+        },
+        None => "Unknown".into(),
+    };
+    let domain = domain.unwrap_or_else(|| "Unknown".into());
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1"><title>Blocked: {domain}</title></head>
+<body style="font-family: sans-serif; max-width: 40em; margin: 4em auto; padding: 0 1em;">
+<h2>{domain} has been blocked</h2>
+<p>This domain is blocked on your network by the Pi DNS filter.</p>
+<p><b>Reason:</b> {reason}</p>
+<form method="post" action="/unblock">
+<input type="hidden" name="domain" value="{domain}">
+<input type="hidden" name="reason" value="{reason}">
+<button type="submit">Request unblock</button>
+</form>
+</body>
+</html>"#,
+        domain = html_escape(&domain),
+        reason = html_escape(&reason),
+    ))
+}
+
+async fn request_unblock(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, WebError> {
+    let domain = form
+        .get("domain")
+        .and_then(|d| normalize(d))
+        .ok_or_else(|| anyhow::anyhow!("Invalid domain"))?;
+    let requester = addr.ip().to_string();
+    let reason = form.get("reason").map(String::as_str);
+    let message = if insert_unblock_request(&domain, &requester, reason).await? {
+        info!("{requester} requested to unblock {domain}");
+        let mut payload = HashMap::new();
+        payload.insert("unblock_request", &domain);
+        if let Ok(msg) = serde_json::to_string(&payload) {
+            send_ws_msg(WsMessage::SendAll(msg));
+        }
+        "Your request has been recorded, an admin will look at it soon."
+    } else {
+        "A request to unblock this domain is already pending."
+    };
+    Ok(Html(format!(
+        r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Unblock {domain}</title></head>
+<body style="font-family: sans-serif; max-width: 40em; margin: 4em auto; padding: 0 1em;">
+<h2>{domain}</h2><p>{message}</p>
+</body></html>"#,
+        domain = html_escape(&domain),
+    )))
+}
+
+pub async fn fetch_unblock_requests() -> Result<impl IntoResponse, WebError> {
+    let requests = load_unblock_requests()
+        .await?
+        .into_iter()
+        .map(WebUnblockRequest::from)
+        .collect::<Vec<_>>();
+    Ok(Json(requests))
+}
+
+pub async fn decide_unblock_request(
+    Path(ur_id): Path<i64>,
+    Json(decision): Json<UnblockDecision>,
+) -> Result<impl IntoResponse, WebError> {
+    let request = find_unblock_request(ur_id)
+        .await?
+        .filter(|ur| ur.status == PENDING)
+        .ok_or_else(|| anyhow::anyhow!("No pending unblock request with id {ur_id}"))?;
+    if decision.approve {
+        info!("Approving unblock request for {}", request.domain_name);
+        let filter = DbFilter {
+            f_id: -1,
+            create_time: Local::now().naive_local(),
+            expr: request.domain_name.clone(),
+            is_regex: false,
+            enabled: true,
+            is_allow: true,
+            modifiers: String::new(),
+            version: 1,
+            hit_count: 0,
+            last_hit: None,
+            expires_at: None,
+        };
+        let (old, new) = approve_unblock_request(ur_id, &filter).await?;
+        let old = old.into_iter().collect::<Vec<_>>();
+        update_filters(&old, std::slice::from_ref(&new)).await;
+    } else {
+        info!("Rejecting unblock request for {}", request.domain_name);
+        update_unblock_request(ur_id, REJECTED).await?;
+    }
+    fetch_unblock_requests().await
+}
+
+/// The blocked domain is whatever the browser asked for in the Host header
+fn blocked_host(headers: &HeaderMap) -> Option<String> {
+    let host = headers.get(header::HOST)?.to_str().ok()?;
+    let host = host.rsplit_once(':').map(|(h, _)| h).unwrap_or(host);
+    normalize(host)
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+
+    use axum::body::HttpBody;
+    use axum::extract::{ConnectInfo, Path};
+    use axum::response::IntoResponse;
+    use axum::{Form, Json};
+    use domain::db::filters::load_all_filters;
+    use domain::db::init_db_at;
+    use domain::db::unblock_requests::{find_unblock_request, load_unblock_requests, APPROVED};
+
+    use super::{decide_unblock_request, request_unblock, UnblockDecision, PENDING, REJECTED};
+
+    async fn unblock(domain: &str) -> Result<String, ()> {
+        let addr: SocketAddr = "192.168.1.10:50000".parse().unwrap();
+        let mut form = HashMap::new();
+        form.insert("domain".to_string(), domain.to_string());
+        form.insert("reason".to_string(), "Blocked by ads.txt".to_string());
+        let mut body = request_unblock(ConnectInfo(addr), Form(form))
+            .await
+            .map_err(|_| ())?
+            .into_response()
+            .into_body();
+        let mut page = Vec::new();
+        while let Some(chunk) = body.data().await {
+            page.extend(chunk.unwrap());
+        }
+        Ok(String::from_utf8(page).unwrap())
+    }
+
+    async fn decide(ur_id: i64, approve: bool) -> bool {
+        decide_unblock_request(Path(ur_id), Json(UnblockDecision { approve }))
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_unblock() {
+        let dir = std::env::temp_dir().join(format!("block-page-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_file = dir.join("domain.db");
+        std::fs::remove_file(&db_file).ok();
+        // The only test of this crate using the domain db
+        init_db_at(&db_file).await.unwrap();
+
+        let page = unblock("Ads.Example.COM.").await.unwrap();
+        assert!(page.contains("<h2>ads.example.com</h2>"), "{page}");
+        assert!(page.contains("has been recorded"), "{page}");
+        let page = unblock("ads.example.com").await.unwrap();
+        assert!(page.contains("already pending"), "{page}");
+        assert!(unblock("<script>.com").await.is_err());
+
+        let requests = load_unblock_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.domain_name, "ads.example.com");
+        assert_eq!(request.requester, "192.168.1.10");
+        assert_eq!(request.reason.as_deref(), Some("Blocked by ads.txt"));
+        assert_eq!(request.status, PENDING);
+
+        assert!(decide(request.ur_id, true).await);
+        let request = find_unblock_request(request.ur_id).await.unwrap().unwrap();
+        assert_eq!(request.status, APPROVED);
+        let filters = load_all_filters().await.unwrap();
+        let filter = filters
+            .iter()
+            .find(|f| f.expr == "ads.example.com")
+            .unwrap();
+        assert!(filter.is_allow && filter.enabled && !filter.is_regex);
+        // Only the pending requests can be decided
+        assert!(!decide(request.ur_id, false).await);
+
+        unblock("tracker.example.com").await.unwrap();
+        let requests = load_unblock_requests().await.unwrap();
+        let request = requests
+            .iter()
+            .find(|ur| ur.domain_name == "tracker.example.com")
+            .unwrap();
+        assert!(decide(request.ur_id, false).await);
+        let request = find_unblock_request(request.ur_id).await.unwrap().unwrap();
+        assert_eq!(request.status, REJECTED);
+        let filters = load_all_filters().await.unwrap();
+        assert!(!filters.iter().any(|f| f.expr == "tracker.example.com"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
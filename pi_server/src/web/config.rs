@@ -2,16 +2,15 @@ use std::collections::{HashMap, HashSet};
 
 use axum::response::IntoResponse;
 use axum::{Form, Json};
-use chrono::Local;
+use chrono::{Local, NaiveDateTime};
 use itertools::{Either, Itertools};
-use regex::Regex;
 use serde::{Deserialize, Serialize};
-use trust_dns_proto::rr::Name;
 
 use crate::downloader::signal_blocked_domain_refresh;
-use domain::db::block_list::{load_block_list, save_block_list, DbBlockList};
+use domain::adblock::{parse_rule, rule_text};
+use domain::db::block_list::{load_block_list, save_block_list, DbBlockList, ListKind};
 use domain::db::filters::{load_all_filters, save_filters, DbFilter};
-use domain::reload_filters;
+use domain::{flush_hits, reload_filters, source_overlaps};
 
 use crate::web::WebError;
 
@@ -19,34 +18,76 @@ use crate::web::WebError;
 struct Config {
     approve_rules: Vec<String>,
     reject_rules: Vec<String>,
-    block_list: Vec<(String, i64)>,
+    /// Source, domain count, hit count and the last hit
+    block_list: Vec<(String, i64, i64, Option<u64>)>,
+    /// How the last refresh of the sources went, keyed by the source
+    block_list_status: HashMap<String, SourceStatus>,
+    /// How many domains the sources have in common, keyed by both sources
+    block_list_overlap: HashMap<String, HashMap<String, u64>>,
+    /// Hit count and the last hit of the rules, keyed by the rule
+    rule_hits: HashMap<String, (i64, Option<u64>)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SourceStatus {
+    last_updated: u64,
+    /// Failures in a row, the source is retried at `next_retry`
+    retry_count: i64,
+    next_retry: Option<u64>,
+    /// Why the last refresh failed or was rejected
+    last_error: Option<String>,
+    http_status: Option<i64>,
+    fetch_millis: Option<i64>,
+    fetch_bytes: Option<i64>,
+}
+
+impl From<&DbBlockList> for SourceStatus {
+    fn from(bl: &DbBlockList) -> Self {
+        SourceStatus {
+            last_updated: bl.last_updated.timestamp_millis() as u64,
+            retry_count: bl.retry_count,
+            next_retry: millis(bl.next_retry),
+            last_error: bl.warning.clone(),
+            http_status: bl.http_status,
+            fetch_millis: bl.fetch_millis,
+            fetch_bytes: bl.fetch_bytes,
+        }
+    }
 }
 
 pub async fn fetch_config() -> Result<impl IntoResponse, WebError> {
-    let (approve_rules, reject_rules) =
-        load_all_filters().await?.into_iter().partition_map(|dbf| {
-            let mut expr = dbf.expr;
-            if dbf.is_regex {
-                expr = format!("* {expr}");
-            }
-            if !dbf.enabled {
-                expr = format!("# {expr}");
-            }
-            if dbf.is_allow {
-                Either::Left(expr)
-            } else {
-                Either::Right(expr)
-            }
-        });
-    let block_list = load_block_list()
-        .await?
+    let filters = load_all_filters().await?;
+    let rule_hits = filters
+        .iter()
+        .map(|dbf| (filter_rule(dbf), (dbf.hit_count, millis(dbf.last_hit))))
+        .collect();
+    let (approve_rules, reject_rules) = filters.into_iter().partition_map(|dbf| {
+        let mut expr = filter_rule(&dbf);
+        if !dbf.enabled {
+            expr = format!("# {expr}");
+        }
+        if dbf.is_allow {
+            Either::Left(expr)
+        } else {
+            Either::Right(expr)
+        }
+    });
+    let block_list = load_block_list().await?;
+    let block_list_status = block_list
+        .iter()
+        .map(|bl| (bl.src.clone(), SourceStatus::from(bl)))
+        .collect();
+    let block_list = block_list
         .into_iter()
-        .map(|bl| (bl.src, bl.domain_count))
+        .map(|bl| (bl.src, bl.domain_count, bl.hit_count, millis(bl.last_hit)))
         .collect();
     let config = Config {
         approve_rules,
         reject_rules,
         block_list,
+        block_list_status,
+        block_list_overlap: source_overlaps().await,
+        rule_hits,
     };
     Ok(Json(config))
 }
@@ -74,6 +115,8 @@ pub async fn save_config(
         );
     }
     log::info!("Saving {} filters", configs.len());
+    // The pending hits are credited by the filter ids, they go in while the ids still match
+    flush_hits().await?;
     save_filters(configs).await?;
     log::info!("Reloading the filters...");
     reload_filters().await?;
@@ -90,6 +133,10 @@ pub async fn save_config(
             .filter(|s| !s.is_empty())
             .collect::<HashSet<_>>();
         if old_block_list != new_block_list {
+            let changed = old_block_list
+                .symmetric_difference(&new_block_list)
+                .map(|src| src.to_string())
+                .collect();
             log::info!(
                 "Block list has been updated {} vs {}",
                 old_block_list.len(),
@@ -105,11 +152,30 @@ pub async fn save_config(
                         retry_count: 0,
                         domain_count: -1,
                         last_updated,
+                        version: 1,
+                        hit_count: 0,
+                        last_hit: None,
+                        etag: None,
+                        last_modified: None,
+                        format: None,
+                        invalid_count: 0,
+                        kind: ListKind::Block.to_string(),
+                        min_domains: None,
+                        max_shrink: None,
+                        warning: None,
+                        next_retry: None,
+                        http_status: None,
+                        fetch_millis: None,
+                        fetch_bytes: None,
+                        name: None,
+                        category: None,
+                        enabled: true,
+                        refresh_hours: None,
                     })
                     .collect::<Vec<_>>(),
             )
             .await?;
-            signal_blocked_domain_refresh();
+            signal_blocked_domain_refresh(changed);
         } else {
             log::warn!("Block list hasn't been updated, nothing to do!");
         }
@@ -118,35 +184,29 @@ pub async fn save_config(
     fetch_config().await
 }
 
-fn extract_filter(mut rule: &str, is_allow: bool) -> Option<DbFilter> {
-    let enabled = if rule.starts_with('#') {
-        rule = rule[1..].trim();
-        false
-    } else {
-        true
-    };
-    let is_regex = if rule.starts_with('*') {
-        rule = rule[1..].trim();
-        if let Err(e) = Regex::new(rule) {
-            log::warn!("Can't parse {rule} as regex: {e:?}",);
-            return None;
-        }
-        true
+pub(super) fn millis(time: Option<NaiveDateTime>) -> Option<u64> {
+    time.map(|t| t.timestamp_millis() as u64)
+}
+
+/// The rule as the user would have written it
+pub(super) fn filter_rule(dbf: &DbFilter) -> String {
+    if !dbf.modifiers.is_empty() {
+        rule_text(dbf)
+    } else if dbf.is_regex {
+        format!("* {}", dbf.expr)
     } else {
-        if let Err(e) = Name::from_str_relaxed(rule) {
-            log::warn!("Can't parse {rule} as domain name: {e:?}");
-            return None;
+        dbf.expr.clone()
+    }
+}
+
+fn extract_filter(rule: &str, is_allow: bool) -> Option<DbFilter> {
+    match parse_rule(rule, is_allow) {
+        Ok(filter) => filter,
+        Err(e) => {
+            log::warn!("Can't parse rule {rule}: {e:?}");
+            None
         }
-        false
-    };
-    Some(DbFilter {
-        f_id: -1,
-        create_time: Local::now().naive_local(),
-        expr: rule.to_lowercase(),
-        is_regex,
-        is_allow,
-        enabled,
-    })
+    }
 }
 
 #[cfg(test)]
@@ -157,4 +217,22 @@ mod test {
     fn test1() {
         dbg!(extract_filter("#*facebook.com", true));
     }
+
+    #[test]
+    fn test_adblock() {
+        let filter = extract_filter("@@||example.com^$client=10.0.0.0/8", false).unwrap();
+        assert!(filter.is_allow && !filter.is_regex);
+        assert_eq!(filter.modifiers, "client=10.0.0.0/8");
+        assert!(extract_filter("||example.com^$badfilter", false).is_none());
+    }
+
+    #[test]
+    fn test_unicode() {
+        let filter = extract_filter("Bücher.Example.", false).unwrap();
+        assert_eq!(filter.expr, "xn--bcher-kva.example");
+        let filter = extract_filter("@@||ПРИМЕР.рф^$denyallow=Shop.ПРИМЕР.рф", false).unwrap();
+        assert_eq!(filter.expr, "xn--e1afmkfd.xn--p1ai");
+        assert_eq!(filter.modifiers, "denyallow=shop.xn--e1afmkfd.xn--p1ai");
+        assert!(extract_filter("bü cher.example", false).is_none());
+    }
 }
@@ -0,0 +1,799 @@
+use std::collections::{HashMap, HashSet};
+
+use axum::extract::Path;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{Duration, Local, NaiveDateTime, TimeZone};
+use http::{header, StatusCode};
+use log::*;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::time;
+
+use domain::adblock::parse_rule;
+use domain::db::block_list::{
+    self, find_block_list, load_block_list, BlockListSettings, DbBlockList, ListKind,
+};
+use domain::db::filters::next_filter_expiry;
+use domain::db::filters::{self, find_filter, load_all_filters, DbFilter};
+use domain::list_format::ListFormat;
+use domain::{expire_filters, reload_block_index, reload_source_labels, update_filters};
+
+use crate::downloader::{local_path, signal_blocked_domain_refresh};
+use crate::web::config::{filter_rule, millis};
+use crate::web::websocket::{send_ws_msg, WsMessage};
+use crate::web::WebError;
+
+const EXPIRY_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Wakes up the expiry watcher when a filter gets an expiry, it might be sooner than it waits for
+static EXPIRY_CHANGED: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[derive(Debug, Serialize)]
+pub struct WebFilter {
+    id: i64,
+    rule: String,
+    allow: bool,
+    enabled: bool,
+    create_time: u64,
+    version: i64,
+    hit_count: i64,
+    last_hit: Option<u64>,
+    expires_at: Option<u64>,
+}
+
+impl From<&DbFilter> for WebFilter {
+    fn from(dbf: &DbFilter) -> Self {
+        WebFilter {
+            id: dbf.f_id,
+            rule: filter_rule(dbf),
+            allow: dbf.is_allow,
+            enabled: dbf.enabled,
+            create_time: dbf.create_time.timestamp_millis() as u64,
+            version: dbf.version,
+            hit_count: dbf.hit_count,
+            last_hit: millis(dbf.last_hit),
+            expires_at: millis(dbf.expires_at),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebBlockList {
+    id: i64,
+    src: String,
+    domain_count: i64,
+    retry_count: i64,
+    last_updated: u64,
+    version: i64,
+    hit_count: i64,
+    last_hit: Option<u64>,
+    /// `None` when it's detected from the content
+    format: Option<String>,
+    invalid_count: i64,
+    kind: String,
+    /// `None` for the defaults of the server
+    min_domains: Option<i64>,
+    max_shrink: Option<i64>,
+    /// Why the last refresh failed or was rejected
+    warning: Option<String>,
+    next_retry: Option<u64>,
+    http_status: Option<i64>,
+    fetch_millis: Option<i64>,
+    fetch_bytes: Option<i64>,
+    /// `None` when it's shown as its `src`
+    name: Option<String>,
+    category: Option<String>,
+    enabled: bool,
+    /// `None` for the default of the server
+    refresh_hours: Option<i64>,
+}
+
+impl From<&DbBlockList> for WebBlockList {
+    fn from(bl: &DbBlockList) -> Self {
+        WebBlockList {
+            id: bl.bl_id,
+            src: bl.src.clone(),
+            domain_count: bl.domain_count,
+            retry_count: bl.retry_count,
+            last_updated: bl.last_updated.timestamp_millis() as u64,
+            version: bl.version,
+            hit_count: bl.hit_count,
+            last_hit: millis(bl.last_hit),
+            format: bl.format.clone(),
+            invalid_count: bl.invalid_count,
+            kind: bl.kind.clone(),
+            min_domains: bl.min_domains,
+            max_shrink: bl.max_shrink,
+            warning: bl.warning.clone(),
+            next_retry: millis(bl.next_retry),
+            http_status: bl.http_status,
+            fetch_millis: bl.fetch_millis,
+            fetch_bytes: bl.fetch_bytes,
+            name: bl.name.clone(),
+            category: bl.category.clone(),
+            enabled: bl.enabled,
+            refresh_hours: bl.refresh_hours,
+        }
+    }
+}
+
+/// Why one of the submitted rules or sources was rejected, `index` is its position in the request
+#[derive(Debug, Serialize)]
+struct ItemError {
+    index: usize,
+    item: String,
+    error: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ValidationErrors {
+    error: String,
+    errors: Vec<ItemError>,
+}
+
+fn validation_errors(errors: Vec<ItemError>) -> Response {
+    let response = ValidationErrors {
+        error: format!("{} of the items are invalid", errors.len()),
+        errors,
+    };
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(response)).into_response()
+}
+
+fn with_etag<T: Serialize>(status: StatusCode, version: i64, body: T) -> Response {
+    (status, [(header::ETAG, etag(version))], Json(body)).into_response()
+}
+
+fn etag(version: i64) -> String {
+    format!("\"{version}\"")
+}
+
+/// Returns the version the client expects to change, `If-Match` is required for every update
+fn if_match(headers: &HeaderMap, current: i64) -> Result<i64, WebError> {
+    let value = headers
+        .get(header::IF_MATCH)
+        .ok_or_else(|| WebError::new(StatusCode::PRECONDITION_REQUIRED, "If-Match is required"))?
+        .to_str()
+        .map_err(|_| WebError::new(StatusCode::BAD_REQUEST, "Invalid If-Match"))?;
+    let matches = value
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag(current));
+    if matches {
+        Ok(current)
+    } else {
+        Err(modified(current))
+    }
+}
+
+fn modified(current: i64) -> WebError {
+    WebError::new(
+        StatusCode::PRECONDITION_FAILED,
+        format!("It has been modified, the current version is {current}"),
+    )
+}
+
+fn not_found(id: i64) -> WebError {
+    WebError::new(StatusCode::NOT_FOUND, format!("Nothing found with id {id}"))
+}
+
+async fn existing_filter(id: i64) -> Result<DbFilter, WebError> {
+    find_filter(id).await?.ok_or_else(|| not_found(id))
+}
+
+async fn existing_block_list(id: i64) -> Result<DbBlockList, WebError> {
+    find_block_list(id).await?.ok_or_else(|| not_found(id))
+}
+
+pub async fn fetch_filters() -> Result<impl IntoResponse, WebError> {
+    let filters = load_all_filters().await?;
+    Ok(Json(
+        filters.iter().map(WebFilter::from).collect::<Vec<_>>(),
+    ))
+}
+
+pub async fn fetch_filter(Path(id): Path<i64>) -> Result<Response, WebError> {
+    let filter = existing_filter(id).await?;
+    Ok(with_etag(
+        StatusCode::OK,
+        filter.version,
+        WebFilter::from(&filter),
+    ))
+}
+
+/// When a filter expires, either at a time in epoch millis or after a duration like `1h30m`
+#[derive(Debug, Default, Deserialize)]
+pub struct Expiry {
+    expires_at: Option<u64>,
+    expires_in: Option<String>,
+}
+
+impl Expiry {
+    fn resolve(&self) -> Result<Option<NaiveDateTime>, String> {
+        let now = Local::now();
+        let expires_at = match (self.expires_at, &self.expires_in) {
+            (Some(_), Some(_)) => {
+                return Err("Only one of expires_at and expires_in is allowed".into())
+            }
+            (Some(millis), None) => Some(
+                Local
+                    .timestamp_millis_opt(millis as i64)
+                    .single()
+                    .ok_or_else(|| format!("Invalid expires_at: {millis}"))?,
+            ),
+            (None, Some(duration)) => Some(
+                now.checked_add_signed(parse_duration(duration)?)
+                    .ok_or_else(|| format!("Invalid expires_in: {duration}"))?,
+            ),
+            (None, None) => None,
+        };
+        match expires_at {
+            Some(expires_at) if expires_at <= now => Err("It has expired already".into()),
+            expires_at => Ok(expires_at.map(|t| t.naive_local())),
+        }
+    }
+}
+
+/// Parses durations like `30m`, `1h30m` or `2d`, the units are s, m, h, d and w
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration: {duration}");
+    let mut total = Duration::zero();
+    let mut rest = duration.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let value = rest[..digits].parse::<i64>().map_err(|_| invalid())?;
+        let unit = rest[digits..].chars().next().ok_or_else(invalid)?;
+        let seconds = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        // Any number of milliseconds is a valid duration, unlike the larger units
+        let part = value
+            .checked_mul(seconds * 1000)
+            .map(Duration::milliseconds)
+            .ok_or_else(invalid)?;
+        total = total.checked_add(&part).ok_or_else(invalid)?;
+        rest = rest[digits + 1..].trim_start();
+    }
+    Ok(total)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewFilters {
+    rules: Vec<String>,
+    allow: bool,
+    #[serde(flatten)]
+    expiry: Expiry,
+}
+
+fn parse_filter(rule: &str, allow: bool) -> Result<DbFilter, String> {
+    match parse_rule(rule.trim(), allow) {
+        Ok(Some(filter)) => Ok(filter),
+        Ok(None) => Err("It's not a rule".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Creates all of the rules, or none of them if any is invalid or already exists
+pub async fn create_filters(Json(new): Json<NewFilters>) -> Result<Response, WebError> {
+    let expires_at = new
+        .expiry
+        .resolve()
+        .map_err(|e| WebError::new(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    let mut filters = Vec::with_capacity(new.rules.len());
+    let mut errors = Vec::new();
+    for (index, rule) in new.rules.iter().enumerate() {
+        match parse_filter(rule, new.allow) {
+            Ok(filter) => filters.push(DbFilter {
+                expires_at,
+                ..filter
+            }),
+            Err(error) => errors.push(ItemError {
+                index,
+                item: rule.clone(),
+                error,
+            }),
+        }
+    }
+    if !errors.is_empty() {
+        return Ok(validation_errors(errors));
+    }
+
+    let results = filters::create_filters(&filters).await?;
+    let errors = results
+        .iter()
+        .enumerate()
+        .filter_map(|(index, result)| {
+            result.as_ref().err().map(|error| ItemError {
+                index,
+                item: new.rules[index].clone(),
+                error: error.clone(),
+            })
+        })
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        return Ok(validation_errors(errors));
+    }
+
+    let mut created = Vec::with_capacity(results.len());
+    for id in results.into_iter().flatten() {
+        created.push(existing_filter(id).await?);
+    }
+    update_filters(&[], &created).await;
+    if expires_at.is_some() {
+        EXPIRY_CHANGED.notify_one();
+    }
+    let created = created.iter().map(WebFilter::from).collect::<Vec<_>>();
+    Ok((StatusCode::CREATED, Json(created)).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdatedFilter {
+    rule: String,
+    allow: bool,
+    enabled: Option<bool>,
+    /// The filter doesn't expire if neither is set
+    #[serde(flatten)]
+    expiry: Expiry,
+}
+
+pub async fn update_filter(
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+    Json(updated): Json<UpdatedFilter>,
+) -> Result<Response, WebError> {
+    let old = existing_filter(id).await?;
+    let version = if_match(&headers, old.version)?;
+    let expires_at = updated
+        .expiry
+        .resolve()
+        .map_err(|e| WebError::new(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    let mut filter = match parse_filter(&updated.rule, updated.allow) {
+        Ok(filter) => filter,
+        Err(error) => {
+            return Ok(validation_errors(vec![ItemError {
+                index: 0,
+                item: updated.rule,
+                error,
+            }]))
+        }
+    };
+    filter.f_id = id;
+    filter.create_time = old.create_time;
+    filter.enabled = updated.enabled.unwrap_or(filter.enabled);
+    filter.expires_at = expires_at;
+    filter.version = version;
+    save_filter(old, filter).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FilterState {
+    enabled: Option<bool>,
+    /// Replaces the expiry if either is set, `"never"` for `expires_in` removes it
+    #[serde(flatten)]
+    expiry: Expiry,
+}
+
+/// Enables or disables the filter, or changes when it expires
+pub async fn patch_filter(
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+    Json(state): Json<FilterState>,
+) -> Result<Response, WebError> {
+    let old = existing_filter(id).await?;
+    let version = if_match(&headers, old.version)?;
+    let expires_at = if state.expiry.expires_in.as_deref() == Some("never") {
+        None
+    } else if state.expiry.expires_at.is_some() || state.expiry.expires_in.is_some() {
+        state
+            .expiry
+            .resolve()
+            .map_err(|e| WebError::new(StatusCode::UNPROCESSABLE_ENTITY, e))?
+    } else if state.enabled == Some(true) && !old.enabled {
+        // Enabling an expired filter again shouldn't disable it right away
+        old.expires_at.filter(|t| *t > Local::now().naive_local())
+    } else {
+        old.expires_at
+    };
+    let filter = DbFilter {
+        enabled: state.enabled.unwrap_or(old.enabled),
+        expires_at,
+        version,
+        ..old.clone()
+    };
+    save_filter(old, filter).await
+}
+
+async fn save_filter(old: DbFilter, filter: DbFilter) -> Result<Response, WebError> {
+    match filters::update_filter(&filter).await? {
+        Ok(true) => {}
+        Ok(false) => return Err(modified(existing_filter(old.f_id).await?.version)),
+        Err(error) => return Err(WebError::new(StatusCode::CONFLICT, error)),
+    }
+    let new = existing_filter(old.f_id).await?;
+    update_filters(&[old], std::slice::from_ref(&new)).await;
+    if new.expires_at.is_some() {
+        EXPIRY_CHANGED.notify_one();
+    }
+    Ok(with_etag(
+        StatusCode::OK,
+        new.version,
+        WebFilter::from(&new),
+    ))
+}
+
+pub async fn delete_filter(
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<StatusCode, WebError> {
+    let old = existing_filter(id).await?;
+    let version = if_match(&headers, old.version)?;
+    if !filters::delete_filter(id, version).await? {
+        return Err(modified(existing_filter(id).await?.version));
+    }
+    update_filters(&[old], &[]).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn fetch_block_lists() -> Result<impl IntoResponse, WebError> {
+    let block_lists = load_block_list().await?;
+    Ok(Json(
+        block_lists
+            .iter()
+            .map(WebBlockList::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+pub async fn fetch_block_list(Path(id): Path<i64>) -> Result<Response, WebError> {
+    let block_list = existing_block_list(id).await?;
+    Ok(with_etag(
+        StatusCode::OK,
+        block_list.version,
+        WebBlockList::from(&block_list),
+    ))
+}
+
+fn validate_src(src: &str, existing: &HashSet<String>) -> Result<String, String> {
+    let src = src.trim();
+    let url = reqwest::Url::parse(src).map_err(|e| e.to_string())?;
+    match url.scheme() {
+        "http" | "https" => {}
+        // A file or a directory of them
+        "file" => {
+            let path = local_path(src).map_err(|e| e.to_string())?;
+            if !path.exists() {
+                return Err(format!("{} doesn't exist", path.display()));
+            }
+        }
+        scheme => return Err(format!("Unsupported scheme: {scheme}")),
+    }
+    if existing.contains(src) {
+        return Err("It already exists".into());
+    }
+    Ok(src.to_owned())
+}
+
+/// What the user sets for a source, only `src` is required
+#[derive(Debug, Default, Deserialize)]
+pub struct WebBlockListSettings {
+    src: String,
+    /// Detected from the content if missing
+    #[serde(default)]
+    format: Option<String>,
+    /// `block` if missing
+    #[serde(default)]
+    kind: Option<String>,
+    #[serde(default)]
+    min_domains: Option<i64>,
+    /// Percent of the domains a refreshed list may lose
+    #[serde(default)]
+    max_shrink: Option<i64>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    category: Option<String>,
+    /// `true` if missing
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    refresh_hours: Option<i64>,
+}
+
+impl WebBlockListSettings {
+    fn validate(&self, existing: &HashSet<String>) -> Result<BlockListSettings, String> {
+        if matches!(self.min_domains, Some(min) if min < 0) {
+            return Err("The minimum domain count can't be negative".into());
+        }
+        if matches!(self.max_shrink, Some(max) if !(0..=100).contains(&max)) {
+            return Err("The maximum shrink has to be a percentage, 0 to 100".into());
+        }
+        if matches!(self.refresh_hours, Some(hours) if hours < 1) {
+            return Err("The refresh interval has to be an hour at least".into());
+        }
+        Ok(BlockListSettings {
+            src: validate_src(&self.src, existing)?,
+            format: self
+                .format
+                .as_deref()
+                .map(|format| format.parse::<ListFormat>().map(|f| f.to_string()))
+                .transpose()?,
+            kind: self
+                .kind
+                .as_deref()
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or(ListKind::Block),
+            min_domains: self.min_domains,
+            max_shrink: self.max_shrink,
+            name: self
+                .name
+                .as_deref()
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned),
+            category: self.category.as_deref().map(str::parse).transpose()?,
+            enabled: self.enabled.unwrap_or(true),
+            refresh_hours: self.refresh_hours,
+        })
+    }
+}
+
+/// A source is either its url, or the url with its settings
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum NewBlockList {
+    Src(String),
+    WithSettings(WebBlockListSettings),
+}
+
+impl NewBlockList {
+    fn src(&self) -> &str {
+        match self {
+            NewBlockList::Src(src) => src,
+            NewBlockList::WithSettings(settings) => &settings.src,
+        }
+    }
+
+    fn validate(&self, existing: &HashSet<String>) -> Result<BlockListSettings, String> {
+        match self {
+            NewBlockList::Src(src) => WebBlockListSettings {
+                src: src.clone(),
+                ..Default::default()
+            }
+            .validate(existing),
+            NewBlockList::WithSettings(settings) => settings.validate(existing),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewBlockLists {
+    sources: Vec<NewBlockList>,
+}
+
+/// Adds all of the sources, or none of them if any is invalid or already exists
+pub async fn create_block_lists(Json(new): Json<NewBlockLists>) -> Result<Response, WebError> {
+    let mut existing = load_block_list()
+        .await?
+        .into_iter()
+        .map(|bl| bl.src)
+        .collect::<HashSet<_>>();
+    let mut sources = Vec::with_capacity(new.sources.len());
+    let mut errors = Vec::new();
+    for (index, new) in new.sources.iter().enumerate() {
+        match new.validate(&existing) {
+            Ok(settings) => {
+                existing.insert(settings.src.clone());
+                sources.push(settings);
+            }
+            Err(error) => errors.push(ItemError {
+                index,
+                item: new.src().to_owned(),
+                error,
+            }),
+        }
+    }
+    if !errors.is_empty() {
+        return Ok(validation_errors(errors));
+    }
+
+    let results = block_list::create_block_lists(&sources).await?;
+    let errors = results
+        .iter()
+        .enumerate()
+        .filter_map(|(index, result)| {
+            result.as_ref().err().map(|error| ItemError {
+                index,
+                item: sources[index].src.clone(),
+                error: error.clone(),
+            })
+        })
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        return Ok(validation_errors(errors));
+    }
+
+    let mut created = Vec::with_capacity(results.len());
+    for id in results.into_iter().flatten() {
+        created.push(WebBlockList::from(&existing_block_list(id).await?));
+    }
+    reload_source_labels().await?;
+    signal_blocked_domain_refresh(sources.into_iter().map(|settings| settings.src).collect());
+    Ok((StatusCode::CREATED, Json(created)).into_response())
+}
+
+pub async fn update_block_list(
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+    Json(updated): Json<WebBlockListSettings>,
+) -> Result<Response, WebError> {
+    let old = existing_block_list(id).await?;
+    let version = if_match(&headers, old.version)?;
+    let existing = load_block_list()
+        .await?
+        .into_iter()
+        .filter(|bl| bl.bl_id != id)
+        .map(|bl| bl.src)
+        .collect::<HashSet<_>>();
+    let settings = match updated.validate(&existing) {
+        Ok(settings) => settings,
+        Err(error) => {
+            return Ok(validation_errors(vec![ItemError {
+                index: 0,
+                item: updated.src,
+                error,
+            }]))
+        }
+    };
+    if !block_list::update_block_list_src(id, &settings, version).await? {
+        return Err(modified(existing_block_list(id).await?.version));
+    }
+    let new = existing_block_list(id).await?;
+    // The domains of a disabled source are left out of the index, without refreshing it
+    if new.enabled != old.enabled {
+        reload_block_index().await?;
+    } else {
+        reload_source_labels().await?;
+    }
+    let enabled = new.enabled && !old.enabled;
+    if new.src != old.src || new.format != old.format || new.kind != old.kind || enabled {
+        signal_blocked_domain_refresh(vec![new.src.clone()]);
+    }
+    Ok(with_etag(
+        StatusCode::OK,
+        new.version,
+        WebBlockList::from(&new),
+    ))
+}
+
+pub async fn delete_block_list(
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<StatusCode, WebError> {
+    let old = existing_block_list(id).await?;
+    let version = if_match(&headers, old.version)?;
+    if !block_list::delete_block_list(id, version).await? {
+        return Err(modified(existing_block_list(id).await?.version));
+    }
+    signal_blocked_domain_refresh(vec![old.src]);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Disables the filters once they expire, and lets the web clients know about it
+pub async fn start_filter_expiry() -> anyhow::Result<()> {
+    loop {
+        let wait = match next_filter_expiry().await {
+            Ok(Some(next)) => (next - Local::now().naive_local())
+                .to_std()
+                .unwrap_or_default()
+                .min(EXPIRY_CHECK_INTERVAL),
+            Ok(None) => EXPIRY_CHECK_INTERVAL,
+            Err(e) => {
+                warn!("Failed to find the next filter expiry: {e:?}");
+                EXPIRY_CHECK_INTERVAL
+            }
+        };
+        tokio::select! {
+            _ = time::sleep(wait) => {}
+            _ = EXPIRY_CHANGED.notified() => continue,
+        }
+
+        match expire_filters().await {
+            Ok(expired) if !expired.is_empty() => {
+                let rules = expired.iter().map(filter_rule).collect::<Vec<_>>();
+                info!("Filters have expired: {rules:?}");
+                let mut payload = HashMap::new();
+                payload.insert("filters_expired", rules);
+                if let Ok(msg) = serde_json::to_string(&payload) {
+                    send_ws_msg(WsMessage::SendAll(msg));
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to expire the filters: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderMap;
+    use http::{header, StatusCode};
+
+    use super::{if_match, parse_duration, Expiry};
+
+    #[test]
+    fn test_if_match() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            if_match(&headers, 3).unwrap_err().status,
+            StatusCode::PRECONDITION_REQUIRED
+        );
+        for (value, ok) in [
+            ("\"3\"", true),
+            ("W/\"3\"", true),
+            ("\"2\", \"3\"", true),
+            ("*", true),
+            ("\"2\"", false),
+            ("3", false),
+        ] {
+            headers.insert(header::IF_MATCH, value.parse().unwrap());
+            match if_match(&headers, 3) {
+                Ok(version) => assert!(ok && version == 3, "{value}"),
+                Err(e) => assert!(
+                    !ok && e.status == StatusCode::PRECONDITION_FAILED,
+                    "{value}"
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90s").unwrap().num_seconds(), 90);
+        assert_eq!(parse_duration("1h30m").unwrap().num_minutes(), 90);
+        assert_eq!(parse_duration("1h 30m").unwrap().num_minutes(), 90);
+        assert_eq!(parse_duration("2d").unwrap().num_hours(), 48);
+        assert_eq!(parse_duration("1w").unwrap().num_days(), 7);
+        for invalid in [
+            "",
+            "h",
+            "10",
+            "5y",
+            "1.5h",
+            "-1h",
+            "99999999999w",
+            "9223372036854775807s",
+            "99999999999999999999s",
+            "10000000000w 10000000000w",
+        ] {
+            assert!(parse_duration(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_expiry() {
+        let expiry = |expires_at: Option<u64>, expires_in: Option<&str>| Expiry {
+            expires_at,
+            expires_in: expires_in.map(str::to_string),
+        };
+        assert_eq!(expiry(None, None).resolve(), Ok(None));
+        assert!(expiry(None, Some("1h")).resolve().unwrap().is_some());
+        assert!(expiry(Some(1), None).resolve().is_err());
+        assert!(expiry(Some(u64::MAX), None).resolve().is_err());
+        assert!(expiry(Some(1), Some("1h")).resolve().is_err());
+        // A valid duration, but way past the last date there is
+        assert!(expiry(None, Some("10000000000w")).resolve().is_err());
+    }
+}
@@ -1,15 +1,22 @@
 use axum::extract::Path;
 use axum::response::IntoResponse;
 use axum::Json;
+use std::cmp::Reverse;
 use std::fmt::Display;
 use std::time::Instant;
 
 use chrono::{Duration, Local};
+use domain::db::block_list::load_block_list;
+use domain::db::filters::load_all_filters;
+use domain::flush_hits;
 use linked_hash_map::LinkedHashMap;
 use serde::{Deserialize, Serialize};
 
-use crate::db::dns_requests::{agg_by_filtered, agg_by_time, agg_by_type, agg_failed_by_time};
+use crate::db::dns_requests::{
+    agg_by_category, agg_by_filtered, agg_by_time, agg_by_type, agg_failed_by_time,
+};
 
+use crate::web::config::{filter_rule, millis};
 use crate::web::WebError;
 use crate::Timer;
 
@@ -30,6 +37,39 @@ struct DashboardInfo {
     queries: LinkedHashMap<String, u64>,
     top_approved: LinkedHashMap<String, u64>,
     top_rejected: LinkedHashMap<String, u64>,
+    /// Requests blocked by the lists of every category, the busiest first
+    rejected_by_category: LinkedHashMap<String, u64>,
+    rule_hits: Vec<RuleHits>,
+}
+
+/// Hits of a filter or a block list since it was added, regardless of the days
+#[derive(Debug, Deserialize, Serialize)]
+struct RuleHits {
+    rule: String,
+    /// allow, block or list
+    kind: String,
+    hit_count: i64,
+    last_hit: Option<u64>,
+}
+
+/// All the filters and block lists, the busiest first, so the unused ones are at the end
+async fn rule_hits() -> anyhow::Result<Vec<RuleHits>> {
+    flush_hits().await?;
+    let filters = load_all_filters().await?.into_iter().map(|dbf| RuleHits {
+        rule: filter_rule(&dbf),
+        kind: if dbf.is_allow { "allow" } else { "block" }.into(),
+        hit_count: dbf.hit_count,
+        last_hit: millis(dbf.last_hit),
+    });
+    let block_lists = load_block_list().await?.into_iter().map(|bl| RuleHits {
+        rule: bl.src,
+        kind: "list".into(),
+        hit_count: bl.hit_count,
+        last_hit: millis(bl.last_hit),
+    });
+    let mut hits = filters.chain(block_lists).collect::<Vec<_>>();
+    hits.sort_by_key(|hits| Reverse((hits.hit_count, hits.last_hit)));
+    Ok(hits)
 }
 
 pub async fn fetch_dashboard(Path(days): Path<u32>) -> Result<impl IntoResponse, WebError> {
@@ -53,6 +93,8 @@ pub async fn fetch_dashboard(Path(days): Path<u32>) -> Result<impl IntoResponse,
             queries: LinkedHashMap::with_capacity(10),
             top_approved: LinkedHashMap::with_capacity(10),
             top_rejected: LinkedHashMap::with_capacity(10),
+            rejected_by_category: LinkedHashMap::new(),
+            rule_hits: Vec::new(),
         };
         let from = Local::now().naive_local() - Duration::days(days as i64);
         let agg_time = tokio::spawn(agg_by_time(from));
@@ -60,6 +102,8 @@ pub async fn fetch_dashboard(Path(days): Path<u32>) -> Result<impl IntoResponse,
         let agg_type = tokio::spawn(agg_by_type(from));
         let agg_filtered_true = tokio::spawn(agg_by_filtered(from, true));
         let agg_filtered_false = tokio::spawn(agg_by_filtered(from, false));
+        let agg_category = tokio::spawn(agg_by_category(from));
+        let rule_hits = tokio::spawn(rule_hits());
 
         for (time, count, res_time, filtered) in agg_time.await?? {
             let time = time.timestamp_millis() as u64;
@@ -121,6 +165,15 @@ pub async fn fetch_dashboard(Path(days): Path<u32>) -> Result<impl IntoResponse,
                 info.top_rejected.insert(k, v as u64);
             });
         }
+        if let Ok(res) = agg_category.await.unwrap() {
+            res.into_iter().for_each(|(k, v)| {
+                info.rejected_by_category.insert(k, v as u64);
+            });
+        }
+        match rule_hits.await? {
+            Ok(res) => info.rule_hits = res,
+            Err(e) => log::warn!("Failed to load the rule hits: {e:?}"),
+        }
         log::info!(
             "Total time to aggregate data for {} day(s): {}",
             days,
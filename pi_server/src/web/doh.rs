@@ -0,0 +1,324 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, ContentLengthLimit, Query};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http::{header, StatusCode};
+use log::*;
+use serde::Deserialize;
+use serde_json::json;
+use trust_dns_proto::op::{Message, Query as DnsQuery};
+use trust_dns_proto::rr::{Name, RecordType};
+
+use crate::dns::{process_message, Transport};
+use crate::PI_CONFIG;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const DNS_JSON: &str = "application/dns-json";
+/// DNS messages can't be larger than that
+const MAX_MESSAGE_SIZE: u64 = 65535;
+
+/// Query parameters of RFC 8484 GET requests, and of the JSON API used by Google/Cloudflare
+#[derive(Debug, Deserialize)]
+pub struct DohParams {
+    dns: Option<String>,
+    name: Option<String>,
+    #[serde(rename = "type")]
+    record_type: Option<String>,
+}
+
+type DohResult = Result<Response, (StatusCode, String)>;
+
+pub async fn doh_get(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<DohParams>,
+) -> DohResult {
+    let addr = client_addr(addr, &headers);
+    if let Some(dns) = &params.dns {
+        return resolve_wire(&decode_dns_param(dns)?, addr).await;
+    }
+
+    // Without the dns parameter it's the JSON flavour, e.g. `curl '.../dns-query?name=example.com'`
+    let name = params
+        .name
+        .as_deref()
+        .ok_or_else(|| bad_request("Either dns or name parameter is required"))?;
+    let record_type = params.record_type.as_deref().unwrap_or("A");
+    resolve_json(name, record_type, addr).await
+}
+
+pub async fn doh_post(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ContentLengthLimit(body): ContentLengthLimit<Bytes, MAX_MESSAGE_SIZE>,
+) -> DohResult {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .unwrap_or_default();
+    if !content_type.starts_with(DNS_MESSAGE) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Content type must be {DNS_MESSAGE}"),
+        ));
+    }
+    resolve_wire(&body, client_addr(addr, &headers)).await
+}
+
+async fn resolve_wire(bytes: &[u8], addr: SocketAddr) -> DohResult {
+    let reply = resolve(bytes, addr).await?;
+    let max_age = Message::from_vec(&reply)
+        .ok()
+        .and_then(|msg| msg.answers().iter().map(|r| r.ttl()).min())
+        .unwrap_or(0);
+    Ok((
+        [
+            (header::CONTENT_TYPE, DNS_MESSAGE.to_owned()),
+            (header::CACHE_CONTROL, format!("max-age={max_age}")),
+        ],
+        reply,
+    )
+        .into_response())
+}
+
+/// The `dns` parameter is the wire format request in base64url, the padding is optional
+fn decode_dns_param(dns: &str) -> Result<Vec<u8>, (StatusCode, String)> {
+    base64::decode_config(dns.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|e| bad_request(format!("Invalid dns parameter: {e}")))
+}
+
+async fn resolve_json(name: &str, record_type: &str, addr: SocketAddr) -> DohResult {
+    let bytes = json_request(name, record_type)?
+        .to_vec()
+        .map_err(|e| bad_request(format!("Failed to create dns request: {e}")))?;
+    let reply = resolve(&bytes, addr).await?;
+    let reply = Message::from_vec(&reply).map_err(server_error)?;
+    let mut response = Json(json_reply(&reply)).into_response();
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(DNS_JSON),
+    );
+    Ok(response)
+}
+
+/// The wire format request for the `name` and `type` parameters of the JSON flavour
+fn json_request(name: &str, record_type: &str) -> Result<Message, (StatusCode, String)> {
+    let mut name =
+        Name::from_str_relaxed(name).map_err(|e| bad_request(format!("Invalid name: {e}")))?;
+    name.set_fqdn(true);
+    let record_type = match record_type.parse::<u16>() {
+        Ok(code) => RecordType::from(code),
+        Err(_) => RecordType::from_str(&record_type.to_uppercase())
+            .map_err(|e| bad_request(format!("Invalid type: {e}")))?,
+    };
+    let mut request = Message::new();
+    request
+        .set_id(rand_id())
+        .set_recursion_desired(true)
+        .add_query(DnsQuery::query(name, record_type));
+    Ok(request)
+}
+
+fn json_reply(reply: &Message) -> serde_json::Value {
+    let records = |records: &[trust_dns_proto::rr::Record]| {
+        records
+            .iter()
+            .map(|r| {
+                json!({
+                    "name": r.name().to_string(),
+                    "type": u16::from(r.record_type()),
+                    "TTL": r.ttl(),
+                    "data": r.data().map(|d| d.to_string()).unwrap_or_default(),
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+    json!({
+        "Status": u16::from(reply.response_code()),
+        "TC": reply.truncated(),
+        "RD": reply.recursion_desired(),
+        "RA": reply.recursion_available(),
+        "AD": reply.authentic_data(),
+        "CD": reply.checking_disabled(),
+        "Question": reply.queries().iter().map(|q| json!({
+            "name": q.name().to_string(),
+            "type": u16::from(q.query_type()),
+        })).collect::<Vec<_>>(),
+        "Answer": records(reply.answers()),
+        "Authority": records(reply.name_servers()),
+    })
+}
+
+async fn resolve(bytes: &[u8], addr: SocketAddr) -> Result<Vec<u8>, (StatusCode, String)> {
+    let server_addr = SocketAddr::from(([0, 0, 0, 0], PI_CONFIG.get().unwrap().web_port as u16));
+    match process_message(bytes, addr, server_addr, Transport::Doh).await {
+        Ok(Some(reply)) => Ok(reply),
+        Ok(None) => Err((StatusCode::BAD_GATEWAY, "No response from upstream".into())),
+        Err(e) => {
+            warn!("Failed to process DoH request from {addr}: {e}");
+            Err(bad_request(e.to_string()))
+        }
+    }
+}
+
+/// Requests proxied by a local reverse proxy carry the real client in X-Forwarded-For.
+/// Only the last entry is the one added by the proxy, the client can send whatever before it.
+fn client_addr(addr: SocketAddr, headers: &HeaderMap) -> SocketAddr {
+    if !addr.ip().is_loopback() {
+        return addr;
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .next_back()
+        .and_then(|xff| xff.to_str().ok())
+        .and_then(|xff| xff.rsplit(',').next())
+        .and_then(|ip| IpAddr::from_str(ip.trim()).ok())
+        .map(|ip| SocketAddr::new(ip, 0))
+        .unwrap_or(addr)
+}
+
+fn rand_id() -> u16 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u16)
+        .unwrap_or_default()
+}
+
+fn bad_request(msg: impl Into<String>) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, msg.into())
+}
+
+fn server_error(e: impl ToString) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use axum::extract::{ConnectInfo, ContentLengthLimit};
+    use axum::http::HeaderMap;
+    use http::{header, StatusCode};
+    use trust_dns_proto::op::{Message, MessageType, Query, ResponseCode};
+    use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+
+    use super::{client_addr, decode_dns_param, doh_post, json_reply, json_request, DNS_JSON};
+
+    #[test]
+    fn test_decode_dns_param() {
+        // www.example.com A, from RFC 8484
+        let dns = "AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB";
+        for dns in [dns.to_string(), format!("{dns}==")] {
+            let request = Message::from_vec(&decode_dns_param(&dns).unwrap()).unwrap();
+            let query = &request.queries()[0];
+            assert_eq!(query.name().to_string(), "www.example.com.");
+            assert_eq!(query.query_type(), RecordType::A);
+        }
+
+        // The URL safe alphabet, `-` and `_` rather than `+` and `/`
+        let bytes = [0xfb, 0xff, 0xbf];
+        assert_eq!(decode_dns_param("-_-_").unwrap(), bytes);
+        assert_eq!(
+            decode_dns_param("+/+/").unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+        assert!(decode_dns_param("not base64!").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_content_type() {
+        let addr = ConnectInfo("192.168.1.10:50000".parse::<SocketAddr>().unwrap());
+        for content_type in [None, Some("text/plain"), Some(DNS_JSON)] {
+            let mut headers = HeaderMap::new();
+            if let Some(content_type) = content_type {
+                headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            }
+            let (status, _) = doh_post(addr, headers, ContentLengthLimit(Default::default()))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                status,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "{content_type:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        for (record_type, expected) in [
+            ("A", RecordType::A),
+            ("aaaa", RecordType::AAAA),
+            ("28", RecordType::AAAA),
+            ("TXT", RecordType::TXT),
+        ] {
+            let request = json_request("example.com", record_type).unwrap();
+            assert!(request.recursion_desired());
+            let query = &request.queries()[0];
+            assert_eq!(query.name().to_string(), "example.com.");
+            assert_eq!(query.query_type(), expected, "{record_type}");
+        }
+        assert_eq!(
+            json_request("example.com", "bogus").unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+        assert!(json_request("exa mple..com", "A").is_err());
+
+        let name = Name::from_str("example.com.").unwrap();
+        let mut reply = Message::new();
+        reply
+            .set_message_type(MessageType::Response)
+            .set_response_code(ResponseCode::NoError)
+            .set_recursion_desired(true)
+            .set_recursion_available(true)
+            .add_query(Query::query(name.clone(), RecordType::A))
+            .add_answer(Record::from_rdata(
+                name,
+                300,
+                RData::A("93.184.216.34".parse().unwrap()),
+            ));
+        let json = json_reply(&reply);
+        assert_eq!(json["Status"], 0);
+        assert_eq!(json["TC"], false);
+        assert_eq!(json["RD"], true);
+        assert_eq!(json["RA"], true);
+        assert_eq!(json["Question"][0]["name"], "example.com.");
+        assert_eq!(json["Question"][0]["type"], 1);
+        assert_eq!(json["Answer"][0]["type"], 1);
+        assert_eq!(json["Answer"][0]["TTL"], 300);
+        assert_eq!(json["Answer"][0]["data"], "93.184.216.34");
+        assert_eq!(json["Authority"].as_array().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.1, 203.0.113.7".parse().unwrap());
+        let loopback = "127.0.0.1:41000".parse::<SocketAddr>().unwrap();
+        let forwarded = client_addr(loopback, &headers);
+        assert_eq!(forwarded.ip().to_string(), "203.0.113.7");
+        let forwarded = client_addr("[::1]:41000".parse().unwrap(), &headers);
+        assert_eq!(forwarded.ip().to_string(), "203.0.113.7");
+
+        // Anyone else could claim to be anything
+        let remote = "192.168.1.10:50000".parse::<SocketAddr>().unwrap();
+        assert_eq!(client_addr(remote, &headers), remote);
+
+        // The proxy appends its own header after the one sent by the client
+        headers.append("x-forwarded-for", "198.51.100.3".parse().unwrap());
+        let forwarded = client_addr(loopback, &headers);
+        assert_eq!(forwarded.ip().to_string(), "198.51.100.3");
+
+        headers.insert("x-forwarded-for", "unknown".parse().unwrap());
+        assert_eq!(client_addr(loopback, &headers), loopback);
+        assert_eq!(client_addr(loopback, &HeaderMap::new()), loopback);
+    }
+}
@@ -0,0 +1,164 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use axum::extract::{Path, Query};
+use axum::response::IntoResponse;
+use axum::Json;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use trust_dns_proto::op::{Message, Query as DnsQuery};
+use trust_dns_proto::rr::{Name, RecordType};
+
+use domain::{explain_filters, PRECEDENCE};
+
+use crate::dns::{check_domain, query_upstream};
+use crate::web::WebError;
+use crate::zones;
+
+#[derive(Debug, Deserialize)]
+pub struct LookupParams {
+    /// Evaluate the rules as if this client was asking
+    client: Option<IpAddr>,
+    #[serde(rename = "type")]
+    record_type: Option<String>,
+    /// Also ask the upstream and include its raw answer
+    #[serde(default)]
+    resolve: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct WebRuleMatch {
+    kind: &'static str,
+    rule: String,
+    /// `$priority` of the rule, the important rules and then the higher priorities decide
+    priority: i64,
+    allowed: bool,
+    /// Rules with `$client` or `$dnstype` modifiers only apply to some of the queries
+    applies: bool,
+    /// This is the rule the decision comes from
+    decisive: bool,
+    /// The decision would be different for the clients or dns types this rule applies to
+    would_change: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct WebRecord {
+    name: String,
+    record_type: String,
+    ttl: u32,
+    data: String,
+}
+
+#[derive(Debug, Serialize)]
+struct WebUpstream {
+    response_code: String,
+    answers: Vec<WebRecord>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct WebLookup {
+    domain: String,
+    client: Option<IpAddr>,
+    record_type: String,
+    allowed: bool,
+    reason: Option<String>,
+    precedence: &'static [&'static str],
+    matches: Vec<WebRuleMatch>,
+    upstream: Option<WebUpstream>,
+}
+
+pub async fn lookup(
+    Path(domain): Path<String>,
+    Query(params): Query<LookupParams>,
+) -> Result<impl IntoResponse, WebError> {
+    let mut name = Name::from_str_relaxed(&domain).map_err(|e| {
+        WebError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid domain {domain}: {e}"),
+        )
+    })?;
+    name.set_fqdn(true);
+    let record_type = match &params.record_type {
+        Some(record_type) => RecordType::from_str(&record_type.to_uppercase()).map_err(|e| {
+            WebError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid type {record_type}: {e}"),
+            )
+        })?,
+        None => RecordType::A,
+    };
+    let domain = name.to_lowercase().to_string();
+    let dns_type = record_type.to_string();
+
+    // Local zones answer before any of the filters are consulted
+    let mut request = Message::new();
+    request.add_query(DnsQuery::query(name.clone(), record_type));
+    let local_zone = zones::resolve(&request).await.map(|(_, reason)| reason);
+    let (allowed, reason) = match &local_zone {
+        Some(reason) => (true, Some(reason.clone())),
+        None => match check_domain(&domain, params.client, Some(&dns_type)).await {
+            Some((reason, allowed, _)) => (allowed, Some(reason)),
+            None => (true, None),
+        },
+    };
+
+    let matches = explain_filters(&domain, params.client, Some(&dns_type)).await?;
+    let decisive = matches
+        .iter()
+        .position(|m| m.applies)
+        .filter(|_| local_zone.is_none());
+    let matches = matches
+        .into_iter()
+        .enumerate()
+        .map(|(idx, m)| WebRuleMatch {
+            kind: PRECEDENCE[m.precedence],
+            decisive: decisive == Some(idx),
+            would_change: local_zone.is_none()
+                && !m.applies
+                && m.is_allow != allowed
+                && decisive.map(|d| idx < d).unwrap_or(true),
+            rule: m.rule,
+            priority: m.priority,
+            allowed: m.is_allow,
+            applies: m.applies,
+        })
+        .collect();
+
+    let upstream = if params.resolve {
+        Some(match query_upstream(name, record_type).await {
+            Ok(response) => WebUpstream {
+                response_code: response.response_code().to_string(),
+                answers: response
+                    .answers()
+                    .iter()
+                    .map(|r| WebRecord {
+                        name: r.name().to_string(),
+                        record_type: r.record_type().to_string(),
+                        ttl: r.ttl(),
+                        data: r.data().map(|d| d.to_string()).unwrap_or_default(),
+                    })
+                    .collect(),
+                error: None,
+            },
+            Err(e) => WebUpstream {
+                response_code: String::new(),
+                answers: Vec::new(),
+                error: Some(e.to_string()),
+            },
+        })
+    } else {
+        None
+    };
+
+    Ok(Json(WebLookup {
+        domain,
+        client: params.client,
+        record_type: dns_type,
+        allowed,
+        reason,
+        precedence: PRECEDENCE,
+        matches,
+        upstream,
+    }))
+}
@@ -1,5 +1,6 @@
 use std::collections::HashSet;
 use std::io::{Cursor, Read};
+use std::net::SocketAddr;
 
 use axum::body::Body;
 use axum::extract::WebSocketUpgrade;
@@ -14,22 +15,37 @@ use routing::{get, post};
 use serde::{Deserialize, Serialize};
 use zip::ZipArchive;
 
+pub use block_page::{start_block_page_server, BlockPageConfig};
+pub use crud::start_filter_expiry;
 pub use health::ws_health_info;
 pub use queries::ws_dns_req;
 pub use websocket::ws_sender;
 
+use crate::web::block_page::{decide_unblock_request, fetch_unblock_requests};
 use crate::web::config::{fetch_config, save_config};
+use crate::web::crud::{
+    create_block_lists, create_filters, delete_block_list, delete_filter, fetch_block_list,
+    fetch_block_lists, fetch_filter, fetch_filters, patch_filter, update_block_list, update_filter,
+};
 use crate::web::dashboard::fetch_dashboard;
+use crate::web::doh::{doh_get, doh_post};
 use crate::web::health::fetch_health_info;
+use crate::web::lookup::lookup;
 use crate::web::queries::fetch_queries;
 use crate::web::websocket::handle_ws;
+use crate::web::zones::fetch_zones;
 use crate::{PiConfig, PI_CONFIG};
 
+mod block_page;
 mod config;
+mod crud;
 mod dashboard;
+mod doh;
 mod health;
+mod lookup;
 mod queries;
 mod websocket;
+mod zones;
 
 static HOME_URLS: Lazy<HashSet<&str>> =
     Lazy::new(|| ["/", "/queries", "/filters", "/health"].into());
@@ -42,9 +58,32 @@ pub async fn start_web_server() -> anyhow::Result<()> {
     let app = Router::new()
         .route("/config", get(fetch_config))
         .route("/config", post(save_config))
+        .route("/filter_rules", get(fetch_filters).post(create_filters))
+        .route(
+            "/filter_rules/:id",
+            get(fetch_filter)
+                .put(update_filter)
+                .patch(patch_filter)
+                .delete(delete_filter),
+        )
+        .route(
+            "/block_lists",
+            get(fetch_block_lists).post(create_block_lists),
+        )
+        .route(
+            "/block_lists/:id",
+            get(fetch_block_list)
+                .put(update_block_list)
+                .delete(delete_block_list),
+        )
         .route("/dashboard/:days", get(fetch_dashboard))
         .route("/health/:days", get(fetch_health_info))
         .route("/queries/:days", get(fetch_queries))
+        .route("/zones", get(fetch_zones))
+        .route("/lookup/:domain", get(lookup))
+        .route("/dns-query", get(doh_get).post(doh_post))
+        .route("/unblock_requests", get(fetch_unblock_requests))
+        .route("/unblock_requests/:id", post(decide_unblock_request))
         .route(
             "/websocket",
             get(|ws: WebSocketUpgrade| async { ws.on_upgrade(handle_ws) }),
@@ -53,7 +92,7 @@ pub async fn start_web_server() -> anyhow::Result<()> {
 
     info!("Starting web server at port {web_port}");
     Server::bind(&([0, 0, 0, 0], *web_port as u16).into())
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await?;
     Ok(())
 }
@@ -61,22 +100,36 @@ pub async fn start_web_server() -> anyhow::Result<()> {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WebError {
     pub error: String,
+    #[serde(skip, default = "internal_error")]
+    pub status: StatusCode,
+}
+
+fn internal_error() -> StatusCode {
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+impl WebError {
+    pub fn new(status: StatusCode, error: impl Into<String>) -> Self {
+        WebError {
+            error: error.into(),
+            status,
+        }
+    }
 }
 
 impl IntoResponse for WebError {
     fn into_response(self) -> Response {
         error!("Sending error response: '{}'", self.error);
+        let status = self.status;
         let mut response = Json(self).into_response();
-        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        *response.status_mut() = status;
         response
     }
 }
 
 impl From<anyhow::Error> for WebError {
     fn from(e: anyhow::Error) -> Self {
-        WebError {
-            error: e.to_string(),
-        }
+        WebError::new(internal_error(), e.to_string())
     }
 }
 
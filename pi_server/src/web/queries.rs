@@ -21,6 +21,8 @@ struct WebQuery {
     filtered: Option<bool>,
     reason: Option<String>,
     resp_time: u64,
+    transport: Option<String>,
+    category: Option<String>,
 }
 
 impl WebQuery {
@@ -36,6 +38,8 @@ impl WebQuery {
             filtered: dr.filtered,
             reason: dr.reason,
             resp_time: dr.resp_ms as u64,
+            transport: dr.transport,
+            category: dr.category,
         }
     }
 }
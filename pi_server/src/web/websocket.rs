@@ -25,8 +25,11 @@ pub enum WsMessage {
     SendAll(String),
 }
 
+/// Dropped if [`ws_sender`] isn't running yet, there is nobody connected to send it to anyway
 pub fn send_ws_msg(msg: WsMessage) {
-    WS_SENDER.get().unwrap().send(msg).ok();
+    if let Some(sender) = WS_SENDER.get() {
+        sender.send(msg).ok();
+    }
 }
 
 pub async fn ws_sender() -> anyhow::Result<()> {
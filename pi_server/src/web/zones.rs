@@ -0,0 +1,8 @@
+use axum::response::IntoResponse;
+use axum::Json;
+
+use crate::zones::zone_statuses;
+
+pub async fn fetch_zones() -> impl IntoResponse {
+    Json(zone_statuses().await)
+}
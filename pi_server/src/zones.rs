@@ -0,0 +1,456 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::*;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::fs;
+use tokio::sync::RwLock;
+use tokio::time;
+use trust_dns_client::serialize::txt::{Lexer, Parser};
+use trust_dns_proto::op::{Message, MessageType, Query, ResponseCode};
+use trust_dns_proto::rr::{Name, RData, Record, RecordSet, RecordType};
+
+use crate::PI_CONFIG;
+
+const ZONE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_CNAME_CHAIN: usize = 8;
+
+static ZONES: Lazy<RwLock<HashMap<String, ZoneFile>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ZoneStatus {
+    pub path: String,
+    pub origin: Option<String>,
+    pub record_count: usize,
+    pub last_loaded: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+struct ZoneFile {
+    modified: Option<SystemTime>,
+    zone: Option<Arc<Zone>>,
+    status: ZoneStatus,
+}
+
+#[derive(Debug)]
+pub struct Zone {
+    origin: Name,
+    soa: Record,
+    name_servers: Vec<Record>,
+    records: HashMap<Name, Vec<RecordSet>>,
+    /// All the names which exist in the zone, including empty non-terminals
+    names: HashSet<Name>,
+}
+
+pub async fn start_zone_watcher() -> anyhow::Result<()> {
+    let zone_files = match &PI_CONFIG.get().unwrap().zone_files {
+        Some(zone_files) if !zone_files.is_empty() => zone_files,
+        _ => {
+            info!("No local zone files are configured");
+            return Ok(());
+        }
+    };
+    info!("Serving local zones from {zone_files:?}");
+    loop {
+        for path in zone_files {
+            let modified = fs::metadata(path)
+                .await
+                .and_then(|meta| meta.modified())
+                .ok();
+            let changed = ZONES
+                .read()
+                .await
+                .get(path)
+                .map(|zf| zf.modified != modified)
+                .unwrap_or(true);
+            if changed {
+                reload_zone(path, modified).await;
+            }
+        }
+        time::sleep(ZONE_CHECK_INTERVAL).await;
+    }
+}
+
+async fn reload_zone(path: &str, modified: Option<SystemTime>) {
+    info!("Loading local zone file: {path}");
+    let result = match fs::read_to_string(path).await {
+        Ok(text) => Zone::parse(&text, default_origin(path)),
+        Err(e) => Err(e.into()),
+    };
+
+    let mut zones = ZONES.write().await;
+    let zone_file = zones.entry(path.to_owned()).or_insert_with(|| ZoneFile {
+        modified: None,
+        zone: None,
+        status: ZoneStatus {
+            path: path.to_owned(),
+            origin: None,
+            record_count: 0,
+            last_loaded: None,
+            error: None,
+        },
+    });
+    zone_file.modified = modified;
+    match result {
+        Ok(zone) => {
+            info!(
+                "Loaded zone {} with {} records from {path}",
+                zone.origin,
+                zone.record_count()
+            );
+            zone_file.status.origin = Some(zone.origin.to_string());
+            zone_file.status.record_count = zone.record_count();
+            zone_file.status.last_loaded = Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+            );
+            zone_file.status.error = None;
+            zone_file.zone = Some(Arc::new(zone));
+        }
+        Err(e) => {
+            // Keep serving whatever was loaded successfully the last time
+            error!("Failed to load zone file {path}: {e}");
+            zone_file.status.error = Some(e.to_string());
+        }
+    }
+}
+
+pub async fn zone_statuses() -> Vec<ZoneStatus> {
+    let mut statuses = ZONES
+        .read()
+        .await
+        .values()
+        .map(|zf| zf.status.clone())
+        .collect::<Vec<_>>();
+    statuses.sort_by(|a, b| a.path.cmp(&b.path));
+    statuses
+}
+
+/// Answers the request authoritatively if its queries belong to one of the local zones
+pub async fn resolve(request: &Message) -> Option<(Message, String)> {
+    let zones = ZONES
+        .read()
+        .await
+        .values()
+        .filter_map(|zf| zf.zone.clone())
+        .collect::<Vec<_>>();
+    resolve_in(&zones, request)
+}
+
+fn resolve_in(zones: &[Arc<Zone>], request: &Message) -> Option<(Message, String)> {
+    let first = request.queries().first()?;
+    let zone = find_zone(zones, first.name())?;
+    let reason = format!("Local zone: {}", zone.origin);
+    // Each answer is only authoritative for its own zone, so a request mixing local and other
+    // names can't be answered as a whole
+    let same_zone = request.queries().iter().all(
+        |query| matches!(find_zone(zones, query.name()), Some(other) if Arc::ptr_eq(zone, other)),
+    );
+    if !same_zone {
+        let mut response =
+            Message::error_msg(request.id(), request.op_code(), ResponseCode::Refused);
+        response
+            .add_queries(request.queries().iter().cloned())
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(true);
+        return Some((response, reason));
+    }
+    Some((zone.answer(request), reason))
+}
+
+fn find_zone<'a>(zones: &'a [Arc<Zone>], name: &Name) -> Option<&'a Arc<Zone>> {
+    zones
+        .iter()
+        .filter(|zone| zone.origin.zone_of(name))
+        .max_by_key(|zone| zone.origin.num_labels())
+}
+
+/// `home.arpa.zone` is for `home.arpa.` unless the file says otherwise with `$ORIGIN`
+fn default_origin(path: &str) -> Option<Name> {
+    let stem = Path::new(path).file_stem()?.to_str()?;
+    let mut name = Name::from_str_relaxed(stem).ok()?;
+    name.set_fqdn(true);
+    Some(name)
+}
+
+impl Zone {
+    pub fn parse(text: &str, origin: Option<Name>) -> anyhow::Result<Zone> {
+        let (origin, record_sets) = Parser::new().parse(Lexer::new(text), origin, None)?;
+        let origin = origin.to_lowercase();
+
+        let mut records: HashMap<Name, Vec<RecordSet>> = HashMap::new();
+        let mut names = HashSet::new();
+        for rrset in record_sets.into_values() {
+            let mut name = rrset.name().to_lowercase();
+            if !origin.zone_of(&name) {
+                return Err(anyhow::anyhow!("{name} is out of zone {origin}"));
+            }
+            records.entry(name.clone()).or_default().push(rrset);
+            while names.insert(name.clone()) && name != origin {
+                name = name.base_name();
+            }
+        }
+
+        let soa = records
+            .get(&origin)
+            .and_then(|sets| sets.iter().find(|rs| rs.record_type() == RecordType::SOA))
+            .and_then(|rs| rs.records_without_rrsigs().next())
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Zone {origin} has no SOA record"))?;
+        let name_servers = records
+            .get(&origin)
+            .into_iter()
+            .flatten()
+            .filter(|rs| rs.record_type() == RecordType::NS)
+            .flat_map(|rs| rs.records_without_rrsigs())
+            .cloned()
+            .collect();
+        Ok(Zone {
+            origin,
+            soa,
+            name_servers,
+            records,
+            names,
+        })
+    }
+
+    fn record_count(&self) -> usize {
+        self.records
+            .values()
+            .flatten()
+            .map(|rs| rs.records_without_rrsigs().count())
+            .sum()
+    }
+
+    /// Builds the authoritative response for a request whose queries are all in this zone
+    fn answer(&self, request: &Message) -> Message {
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request.op_code())
+            .set_authoritative(true)
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(true);
+        for query in request.queries() {
+            response.add_query(query.clone());
+            let (code, answers) = self.lookup(query);
+            if code == ResponseCode::NXDomain || response.response_code() == ResponseCode::NoError {
+                response.set_response_code(code);
+            }
+            if answers.is_empty() {
+                if response.name_servers().is_empty() {
+                    response.add_name_server(self.negative_soa());
+                }
+            } else {
+                response.add_answers(answers);
+            }
+        }
+        if !response.answers().is_empty() && response.name_servers().is_empty() {
+            response.add_name_servers(self.name_servers.iter().cloned());
+        }
+        response
+    }
+
+    pub fn lookup(&self, query: &Query) -> (ResponseCode, Vec<Record>) {
+        let mut answers = Vec::new();
+        let mut name = query.name().to_lowercase();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let sets = match self.records.get(&name) {
+                Some(sets) => sets,
+                None if self.names.contains(&name) => break,
+                None => match self.wildcard(&name) {
+                    Some(sets) => sets,
+                    None if answers.is_empty() => return (ResponseCode::NXDomain, answers),
+                    None => break,
+                },
+            };
+            let matching = sets
+                .iter()
+                .filter(|rs| {
+                    rs.record_type() == query.query_type() || query.query_type() == RecordType::ANY
+                })
+                .flat_map(|rs| rs.records_without_rrsigs())
+                .collect::<Vec<_>>();
+            let cname = sets
+                .iter()
+                .find(|rs| rs.record_type() == RecordType::CNAME)
+                .and_then(|rs| rs.records_without_rrsigs().next());
+            // Wildcard records are synthesized with the name being queried
+            let mut push = |record: &Record| {
+                let mut record = record.clone();
+                record.set_name(name.clone());
+                answers.push(record);
+            };
+            match (matching.is_empty(), cname) {
+                (false, _) => {
+                    matching.into_iter().for_each(push);
+                    break;
+                }
+                (true, Some(cname)) => {
+                    push(cname);
+                    match cname.data() {
+                        Some(RData::CNAME(target)) if self.origin.zone_of(target) => {
+                            name = target.to_lowercase();
+                        }
+                        _ => break,
+                    }
+                }
+                (true, None) => break,
+            }
+        }
+        (ResponseCode::NoError, answers)
+    }
+
+    /// Finds the records of `*.<closest encloser>` for a name which doesn't exist in the zone
+    fn wildcard(&self, name: &Name) -> Option<&Vec<RecordSet>> {
+        let mut encloser = name.base_name();
+        while self.origin.zone_of(&encloser) && !encloser.is_root() {
+            if self.names.contains(&encloser) {
+                let wildcard = Name::from_ascii("*").ok()?.append_domain(&encloser).ok()?;
+                return self.records.get(&wildcard);
+            }
+            encloser = encloser.base_name();
+        }
+        None
+    }
+
+    /// SOA for the authority section of negative answers, its TTL caps the negative caching
+    fn negative_soa(&self) -> Record {
+        let mut soa = self.soa.clone();
+        if let Some(RData::SOA(data)) = self.soa.data() {
+            soa.set_ttl(self.soa.ttl().min(data.minimum()));
+        }
+        soa
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use trust_dns_proto::op::{Message, Query, ResponseCode};
+    use trust_dns_proto::rr::{Name, RecordType};
+
+    use super::{resolve_in, Zone};
+
+    const ZONE: &str = r#"
+$ORIGIN home.arpa.
+$TTL 3600
+@       IN  SOA ns.home.arpa. admin.home.arpa. ( 1 7200 3600 1209600 300 )
+@       IN  NS  ns.home.arpa.
+ns      IN  A   192.168.1.5
+nas     IN  A   192.168.1.20
+router  IN  A   192.168.1.1
+www     IN  CNAME nas.home.arpa.
+*.dev   IN  A   192.168.1.30
+a.b     IN  A   192.168.1.40
+"#;
+
+    const LAN_ZONE: &str = r#"
+$ORIGIN lan.
+@   600 IN  SOA ns.lan. admin.lan. ( 1 7200 3600 1209600 60 )
+printer IN  A   192.168.2.9
+"#;
+
+    fn request(names: &[&str], rtype: RecordType) -> Message {
+        let mut request = Message::new();
+        request.set_id(42);
+        for name in names {
+            request.add_query(Query::query(Name::from_str(name).unwrap(), rtype));
+        }
+        request
+    }
+
+    fn lookup(zone: &Zone, name: &str, rtype: RecordType) -> (ResponseCode, usize) {
+        let query = Query::query(Name::from_str(name).unwrap(), rtype);
+        let (code, answers) = zone.lookup(&query);
+        (code, answers.len())
+    }
+
+    #[test]
+    fn test_lookup() {
+        let zone = Zone::parse(ZONE, None).unwrap();
+        assert_eq!(zone.record_count(), 8);
+        assert_eq!(zone.negative_soa().ttl(), 300);
+
+        assert_eq!(
+            lookup(&zone, "NAS.home.arpa.", RecordType::A),
+            (ResponseCode::NoError, 1)
+        );
+        assert_eq!(
+            lookup(&zone, "www.home.arpa.", RecordType::A),
+            (ResponseCode::NoError, 2)
+        );
+        assert_eq!(
+            lookup(&zone, "nas.home.arpa.", RecordType::AAAA),
+            (ResponseCode::NoError, 0)
+        );
+        assert_eq!(
+            lookup(&zone, "foo.home.arpa.", RecordType::A),
+            (ResponseCode::NXDomain, 0)
+        );
+        assert_eq!(
+            lookup(&zone, "x.dev.home.arpa.", RecordType::A),
+            (ResponseCode::NoError, 1)
+        );
+        assert_eq!(
+            lookup(&zone, "b.home.arpa.", RecordType::A),
+            (ResponseCode::NoError, 0)
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let zones = vec![
+            Arc::new(Zone::parse(ZONE, None).unwrap()),
+            Arc::new(Zone::parse(LAN_ZONE, None).unwrap()),
+        ];
+
+        let (response, reason) =
+            resolve_in(&zones, &request(&["nas.home.arpa."], RecordType::A)).unwrap();
+        assert_eq!(reason, "Local zone: home.arpa.");
+        assert_eq!(response.id(), 42);
+        assert!(response.authoritative());
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::NS);
+
+        // NODATA: the name exists without records of the type
+        let (response, _) =
+            resolve_in(&zones, &request(&["nas.home.arpa."], RecordType::AAAA)).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        let soa = &response.name_servers()[0];
+        assert_eq!(soa.record_type(), RecordType::SOA);
+        assert_eq!(soa.ttl(), 300);
+
+        // NXDOMAIN: the authority carries the SOA of the zone with its negative TTL
+        let (response, _) = resolve_in(&zones, &request(&["foo.lan."], RecordType::A)).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert!(response.answers().is_empty());
+        let soa = &response.name_servers()[0];
+        assert_eq!(soa.record_type(), RecordType::SOA);
+        assert_eq!(*soa.name(), Name::from_str("lan.").unwrap());
+        assert_eq!(soa.ttl(), 60);
+
+        assert!(resolve_in(&zones, &request(&["example.com."], RecordType::A)).is_none());
+
+        let spanning = ["nas.home.arpa.", "printer.lan."];
+        let (response, _) = resolve_in(&zones, &request(&spanning, RecordType::A)).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert!(response.answers().is_empty());
+        assert_eq!(response.queries().len(), 2);
+
+        let outside = ["nas.home.arpa.", "example.com."];
+        let (response, _) = resolve_in(&zones, &request(&outside, RecordType::A)).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+    }
+}